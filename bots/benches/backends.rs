@@ -0,0 +1,197 @@
+//! Compares the mio and io_uring backends by putting the same amount of bots on a local mock
+//! server with each, then measuring the traffic and the cpu time of the worker threads.
+//!
+//! Run with `cargo bench -p bots --features io-uring --bench backends`. `BOTS`, `WORKERS` and
+//! `SECONDS` change the amount of bots, worker threads and how long each backend is measured.
+//! Every bot takes a socket on both ends, so the open file limit is raised as far as allowed
+//! and has to end up above twice the amount of bots.
+
+use std::os::unix::thread::JoinHandleExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{env, io, mem};
+
+use bots::threading::{BotMessage, Worker};
+use bots::{bot, Args};
+use clap::Parser;
+use mock_server::{Config, MockServer};
+
+const BOTS: usize = 10_000;
+const WORKERS: usize = 4;
+const SECONDS: u64 = 10;
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(120);
+
+struct Totals {
+    packets: u64,
+    bytes: u64,
+    cpu: Duration,
+}
+
+fn main() {
+    let bots = env_or("BOTS", BOTS);
+    let workers = env_or("WORKERS", WORKERS);
+    let measure = Duration::from_secs(env_or("SECONDS", SECONDS));
+
+    let files = raise_file_limit().expect("Raise the open file limit");
+    if files < 2 * bots as u64 + 64 {
+        eprintln!("warning: {files} open files are allowed, which isn't enough for {bots} bots");
+    }
+
+    println!(
+        "{:<10} {:>6} {:>9} {:>12} {:>10} {:>11}",
+        "backend", "bots", "login", "packets/s", "MiB/s", "worker cpu"
+    );
+    for (name, extra_args) in [("mio", &[][..]), ("io_uring", &["--io-uring"][..])] {
+        run(name, extra_args, bots, workers, measure);
+    }
+}
+
+fn run(name: &str, extra_args: &[&str], bots: usize, workers: usize, measure: Duration) {
+    let config = Config {
+        compression_threshold: 256,
+        max_players: bots,
+        keep_alive_interval: Duration::from_secs(1),
+        ..Config::default()
+    };
+    let mut server = MockServer::bind("127.0.0.1:0".parse().unwrap(), config).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let stop = server.stop_handle();
+    let recorder = server.recorder();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let count = bots.to_string();
+    let mut args = vec!["bots", &addr, &count, "--no-ui"];
+    args.extend_from_slice(extra_args);
+    let args = Args::parse_from(args);
+
+    let workers: Vec<(Arc<Worker>, JoinHandle<()>)> = (0..workers)
+        .map(|_| {
+            let mut worker = Worker::new();
+            let ctx = bot::setup_bot(&mut worker, &args).unwrap();
+            let worker = Arc::new(worker);
+
+            let handle = {
+                let worker = worker.clone();
+                let args = args.clone();
+                thread::spawn(move || bot::start(ctx, &args, worker, None).unwrap())
+            };
+
+            (worker, handle)
+        })
+        .collect();
+
+    let ticking = Arc::new(AtomicBool::new(true));
+    let tick_thread = {
+        let workers: Vec<_> = workers.iter().map(|(worker, _)| worker.clone()).collect();
+        let ticking = ticking.clone();
+        let tick_duration = Duration::from_millis(args.tick_rate);
+
+        thread::spawn(move || {
+            while ticking.load(Ordering::Relaxed) {
+                for worker in &workers {
+                    worker.send(BotMessage::Tick);
+                }
+                thread::sleep(tick_duration);
+            }
+        })
+    };
+
+    // Every bot connects at once, which is the worst case for the connect path
+    let start = Instant::now();
+    for i in 0..bots {
+        workers[i % workers.len()]
+            .0
+            .send(BotMessage::ConnectBot(format!("Bot{i}")));
+    }
+    while recorder.online() < bots && start.elapsed() < LOGIN_TIMEOUT {
+        thread::sleep(Duration::from_millis(50));
+    }
+    let login = start.elapsed();
+    let online = recorder.online();
+
+    let before = totals(&workers);
+    let start = Instant::now();
+    thread::sleep(measure);
+    let after = totals(&workers);
+    let elapsed = start.elapsed().as_secs_f64();
+
+    ticking.store(false, Ordering::Relaxed);
+    tick_thread.join().unwrap();
+    for (worker, handle) in workers {
+        worker.send(BotMessage::Stop);
+        handle.join().unwrap();
+    }
+    stop.stop().unwrap();
+    server_thread.join().unwrap();
+
+    println!(
+        "{:<10} {:>6} {:>8.2}s {:>12.0} {:>10.2} {:>10.1}%",
+        name,
+        online,
+        login.as_secs_f64(),
+        (after.packets - before.packets) as f64 / elapsed,
+        (after.bytes - before.bytes) as f64 / elapsed / (1024.0 * 1024.0),
+        (after.cpu - before.cpu).as_secs_f64() / elapsed * 100.0,
+    );
+}
+
+// Traffic in both directions and cpu time across every worker
+fn totals(workers: &[(Arc<Worker>, JoinHandle<()>)]) -> Totals {
+    let mut totals = Totals {
+        packets: 0,
+        bytes: 0,
+        cpu: Duration::ZERO,
+    };
+
+    for (worker, handle) in workers {
+        totals.packets +=
+            worker.packets_tx.load(Ordering::Relaxed) + worker.packets_rx.load(Ordering::Relaxed);
+        totals.bytes +=
+            worker.bytes_tx.load(Ordering::Relaxed) + worker.bytes_rx.load(Ordering::Relaxed);
+        totals.cpu += thread_cpu_time(handle).expect("Thread cpu time");
+    }
+
+    totals
+}
+
+fn thread_cpu_time(handle: &JoinHandle<()>) -> io::Result<Duration> {
+    let mut clock = 0;
+    // Safety: the thread is still running, as it is only joined after this
+    let res = unsafe { libc::pthread_getcpuclockid(handle.as_pthread_t(), &mut clock) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+
+    // Safety: all zeros is a valid `timespec`
+    let mut time: libc::timespec = unsafe { mem::zeroed() };
+    if unsafe { libc::clock_gettime(clock, &mut time) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+// Raises the soft open file limit to the hard limit, returning the new limit
+fn raise_file_limit() -> io::Result<u64> {
+    // Safety: all zeros is a valid `rlimit`
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    limit.rlim_cur = limit.rlim_max;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(limit.rlim_cur)
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|it| it.parse().ok())
+        .unwrap_or(default)
+}
@@ -1,5 +1,10 @@
-use crate::address::MinecraftAddress;
-use clap::{Parser, ValueEnum};
+use crate::identity;
+use crate::impairment::{self, Millis};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use mc_io::address::MinecraftAddress;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -56,6 +61,140 @@ pub struct Args {
         help = "The chance of sending an action packet"
     )]
     pub action_chance: f64,
+    #[arg(
+        long,
+        default_value_t = 4 * 1024 * 1024,
+        help = "Bytes a bot may buffer in either direction before it is disconnected"
+    )]
+    pub high_water_mark: usize,
+    #[arg(
+        long,
+        default_value_t = 64 * 1024,
+        help = "Buffer capacity each bot keeps once its buffers drain"
+    )]
+    pub retained_buffer: usize,
+    #[arg(
+        long,
+        help = "Bytes per second each bot may send, anything past it is queued until the limit allows it"
+    )]
+    pub max_upload_per_bot: Option<u32>,
+    #[arg(
+        long,
+        help = "Packets per second each bot may send, anything past it is queued until the limit allows it"
+    )]
+    pub max_packets_per_bot: Option<u32>,
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(0..=12),
+        help = "The level packets are compressed with, from 0 (stored without compressing) to 12, 1 is the fastest level that actually compresses"
+    )]
+    pub compression_level: u8,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[arg(
+        long,
+        conflicts_with_all = ["latency", "jitter", "stall_interval"],
+        help = "Drive the bots with io_uring instead of epoll, needs linux 6.0 or newer"
+    )]
+    pub io_uring: bool,
+    #[arg(
+        long,
+        value_parser = impairment::parse_millis,
+        help = "One way latency in ms added to everything a bot sends and receives, either fixed or a range each bot picks its own from, eg. 50-150"
+    )]
+    pub latency: Option<Millis>,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "The most time in ms data is delayed by on top of the latency, it still arrives in order"
+    )]
+    pub jitter: u64,
+    #[arg(
+        long,
+        help = "Time in ms between stalls of each bot's link, during which nothing gets through in either direction"
+    )]
+    pub stall_interval: Option<u64>,
+    #[arg(long, default_value_t = 1000, help = "How long in ms each stall lasts")]
+    pub stall_duration: u64,
+    #[arg(
+        long,
+        help = "The session server used to authenticate with online mode servers, eg. https://sessionserver.mojang.com"
+    )]
+    pub session_server: Option<String>,
+    #[arg(
+        long,
+        default_value = "mc-tools",
+        help = "The access token presented to the session server"
+    )]
+    pub access_token: String,
+    #[arg(
+        long,
+        help = "Answer Velocity modern forwarding requests signed with this secret"
+    )]
+    pub velocity_secret: Option<String>,
+    #[arg(
+        long,
+        help = "The client ip forwarded to backend servers, each bot gets a random ip if not set"
+    )]
+    pub forwarded_ip: Option<IpAddr>,
+    #[arg(
+        long,
+        help = "Send BungeeCord ip forwarding data in the handshake, for servers behind BungeeCord"
+    )]
+    pub bungee_forwarding: bool,
+    #[arg(long, value_enum, default_value_t = UuidMode::Offline, help = "How the uuid each bot logs in with is picked")]
+    pub uuid_mode: UuidMode,
+    #[arg(
+        long,
+        value_parser = identity::parse_uuid,
+        required_if_eq("uuid_mode", "fixed"),
+        help = "The uuid used by every bot when `uuid_mode` is fixed"
+    )]
+    pub fixed_uuid: Option<u128>,
+    #[arg(
+        long,
+        help = "A capture of a player whose movement, actions and chat every bot replays, in place of generated movement and actions"
+    )]
+    pub replay: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 8.0,
+        help = "The most blocks each bot's replayed movement is offset by"
+    )]
+    pub replay_spread: f64,
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "The most time in ms each replayed packet is delayed by"
+    )]
+    pub replay_jitter: u64,
+}
+
+impl Args {
+    /// Checks the args that depend on each other, which clap can't check while parsing
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        if let Some(interval) = self.stall_interval {
+            if self.stall_duration >= interval {
+                return Err(Self::command().error(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "`--stall-duration` of {}ms has to be shorter than `--stall-interval` of {}ms",
+                        self.stall_duration, interval
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum UuidMode {
+    /// The uuid an offline mode server would assign
+    Offline,
+    Random,
+    Fixed,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -1,24 +1,34 @@
 use crate::context::Context as WorkerContext;
+use crate::identity;
+use crate::impairment::{ImpairedStream, Impairment, Timers};
+use crate::login_plugin::{LoginPluginResponder, Unsupported, VelocityForwarding};
 use crate::player::Player;
+use crate::replay::Replay;
+use crate::session::SessionJoiner;
 use crate::threading::{ConsoleMessage, Worker};
-use crate::{threading::BotMessage, Args};
+use crate::{args::UuidMode, threading::BotMessage, Args};
 use anyhow::Context;
 use log::{info, warn};
+use mc_io::auth::SessionServer;
 use mc_io::error::CommunicationError;
-use mc_io::{GlobalReadContext, GlobalWriteContext};
+use mc_io::observer::Observer;
+use mc_io::rate_limit::RateLimit;
+use mc_io::state::{Client, ProtocolState};
+use mc_io::uuid;
+use mc_io::{Backpressure, BufferLimits, CompressionLevel, GlobalReadContext, GlobalWriteContext};
 use mio::net::TcpStream;
 use mio::{Events, Interest, Poll, Token, Waker};
 use proto::packets::c2s::handshake::HandshakePacket;
-use proto::packets::c2s::login::{LoginProtoC2S, LoginStartPacket};
+use proto::packets::c2s::login::LoginStartPacket;
 
 use std::collections::HashMap;
 use std::fs;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{ErrorKind, IoSlice, Read, Write};
 use std::net::SocketAddr;
 
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 const PROTOCOL_VERSION: u32 = 760;
 
@@ -26,40 +36,59 @@ const WAKER_TOKEN: Token = Token(0);
 
 static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(1);
 
-type Backend = LoggedStream;
+type Backend = ImpairedStream<LoggedStream>;
 
-pub struct BotContext {
-    poll: Poll,
+/// The io backend a worker drives its bots with, picked by `--io-uring`
+pub enum BotContext {
+    Mio(Poll),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    IoUring(Box<crate::uring::UringContext>),
 }
 
-pub fn setup_bot(worker: &mut Worker) -> anyhow::Result<BotContext> {
+pub fn setup_bot(worker: &mut Worker, args: &Args) -> anyhow::Result<BotContext> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if args.io_uring {
+        return crate::uring::setup_bot(worker).map(|ctx| BotContext::IoUring(Box::new(ctx)));
+    }
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    let _ = args;
+
     let poll = Poll::new().context("Create poll")?;
 
     let waker = Waker::new(poll.registry(), WAKER_TOKEN).context("Create waker")?;
-    worker.waker = Some(waker);
+    worker.waker = Some(Box::new(waker));
 
-    Ok(BotContext { poll })
+    Ok(BotContext::Mio(poll))
 }
 
-pub fn start(ctx: BotContext, args: &Args, worker: Arc<Worker>) -> anyhow::Result<()> {
-    let mut poll = ctx.poll;
-    let mut events = Events::with_capacity(500);
-
-    let mut ctx_read = GlobalReadContext::new();
+pub fn start(
+    ctx: BotContext,
+    args: &Args,
+    worker: Arc<Worker>,
+    replay: Option<Arc<Replay>>,
+) -> anyhow::Result<()> {
+    match ctx {
+        BotContext::Mio(poll) => start_mio(poll, args, worker, replay),
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        BotContext::IoUring(ctx) => crate::uring::start(*ctx, args, worker, replay),
+    }
+}
 
+fn start_mio(
+    mut poll: Poll,
+    args: &Args,
+    worker: Arc<Worker>,
+    replay: Option<Arc<Replay>>,
+) -> anyhow::Result<()> {
+    let mut events = Events::with_capacity(500);
+    let mut driver = Driver::new(args, worker.clone(), replay)?;
     let mut players = HashMap::new();
-
-    let mut last_tick = Instant::now();
-
-    let ctx_write = GlobalWriteContext::new();
-    let messages = generate_messages(args).context("Could not generate chat messages")?;
-    let mut context = WorkerContext {
-        messages,
-        g_write_ctx: ctx_write,
-    };
+    let impairment = Impairment::from_args(args);
+    let mut timers = Timers::new();
 
     'main_loop: loop {
-        poll.poll(&mut events, None).context("Poll")?;
+        poll.poll(&mut events, timers.timeout(Instant::now()))
+            .context("Poll")?;
 
         for event in &events {
             match event.token() {
@@ -67,38 +96,22 @@ pub fn start(ctx: BotContext, args: &Args, worker: Arc<Worker>) -> anyhow::Resul
                     for message in worker.bot_bound.1.try_iter() {
                         match message {
                             BotMessage::ConnectBot(username) => {
-                                if let Some((token, player)) =
-                                    create_bot(&mut poll, args.server.0, username, &worker)
-                                {
+                                if let Some((token, player)) = create_bot(
+                                    &mut poll,
+                                    args.server.0,
+                                    username,
+                                    &driver,
+                                    impairment.as_ref(),
+                                ) {
                                     players.insert(token, player);
                                 }
                             }
                             BotMessage::Tick => {
-                                let mut tps_total = 0.0;
-                                let mut tps_count = 0;
-
-                                for player in players.values_mut() {
-                                    let res = player.tick(args, &mut context);
-
-                                    if player.last_game_time.1 > last_tick && !player.tps.is_nan() {
-                                        tps_total += player.tps;
-                                        tps_count += 1;
-                                    }
+                                driver.tick(players.values_mut())?;
 
-                                    if let Err(error) = res {
-                                        handle_error(player, error, &worker);
-                                    }
+                                for (&token, player) in &players {
+                                    timers.schedule(token, &player.socket);
                                 }
-
-                                if tps_count != 0 {
-                                    worker
-                                        .console_bound
-                                        .0
-                                        .send(ConsoleMessage::TPS(tps_total, tps_count))
-                                        .context("Send msg")?;
-                                }
-
-                                last_tick = Instant::now();
                             }
                             BotMessage::Stop => {
                                 break 'main_loop;
@@ -110,49 +123,45 @@ pub fn start(ctx: BotContext, args: &Args, worker: Arc<Worker>) -> anyhow::Resul
                     if let Some(player) = players.get_mut(&bot_token) {
                         // Set up the player if needed
                         if event.is_writable() && !player.connected && !player.kicked {
-                            let res = connect_bot(
-                                player,
-                                args.server.0,
-                                &mut context,
-                                &worker,
-                                args.proto_id.unwrap_or(PROTOCOL_VERSION),
-                            );
-
-                            if let Err(error) = res {
-                                handle_error(player, error, &worker);
+                            match connected(player) {
+                                Ok(true) => driver.login(player),
+                                Ok(false) => (),
+                                Err(error) => handle_error(player, error, &worker),
                             }
                         }
 
                         // handle write
-                        if event.is_writable() && !player.kicked {
-                            let res = player
-                                .ctx_write
-                                .as_mut()
-                                .context("Connection writer theft")?
-                                .write_unwritten();
-
-                            if let Err(error) = res {
-                                handle_error(player, error, &worker);
+                        if event.is_writable() {
+                            if let Err(error) = player.socket.release() {
+                                handle_error(player, error.into(), &worker);
                             }
+                            driver.write(player)?;
                         }
 
                         // handle read
-                        if event.is_readable() && !player.kicked {
-                            let player_read = player.ctx_read.take();
+                        if event.is_readable() {
+                            driver.read(player);
+                        }
 
-                            if let Some(mut player_read) = player_read {
-                                let res =
-                                    player_read.read_packets(&mut ctx_read, player, &mut context);
+                        timers.schedule(bot_token, &player.socket);
+                    }
+                }
+            }
+        }
 
-                                if let Err(error) = res {
-                                    handle_error(player, error, &worker);
-                                }
+        // Passes on what an impaired link held back
+        let now = Instant::now();
+        for token in timers.expired(now) {
+            if let Some(player) = players.get_mut(&token) {
+                player.socket.fired(now);
 
-                                player.ctx_read = Some(player_read);
-                            }
-                        }
-                    }
+                if let Err(error) = player.socket.release() {
+                    handle_error(player, error.into(), &worker);
                 }
+                driver.write(player)?;
+                driver.read(player);
+
+                timers.schedule(token, &player.socket);
             }
         }
 
@@ -162,6 +171,196 @@ pub fn start(ctx: BotContext, args: &Args, worker: Arc<Worker>) -> anyhow::Resul
     Ok(())
 }
 
+/// The state a worker thread drives its bots with, shared by every io backend
+pub(crate) struct Driver<'a> {
+    pub args: &'a Args,
+    pub worker: Arc<Worker>,
+    context: WorkerContext,
+    ctx_read: GlobalReadContext,
+    limits: BufferLimits,
+    last_tick: Instant,
+}
+
+impl<'a> Driver<'a> {
+    pub fn new(
+        args: &'a Args,
+        worker: Arc<Worker>,
+        replay: Option<Arc<Replay>>,
+    ) -> anyhow::Result<Self> {
+        let limits = BufferLimits {
+            high_water_mark: args.high_water_mark,
+            retained_capacity: args.retained_buffer,
+            backpressure: Backpressure::Error,
+        };
+
+        let compression_level =
+            CompressionLevel::new(args.compression_level).expect("Checked by clap");
+        let ctx_write = GlobalWriteContext::with_compression_level(compression_level);
+        let messages = generate_messages(args).context("Could not generate chat messages")?;
+        let session = args.session_server.as_ref().map(|url| {
+            SessionJoiner::new(SessionServer::new(url.as_str()), args.access_token.clone())
+        });
+        let login_plugins: Box<dyn LoginPluginResponder> = match args.velocity_secret {
+            Some(ref secret) => Box::new(VelocityForwarding::new(secret.as_bytes())),
+            None => Box::new(Unsupported),
+        };
+        let context = WorkerContext {
+            messages,
+            g_write_ctx: ctx_write,
+            session,
+            login_plugins,
+            worker: worker.clone(),
+            replay,
+        };
+
+        Ok(Self {
+            args,
+            worker,
+            context,
+            ctx_read: GlobalReadContext::new(),
+            limits,
+            last_tick: Instant::now(),
+        })
+    }
+
+    /// A bot that logs in over `stream` once it is connected
+    pub fn create_player<S>(&self, stream: S, username: String) -> Player<S>
+    where
+        for<'b> &'b S: Read + Write,
+    {
+        let uuid = match self.args.uuid_mode {
+            UuidMode::Offline => uuid::offline_uuid(&username),
+            UuidMode::Random => identity::random_uuid(),
+            UuidMode::Fixed => self.args.fixed_uuid.expect("Required by clap"),
+        };
+
+        let observer = Observer::new::<Client>(self.worker.clone());
+        let mut player = Player::new(stream, username, self.limits.clone(), observer);
+        player.uuid = uuid;
+        player.forwarded_ip = self.args.forwarded_ip.unwrap_or_else(identity::random_ip);
+
+        let (bytes, packets) = (self.args.max_upload_per_bot, self.args.max_packets_per_bot);
+        if bytes.is_some() || packets.is_some() {
+            // A tick's worth may be sent at once, so the bots send as often as they would without
+            // a limit
+            let burst = Duration::from_millis(self.args.tick_rate);
+            let ctx_write = player.ctx_write.as_mut().expect("Created with the player");
+            ctx_write.rate_limit = Some(RateLimit::new(bytes, packets, burst));
+        }
+
+        player
+    }
+
+    pub fn tick<'p, S: 'p>(
+        &mut self,
+        players: impl Iterator<Item = &'p mut Player<S>>,
+    ) -> anyhow::Result<()>
+    where
+        for<'b> &'b S: Read + Write,
+    {
+        let mut tps_total = 0.0;
+        let mut tps_count = 0;
+
+        for player in players {
+            let res = player
+                .poll_join(&mut self.context)
+                .and_then(|()| player.tick(self.args, &mut self.context))
+                .and_then(|()| {
+                    match &mut player.ctx_write {
+                        // Nothing else releases what a rate limit held back
+                        Some(ctx_write) if !player.kicked => ctx_write.release_limited(),
+                        _ => Ok(()),
+                    }
+                });
+
+            if player.last_game_time.1 > self.last_tick && !player.tps.is_nan() {
+                tps_total += player.tps;
+                tps_count += 1;
+            }
+
+            if let Err(error) = res {
+                handle_error(player, error, &self.worker);
+            }
+        }
+
+        *self.worker.compression_tx.lock().unwrap() = self.context.g_write_ctx.compression_stats;
+        *self.worker.compression_rx.lock().unwrap() = self.ctx_read.compression_stats;
+
+        if tps_count != 0 {
+            self.worker
+                .console_bound
+                .0
+                .send(ConsoleMessage::TPS(tps_total, tps_count))
+                .context("Send msg")?;
+        }
+
+        self.last_tick = Instant::now();
+
+        Ok(())
+    }
+
+    /// Sends the handshake and login start of a bot whose socket just connected
+    pub fn login<S>(&mut self, player: &mut Player<S>)
+    where
+        for<'b> &'b S: Read + Write,
+    {
+        let res = login(
+            player,
+            self.args.server.0,
+            &mut self.context,
+            &self.worker,
+            self.args.proto_id.unwrap_or(PROTOCOL_VERSION),
+            self.args.bungee_forwarding,
+        );
+
+        if let Err(error) = res {
+            handle_error(player, error, &self.worker);
+        }
+    }
+
+    /// Writes whatever a bot couldn't write before its socket filled up
+    pub fn write<S>(&mut self, player: &mut Player<S>) -> anyhow::Result<()>
+    where
+        for<'b> &'b S: Read + Write,
+    {
+        if player.kicked {
+            return Ok(());
+        }
+
+        let res = player
+            .ctx_write
+            .as_mut()
+            .context("Connection writer theft")?
+            .write_unwritten();
+
+        if let Err(error) = res {
+            handle_error(player, error, &self.worker);
+        }
+
+        Ok(())
+    }
+
+    /// Reads and handles every packet a bot's socket has buffered
+    pub fn read<S>(&mut self, player: &mut Player<S>)
+    where
+        for<'b> &'b S: Read + Write,
+    {
+        if player.kicked {
+            return;
+        }
+
+        if let Some(mut player_read) = player.ctx_read.take() {
+            let res = player_read.read_packets(&mut self.ctx_read, player, &mut self.context);
+
+            if let Err(error) = res {
+                handle_error(player, error, &self.worker);
+            }
+
+            player.ctx_read = Some(player_read);
+        }
+    }
+}
+
 fn generate_messages(args: &Args) -> anyhow::Result<Vec<String>> {
     if let Some(name) = &args.message_file {
         let file = fs::read_to_string(name).context("Could not read `message_file`")?;
@@ -179,7 +378,8 @@ fn create_bot(
     poll: &mut Poll,
     server: SocketAddr,
     username: String,
-    worker: &Arc<Worker>,
+    driver: &Driver,
+    impairment: Option<&Impairment>,
 ) -> Option<(Token, Player<Backend>)> {
     info!("Starting Bot: {}", username);
 
@@ -197,54 +397,69 @@ fn create_bot(
         .register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)
         .expect("Register");
 
-    let stream = LoggedStream(stream, worker.clone());
-    let player = Player::new(stream, username);
+    let stream = LoggedStream(stream, driver.worker.clone());
+    let stream = ImpairedStream::new(stream, impairment.map(Impairment::link));
 
-    Some((token, player))
+    Some((token, driver.create_player(stream, username)))
 }
 
-fn connect_bot(
-    player: &mut Player<Backend>,
-    server: SocketAddr,
-    ctx: &mut WorkerContext,
-    worker: &Worker,
-    protocol_version: u32,
-) -> Result<(), CommunicationError> {
-    match player.socket.0.peer_addr() {
-        Err(err) if err.kind() == ErrorKind::NotConnected => return Ok(()),
+// Whether the non blocking connect of a bot finished
+fn connected(player: &mut Player<Backend>) -> Result<bool, CommunicationError> {
+    let socket = &player.socket.get_ref().0;
+
+    match socket.peer_addr() {
+        Err(err) if err.kind() == ErrorKind::NotConnected => return Ok(false),
         Err(err) => return Err(err.into()),
         _ => (),
     }
 
-    player.socket.0.set_nodelay(true)?;
+    socket.set_nodelay(true)?;
+
+    Ok(true)
+}
+
+fn login<S>(
+    player: &mut Player<S>,
+    server: SocketAddr,
+    ctx: &mut WorkerContext,
+    worker: &Worker,
+    protocol_version: u32,
+    bungee_forwarding: bool,
+) -> Result<(), CommunicationError>
+where
+    for<'a> &'a S: Read + Write,
+{
+    let host = server.ip().to_string();
+    let server_address = if bungee_forwarding {
+        identity::bungee_address(&host, player.forwarded_ip, player.uuid)
+    } else {
+        host
+    };
 
     let handshake = HandshakePacket {
         protocol_version,
-        server_address: &server.ip().to_string(),
+        server_address: &server_address,
         server_port: server.port(),
-        next_state: LoginProtoC2S::PROTOCOL_ID,
+        next_state: ProtocolState::Login.id(),
     };
 
     let login_start = LoginStartPacket {
         username: &player.username,
         signature_data: None,
-        uuid: None,
+        uuid: Some(player.uuid),
     };
 
     player
         .ctx_write
         .as_mut()
         .ok_or("Connection writer theft")?
-        .write_packets(
-            &mut ctx.g_write_ctx,
-            player.compression_threshold,
-            |writer| {
-                writer.write_packet(&handshake)?;
-                writer.write_packet(&login_start)?;
+        .write_packets(&mut ctx.g_write_ctx, &mut player.protocol, |writer| {
+            writer.write_packet(&handshake)?;
+            writer.state().transition(ProtocolState::Login)?;
+            writer.write_packet(&login_start)?;
 
-                Ok(())
-            },
-        )?;
+            Ok(())
+        })?;
 
     info!("Bot Connected: {}", player.username);
     worker
@@ -259,7 +474,7 @@ fn connect_bot(
     Ok(())
 }
 
-fn handle_error<S>(player: &mut Player<S>, error: CommunicationError, worker: &Worker) {
+pub(crate) fn handle_error<S>(player: &mut Player<S>, error: CommunicationError, worker: &Worker) {
     player.kicked = true;
 
     warn!("Bot encountered error {}: {}", player.username, error);
@@ -298,6 +513,16 @@ impl Write for LoggedStream {
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        match self.0.write_vectored(bufs) {
+            Ok(amount) => {
+                self.1.bytes_tx.fetch_add(amount as u64, Ordering::Relaxed);
+                Ok(amount)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
     fn flush(&mut self) -> std::io::Result<()> {
         self.0.flush()
     }
@@ -326,6 +551,16 @@ impl Write for &LoggedStream {
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        match (&self.0).write_vectored(bufs) {
+            Ok(amount) => {
+                self.1.bytes_tx.fetch_add(amount as u64, Ordering::Relaxed);
+                Ok(amount)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
     fn flush(&mut self) -> std::io::Result<()> {
         (&self.0).flush()
     }
@@ -1,12 +1,19 @@
 use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
     fmt::Display,
     sync::{atomic::Ordering, Arc},
     time::Duration,
 };
 
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MouseEvent};
+use mc_io::packet::compression::CompressionStats;
+use proto::Direction;
 
-use crate::threading::{ConsoleMessage, Worker};
+use crate::{
+    stats::PacketTypeStats,
+    threading::{ConsoleMessage, Worker},
+};
 
 #[derive(Default, Debug, Clone)]
 pub struct App<'a> {
@@ -21,6 +28,14 @@ pub struct App<'a> {
     pub packets_tx: u64,
     pub packets_rx: u64,
 
+    pub login_anomalies: u64,
+
+    pub compression_tx: CompressionStats,
+    pub compression_rx: CompressionStats,
+
+    // Sorted by bytes, largest first
+    pub clientbound_packets: Vec<PacketTypeStats>,
+
     pub tps: Vec<u64>,
 
     pub should_quit: bool,
@@ -67,6 +82,10 @@ impl<'a> App<'a> {
         let mut bytes_rx_next = 0;
         let mut packets_tx_next = 0;
         let mut packets_rx_next = 0;
+        let mut login_anomalies_next = 0;
+        let mut clientbound_packets = BTreeMap::new();
+        let mut compression_tx = CompressionStats::default();
+        let mut compression_rx = CompressionStats::default();
 
         let mut tps_data = None;
 
@@ -95,6 +114,19 @@ impl<'a> App<'a> {
             bytes_rx_next += worker.bytes_rx.load(Ordering::Relaxed);
             packets_tx_next += worker.packets_tx.load(Ordering::Relaxed);
             packets_rx_next += worker.packets_rx.load(Ordering::Relaxed);
+            login_anomalies_next += worker.login_anomalies.load(Ordering::Relaxed);
+            compression_tx += *worker.compression_tx.lock().unwrap();
+            compression_rx += *worker.compression_rx.lock().unwrap();
+
+            for stats in worker.packet_stats.types(Direction::ServerToClient) {
+                clientbound_packets
+                    .entry((stats.state.id(), stats.id))
+                    .and_modify(|total: &mut PacketTypeStats| {
+                        total.packets += stats.packets;
+                        total.bytes += stats.bytes;
+                    })
+                    .or_insert(stats);
+            }
         }
 
         let bandwidth_tx = (bytes_tx_next - bytes_tx_last) as f64 / tick_time.as_secs_f64();
@@ -123,6 +155,15 @@ impl<'a> App<'a> {
         self.packets_tx = packets_tx_next;
         self.packets_rx = packets_rx_next;
 
+        self.login_anomalies = login_anomalies_next;
+
+        self.compression_tx = compression_tx;
+        self.compression_rx = compression_rx;
+
+        self.clientbound_packets = clientbound_packets.into_values().collect();
+        self.clientbound_packets
+            .sort_by_key(|stats| Reverse(stats.bytes));
+
         self.tick += 1;
     }
 }
@@ -1,10 +1,11 @@
+use mc_io::packet::compression::CompressionStats;
 use tui::{
     backend::Backend,
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style},
     symbols::Marker,
     text::Span,
-    widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, Paragraph, Sparkline},
+    widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, Paragraph, Row, Sparkline, Table},
     Frame,
 };
 use tui_logger::TuiLoggerWidget;
@@ -20,7 +21,7 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     let chunks = Layout::default()
         .constraints(
             [
-                Constraint::Length(9),
+                Constraint::Length(10),
                 Constraint::Percentage(60),
                 Constraint::Percentage(0),
             ]
@@ -37,7 +38,7 @@ fn draw_stats<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     let chunks = Layout::default()
         .constraints(
             [
-                Constraint::Length(2),
+                Constraint::Length(3),
                 Constraint::Length(5),
                 Constraint::Length(0),
             ]
@@ -50,8 +51,9 @@ fn draw_stats<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     f.render_widget(block, area);
 
     let message = format!(
-        "Server: {}, Bots connected: {:6}, Bytes tx: {}, Bytes rx: {}, Packets tx: {:6}, Packets rx: {:6}",
-        app.server, app.bots, app.bytes_tx, app.bytes_rx, app.packets_tx, app.packets_rx
+        "Server: {}, Bots connected: {:6}, Bytes tx: {}, Bytes rx: {}, Packets tx: {:6}, Packets rx: {:6}, Login anomalies: {}\nCompression tx: {}, Compression rx: {}",
+        app.server, app.bots, app.bytes_tx, app.bytes_rx, app.packets_tx, app.packets_rx, app.login_anomalies,
+        compression(&app.compression_tx), compression(&app.compression_rx)
     );
     let status = Paragraph::new(message).block(Block::default().title("Status:"));
     f.render_widget(status, chunks[0]);
@@ -65,14 +67,38 @@ fn draw_stats<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     f.render_widget(tps, chunks[1]);
 }
 
+fn compression(stats: &CompressionStats) -> String {
+    let ratio = match stats.ratio() {
+        Some(ratio) => format!("{:5.1}%", ratio * 100.0),
+        None => "    -".to_owned(),
+    };
+
+    format!(
+        "{} of {} ({} packets, {} skipped) in {:.2}s",
+        ratio,
+        Bytes(stats.uncompressed_bytes),
+        stats.compressed_packets,
+        stats.skipped_packets,
+        stats.time.as_secs_f64()
+    )
+}
+
 fn draw_graphs<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     let chunks = Layout::default()
-        .constraints([Constraint::Percentage(40), Constraint::Percentage(0)].as_ref())
+        .constraints(
+            [
+                Constraint::Percentage(30),
+                Constraint::Percentage(40),
+                Constraint::Percentage(0),
+            ]
+            .as_ref(),
+        )
         .direction(Direction::Horizontal)
         .split(area);
 
     draw_players(f, app, chunks[0]);
     draw_bandwidth(f, app, chunks[1]);
+    draw_packets(f, app, chunks[2]);
 }
 
 fn draw_players<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
@@ -192,6 +218,47 @@ fn draw_bandwidth<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     f.render_widget(bandwidth, area);
 }
 
+fn draw_packets<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
+    let total = app
+        .clientbound_packets
+        .iter()
+        .map(|stats| stats.bytes)
+        .sum::<u64>()
+        .max(1);
+
+    let rows = app.clientbound_packets.iter().map(|stats| {
+        let name = stats
+            .state
+            .packet_name(proto::Direction::ServerToClient, stats.id)
+            .unwrap_or_else(|| format!("Unknown(0x{:02x})", stats.id));
+
+        Row::new(vec![
+            name.trim_end_matches("Packet").to_owned(),
+            stats.packets.to_string(),
+            Bytes(stats.bytes).to_string(),
+            format!("{:5.1}%", stats.bytes as f64 * 100.0 / total as f64),
+        ])
+    });
+
+    let packets = Table::new(rows)
+        .header(
+            Row::new(vec!["Packet", "Count", "Bytes", "Share"])
+                .style(Style::default().fg(Color::Red)),
+        )
+        .block(
+            Block::default()
+                .title("Clientbound packets")
+                .borders(Borders::ALL),
+        )
+        .widths(&[
+            Constraint::Percentage(40),
+            Constraint::Percentage(20),
+            Constraint::Percentage(25),
+            Constraint::Percentage(15),
+        ]);
+    f.render_widget(packets, area);
+}
+
 fn draw_logs<B: Backend>(f: &mut Frame<B>, _app: &mut App, area: Rect) {
     let logs =
         TuiLoggerWidget::default().block(Block::default().title("Logs").borders(Borders::ALL));
@@ -1,8 +1,20 @@
+use std::sync::Arc;
+
 use mc_io::GlobalWriteContext;
 
+use crate::{
+    login_plugin::LoginPluginResponder, replay::Replay, session::SessionJoiner, threading::Worker,
+};
+
 pub type Context = ContextInner;
 
 pub struct ContextInner {
     pub messages: Vec<String>,
     pub g_write_ctx: GlobalWriteContext,
+    // Bots skip authentication when this isn't set
+    pub session: Option<SessionJoiner>,
+    pub login_plugins: Box<dyn LoginPluginResponder>,
+    pub worker: Arc<Worker>,
+    // Bots replay this instead of generating movement and actions when set
+    pub replay: Option<Arc<Replay>>,
 }
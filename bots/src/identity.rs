@@ -0,0 +1,48 @@
+use rand::Rng;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// A random version 4 uuid
+pub fn random_uuid() -> u128 {
+    let mut uuid: [u8; 16] = rand::random();
+
+    uuid[6] = uuid[6] & 0x0f | 0x40;
+    uuid[8] = uuid[8] & 0x3f | 0x80;
+
+    u128::from_be_bytes(uuid)
+}
+
+/// Parses a uuid with or without dashes
+pub fn parse_uuid(uuid: &str) -> Result<u128, String> {
+    let hex = uuid.replace('-', "");
+
+    if hex.len() != 32 {
+        return Err(format!("`{uuid}` is not a uuid"));
+    }
+
+    u128::from_str_radix(&hex, 16).map_err(|err| format!("`{uuid}` is not a uuid: {err}"))
+}
+
+/// A random ipv4 address that could belong to a real client
+pub fn random_ip() -> IpAddr {
+    let mut rng = rand::thread_rng();
+
+    loop {
+        let ip = Ipv4Addr::from(rng.gen::<u32>());
+
+        if !(ip.is_unspecified()
+            || ip.is_private()
+            || ip.is_loopback()
+            || ip.is_link_local()
+            || ip.is_multicast()
+            || ip.is_broadcast()
+            || ip.is_documentation())
+        {
+            return IpAddr::V4(ip);
+        }
+    }
+}
+
+/// The handshake address used by BungeeCord's legacy ip forwarding
+pub fn bungee_address(host: &str, client_ip: IpAddr, uuid: u128) -> String {
+    format!("{host}\0{client_ip}\0{uuid:032x}")
+}
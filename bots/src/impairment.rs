@@ -0,0 +1,418 @@
+//! Simulates a laggy link between each bot and the server. Everything a bot sends and receives is
+//! held back by a one way latency picked per bot, with jitter on top, and the link of a bot can
+//! stall periodically. Held back data is released by the worker loop, which keeps a timer queue
+//! of when each bot has data due.
+
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::args::Args;
+
+// Writes report `WouldBlock` past this, and reads stop taking data off the socket, until some of
+// the held back data is released
+const MAX_HELD: usize = 256 * 1024;
+const READ_CHUNK: usize = 16 * 1024;
+
+/// A range of milliseconds, written as `50` or `50-150`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Millis {
+    pub min: u64,
+    pub max: u64,
+}
+
+pub fn parse_millis(range: &str) -> Result<Millis, String> {
+    let parse = |it: &str| {
+        it.trim()
+            .parse()
+            .map_err(|err| format!("`{it}` is not a time in ms: {err}"))
+    };
+
+    let (min, max) = match range.split_once('-') {
+        Some((min, max)) => (parse(min)?, parse(max)?),
+        None => (parse(range)?, parse(range)?),
+    };
+
+    if min > max {
+        return Err(format!("`{range}` starts after it ends"));
+    }
+
+    Ok(Millis { min, max })
+}
+
+/// How the links of the bots are impaired, each bot picks its own latency and stall phase from
+/// these
+#[derive(Clone, Debug)]
+pub struct Impairment {
+    latency: Millis,
+    jitter: u64,
+    stall_interval: Option<u64>,
+    stall_duration: u64,
+}
+
+impl Impairment {
+    /// `None` if the args don't impair the links at all
+    pub fn from_args(args: &Args) -> Option<Self> {
+        let latency = args.latency.unwrap_or(Millis { min: 0, max: 0 });
+
+        if latency.max == 0 && args.jitter == 0 && args.stall_interval.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            latency,
+            jitter: args.jitter,
+            stall_interval: args.stall_interval,
+            stall_duration: args.stall_duration,
+        })
+    }
+
+    /// The link of a single bot
+    pub fn link(&self) -> Link {
+        let mut rng = rand::thread_rng();
+        let now = Instant::now();
+
+        Link {
+            latency: Duration::from_millis(rng.gen_range(self.latency.min..=self.latency.max)),
+            jitter: self.jitter,
+            stalls: self
+                .stall_interval
+                .filter(|&interval| interval > 0)
+                .map(|interval| Stalls {
+                    // Bots don't all stall at once
+                    start: now + Duration::from_millis(rng.gen_range(0..interval)),
+                    interval: Duration::from_millis(interval),
+                    duration: Duration::from_millis(self.stall_duration),
+                }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Link {
+    latency: Duration,
+    jitter: u64,
+    stalls: Option<Stalls>,
+}
+
+// The link stalls for `duration` every `interval`, starting at `start`
+#[derive(Clone, Debug)]
+struct Stalls {
+    start: Instant,
+    interval: Duration,
+    duration: Duration,
+}
+
+impl Link {
+    // When data that was sent at `now` gets through. Data stays in order, so it never gets
+    // through before `previous`.
+    fn arrival(&self, now: Instant, previous: Option<Instant>) -> Instant {
+        let jitter = match self.jitter {
+            0 => Duration::ZERO,
+            jitter => Duration::from_millis(rand::thread_rng().gen_range(0..=jitter)),
+        };
+        let mut at = now + self.latency + jitter;
+
+        if let Some(previous) = previous {
+            at = at.max(previous);
+        }
+
+        match &self.stalls {
+            Some(stalls) => stalls.after(at),
+            None => at,
+        }
+    }
+}
+
+impl Stalls {
+    // `at`, or the end of the stall it falls into
+    fn after(&self, at: Instant) -> Instant {
+        let Some(since) = at.checked_duration_since(self.start) else {
+            return at;
+        };
+
+        let into_stall = Duration::from_nanos((since.as_nanos() % self.interval.as_nanos()) as u64);
+        if into_stall < self.duration {
+            at + (self.duration - into_stall)
+        } else {
+            at
+        }
+    }
+}
+
+/// Holds back what passes through `S` as its link dictates. Without a link everything is passed
+/// straight through.
+pub struct ImpairedStream<S> {
+    inner: S,
+    link: Option<Link>,
+    held: RefCell<Held>,
+    // The earliest time a timer is queued for, if any
+    armed: Cell<Option<Instant>>,
+}
+
+#[derive(Default)]
+struct Held {
+    incoming: DelayQueue,
+    // When the other side closed the connection, as seen by the bot
+    closed_at: Option<Instant>,
+    outgoing: DelayQueue,
+    // The socket didn't take the due outgoing data, it is sent once the socket is writable again
+    blocked: bool,
+}
+
+#[derive(Default)]
+struct DelayQueue {
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    // Of the first chunk
+    consumed: usize,
+    len: usize,
+}
+
+impl<S> ImpairedStream<S>
+where
+    for<'a> &'a S: Read + Write,
+{
+    pub fn new(inner: S, link: Option<Link>) -> Self {
+        Self {
+            inner,
+            link,
+            held: Default::default(),
+            armed: Cell::new(None),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Sends the outgoing data that is due, to be called once a timer fired or the socket became
+    /// writable
+    pub fn release(&self) -> io::Result<()> {
+        let held = &mut *self.held.borrow_mut();
+        held.blocked = false;
+
+        self.send_due(held, Instant::now())
+    }
+
+    /// When a timer has to fire for this stream, unless one already fires by then
+    pub fn timer(&self) -> Option<Instant> {
+        let held = self.held.borrow();
+
+        let due = [
+            held.incoming.next(),
+            held.closed_at,
+            held.outgoing.next().filter(|_| !held.blocked),
+        ]
+        .into_iter()
+        .flatten()
+        .min()?;
+
+        match self.armed.get() {
+            Some(armed) if armed <= due => None,
+            _ => {
+                self.armed.set(Some(due));
+                Some(due)
+            }
+        }
+    }
+
+    /// Called when a timer of this stream fired at `now`
+    pub fn fired(&self, now: Instant) {
+        if self.armed.get().is_some_and(|armed| armed <= now) {
+            self.armed.set(None);
+        }
+    }
+
+    fn send_due(&self, held: &mut Held, now: Instant) -> io::Result<()> {
+        while !held.blocked {
+            let Some(due) = held.outgoing.due(now) else {
+                break;
+            };
+
+            match (&self.inner).write(due) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(amount) => held.outgoing.consume(amount),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => held.blocked = true,
+                Err(error) if error.kind() == ErrorKind::Interrupted => (),
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Takes everything the socket has, stamped with when it gets through
+    fn receive(&self, link: &Link, held: &mut Held, now: Instant) -> io::Result<()> {
+        let mut buffer = [0; READ_CHUNK];
+
+        while held.closed_at.is_none() && held.incoming.len < MAX_HELD {
+            match (&self.inner).read(&mut buffer) {
+                Ok(0) => held.closed_at = Some(link.arrival(now, held.incoming.last())),
+                Ok(amount) => {
+                    let at = link.arrival(now, held.incoming.last());
+                    held.incoming.push(at, &buffer[..amount]);
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => (),
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl DelayQueue {
+    fn push(&mut self, at: Instant, data: &[u8]) {
+        self.chunks.push_back((at, data.to_vec()));
+        self.len += data.len();
+    }
+
+    fn next(&self) -> Option<Instant> {
+        self.chunks.front().map(|(at, _)| *at)
+    }
+
+    fn last(&self) -> Option<Instant> {
+        self.chunks.back().map(|(at, _)| *at)
+    }
+
+    // The rest of the first chunk, if it is due
+    fn due(&self, now: Instant) -> Option<&[u8]> {
+        match self.chunks.front() {
+            Some((at, data)) if *at <= now => Some(&data[self.consumed..]),
+            _ => None,
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.consumed += amount;
+        self.len -= amount;
+
+        if self
+            .chunks
+            .front()
+            .is_some_and(|(_, data)| data.len() == self.consumed)
+        {
+            self.chunks.pop_front();
+            self.consumed = 0;
+        }
+    }
+}
+
+impl<S> Read for &ImpairedStream<S>
+where
+    for<'a> &'a S: Read + Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(link) = &self.link else {
+            return (&self.inner).read(buf);
+        };
+
+        let held = &mut *self.held.borrow_mut();
+        let now = Instant::now();
+        self.receive(link, held, now)?;
+
+        match held.incoming.due(now) {
+            Some(due) => {
+                let len = due.len().min(buf.len());
+                buf[..len].copy_from_slice(&due[..len]);
+                held.incoming.consume(len);
+
+                Ok(len)
+            }
+            None if held.incoming.len == 0 && held.closed_at.is_some_and(|at| at <= now) => Ok(0),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S> Write for &ImpairedStream<S>
+where
+    for<'a> &'a S: Read + Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let Some(link) = &self.link else {
+            return (&self.inner).write_vectored(bufs);
+        };
+        // Nothing to queue, an empty chunk would make `send_due` fail with `WriteZero`
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Ok(0);
+        }
+
+        let held = &mut *self.held.borrow_mut();
+        let now = Instant::now();
+        // Sent first, so the socket is full before data is queued behind it
+        self.send_due(held, now)?;
+
+        if held.outgoing.len >= MAX_HELD {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        let at = link.arrival(now, held.outgoing.last());
+        held.outgoing.push(at, &data);
+
+        // Without latency the data may be due already
+        self.send_due(held, now)?;
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.inner).flush()
+    }
+}
+
+/// When each bot has held back data due, ordered by time
+pub struct Timers<T> {
+    queue: BinaryHeap<Reverse<(Instant, T)>>,
+}
+
+impl<T: Ord + Copy> Timers<T> {
+    pub fn new() -> Self {
+        Self {
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    /// Queues a timer for the stream of `token` if it needs one
+    pub fn schedule<S>(&mut self, token: T, stream: &ImpairedStream<S>)
+    where
+        for<'a> &'a S: Read + Write,
+    {
+        if let Some(at) = stream.timer() {
+            self.queue.push(Reverse((at, token)));
+        }
+    }
+
+    /// The time until the next timer fires
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.queue
+            .peek()
+            .map(|Reverse((at, _))| at.saturating_duration_since(now))
+    }
+
+    /// Removes the timers that fired by `now`, returning their tokens
+    pub fn expired(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+
+        while let Some(Reverse((at, token))) = self.queue.peek().copied() {
+            if at > now {
+                break;
+            }
+
+            self.queue.pop();
+            expired.push(token);
+        }
+
+        expired
+    }
+}
@@ -0,0 +1,20 @@
+use std::sync::atomic::AtomicBool;
+
+pub use crate::args::Args;
+
+pub mod args;
+pub mod bot;
+pub mod console;
+pub mod context;
+mod identity;
+mod impairment;
+mod login_plugin;
+mod player;
+pub mod replay;
+mod session;
+pub mod stats;
+pub mod threading;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub static STOP_THE_WORLD: AtomicBool = AtomicBool::new(false);
@@ -0,0 +1,87 @@
+use hmac::{Hmac, Mac};
+use proto::packets::s2c::login::Property;
+use proto::primitive::VarInt;
+use proto::{define_data, Data};
+use sha2::Sha256;
+use std::net::IpAddr;
+
+pub const VELOCITY_CHANNEL: &str = "velocity:player_info";
+
+// The forwarding version without player chat keys
+const VELOCITY_DEFAULT_VERSION: i32 = 1;
+
+/// The bot a login plugin request was sent to
+pub struct BotInfo<'a> {
+    pub username: &'a str,
+    pub uuid: u128,
+    pub address: IpAddr,
+}
+
+/// Answers login plugin requests, eg. from proxies or mods
+pub trait LoginPluginResponder {
+    /// The data to respond with, `None` tells the server that the channel isn't understood
+    fn respond(&self, channel: &str, data: &[u8], bot: &BotInfo) -> Option<Vec<u8>>;
+}
+
+/// Doesn't understand any channel
+pub struct Unsupported;
+
+impl LoginPluginResponder for Unsupported {
+    fn respond(&self, _: &str, _: &[u8], _: &BotInfo) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Answers `velocity:player_info` as a Velocity proxy using modern forwarding would, so bots can
+/// join backend servers directly
+pub struct VelocityForwarding {
+    secret: Vec<u8>,
+}
+
+impl VelocityForwarding {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+}
+
+define_data! {
+    pub struct VelocityPlayerInfo<'a> {
+        pub version: i32 as VarInt,
+        pub address: &'a str,
+        pub uuid: u128,
+        pub username: &'a str,
+        pub properties: Vec<Property<'a>>
+    }
+}
+
+impl LoginPluginResponder for VelocityForwarding {
+    fn respond(&self, channel: &str, _: &[u8], bot: &BotInfo) -> Option<Vec<u8>> {
+        if channel != VELOCITY_CHANNEL {
+            return None;
+        }
+
+        let address = bot.address.to_string();
+        let info = VelocityPlayerInfo {
+            version: VELOCITY_DEFAULT_VERSION,
+            address: &address,
+            uuid: bot.uuid,
+            username: bot.username,
+            properties: Vec::new(),
+        };
+
+        let mut payload = vec![0; info.expected_size()];
+        let remaining = info.encode(&mut payload).len();
+        payload.truncate(payload.len() - remaining);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes any key");
+        mac.update(&payload);
+
+        // The payload is prefixed with its signature
+        let mut response = mac.finalize().into_bytes().to_vec();
+        response.extend_from_slice(&payload);
+
+        Some(response)
+    }
+}
@@ -1,39 +1,29 @@
-use crate::{
+use anyhow::Context;
+use bots::{
     args::Args,
+    bot, console,
+    replay::Replay,
     threading::{BotMessage, Worker},
+    STOP_THE_WORLD,
 };
-use anyhow::Context;
 use clap::Parser;
-use crossbeam::channel::unbounded;
 use log::{info, LevelFilter};
 use std::{
-    sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc,
-    },
+    sync::{atomic::Ordering, Arc},
     thread,
     time::{Duration, Instant},
 };
 
-mod address;
-mod args;
-mod bot;
-mod console;
-pub mod context;
-mod player;
-mod threading;
-
 const NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub static STOP_THE_WORLD: AtomicBool = AtomicBool::new(false);
-
 // motd, cached reading? (hash first few bytes and lookup in some kind
-// of hash map), make ui more colerful, use write/read vectored?,
+// of hash map), make ui more colerful, use read vectored?,
 // steal graphs from bottom, write docs, write readme, make log widget update
 // faster, make args toggleable by keybind
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    args.validate().unwrap_or_else(|err| err.exit());
 
     if !args.no_ui {
         tui_logger::init_logger(LevelFilter::Info).unwrap();
@@ -59,29 +49,24 @@ fn main() -> anyhow::Result<()> {
 
     info!("Using {} threads", threads);
 
+    let replay = Replay::from_args(&args)?;
+
     let mut workers = Vec::new();
 
     thread::scope(|s| {
         // Worker threads
         for _ in 0..threads {
-            let mut worker = Worker {
-                packets_tx: AtomicU64::new(0),
-                packets_rx: AtomicU64::new(0),
-                bytes_tx: AtomicU64::new(0),
-                bytes_rx: AtomicU64::new(0),
-                bot_bound: unbounded(),
-                console_bound: unbounded(),
-                waker: None,
-            };
-
-            let bot_context = bot::setup_bot(&mut worker).expect("Setup bot");
+            let mut worker = Worker::new();
+
+            let bot_context = bot::setup_bot(&mut worker, &args).expect("Setup bot");
 
             let worker = Arc::new(worker);
 
             {
                 let worker = worker.clone();
+                let replay = replay.clone();
                 s.spawn(|| {
-                    bot::start(bot_context, &args, worker).unwrap();
+                    bot::start(bot_context, &args, worker, replay).unwrap();
                 });
             }
 
@@ -106,13 +91,7 @@ fn main() -> anyhow::Result<()> {
                 let worker = &workers[worker];
 
                 let name = format!("Bot{i}");
-                worker
-                    .bot_bound
-                    .0
-                    .send(BotMessage::ConnectBot(name))
-                    .expect("Send msg");
-
-                worker.waker.as_ref().unwrap().wake().unwrap();
+                worker.send(BotMessage::ConnectBot(name));
 
                 thread::sleep(Duration::from_millis(args.join_rate));
             }
@@ -126,16 +105,14 @@ fn main() -> anyhow::Result<()> {
             loop {
                 if STOP_THE_WORLD.load(Ordering::SeqCst) {
                     for worker in &workers {
-                        worker.bot_bound.0.send(BotMessage::Stop).expect("Send msg");
-                        worker.waker.as_ref().unwrap().wake().unwrap();
+                        worker.send(BotMessage::Stop);
                     }
 
                     break;
                 }
 
                 for worker in &workers {
-                    worker.bot_bound.0.send(BotMessage::Tick).expect("Send msg");
-                    worker.waker.as_ref().unwrap().wake().unwrap();
+                    worker.send(BotMessage::Tick);
                 }
 
                 tick += tick_duration;
@@ -1,26 +1,35 @@
 use std::{
     io::{Read, Write},
+    net::{IpAddr, Ipv4Addr},
     ops::Mul,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     time::{Duration, Instant, SystemTime},
 };
 
+use crossbeam::channel::{Receiver, TryRecvError};
 use euclid::{default::*, Angle};
+use log::warn;
 use mc_io::{
-    error::{CommunicationError, ReadError},
-    ConnectionReadContext, ConnectionWriteContext, PacketHandler, RawPacket,
+    auth,
+    encryption::{self, Decryptor, SharedSecret},
+    error::{AuthError, CommunicationError},
+    observer::Observer,
+    state::{Client, ProtocolState, StateMachine},
+    BufferLimits, ConnectionReadContext, ConnectionWriteContext, PacketHandler, RawPacket,
 };
 use proto::packets::{
     c2s::{
         self,
+        login::{EncryptionResponsePacket, LoginPluginResponsePacket},
         play::{
             AnimationPacket, ChatMesssagePacket, HeldSlotPacket, PlayerActionPacket,
             PositionPacket, PositionRotationPacket,
         },
     },
     s2c::{
-        login::{self, LoginProtoS2C, PacketHandlerLoginProtoS2C},
-        play::{self, PacketHandlerPlayProtoS2C, PlayProtoS2C},
+        login::{self, PacketHandlerLoginProtoS2C},
+        play::{self, PacketHandlerPlayProtoS2C},
+        status::PacketHandlerStatusProtoS2C,
     },
 };
 use rand::{distributions::Standard, prelude::Distribution, seq::SliceRandom, Rng};
@@ -28,6 +37,8 @@ use rand::{distributions::Standard, prelude::Distribution, seq::SliceRandom, Rng
 use crate::{
     args::{Args, Movement},
     context::Context,
+    login_plugin::BotInfo,
+    replay::{self, ReplayState},
 };
 
 pub struct Player<S> {
@@ -36,16 +47,21 @@ pub struct Player<S> {
     pub ctx_write: Option<ConnectionWriteContext<Arc<S>>>,
 
     pub entity_id: u32,
-    pub proto_state: u8,
     pub username: String,
     pub uuid: u128,
+    // The client ip reported to backend servers when forwarding
+    pub forwarded_ip: IpAddr,
 
     // TODO Are 3 flags necessary?
     pub connected: bool,
     pub should_tick: bool,
     pub kicked: bool,
 
-    pub compression_threshold: i32,
+    pub protocol: StateMachine<Client>,
+    // Handed to the reader once the encryption response is sent
+    pub decryptor: Option<Decryptor>,
+    // Set while the session server is asked to let the bot join
+    pub pending_join: Option<PendingJoin>,
 
     pub position: Point3D<f64>,
     pub velocity: Vector3D<f64>,
@@ -59,23 +75,38 @@ pub struct Player<S> {
     pub sneaking: bool,
     pub sprinting: bool,
 
-    state: u8,
+    // Created on the first tick when replaying a capture
+    pub replay: Option<ReplayState>,
+}
+
+/// An encryption response, held back until the session server answered the join
+pub struct PendingJoin {
+    shared_secret: SharedSecret,
+    encrypted_secret: Vec<u8>,
+    encrypted_token: Vec<u8>,
+    joined: Option<Receiver<Result<(), AuthError>>>,
 }
 
 impl<S> Player<S>
 where
     for<'a> &'a S: Read + Write,
 {
-    pub fn new(stream: S, username: String) -> Self {
+    pub fn new(stream: S, username: String, limits: BufferLimits, observer: Observer) -> Self {
         let stream = Arc::new(stream);
         let velocity = (rand::random::<f64>() - 0.5, rand::random::<f64>() - 0.5);
 
+        let mut ctx_read = ConnectionReadContext::new(stream.clone());
+        ctx_read.limits = limits.clone();
+        ctx_read.observer = Some(observer.clone());
+        let mut ctx_write = ConnectionWriteContext::new(stream.clone());
+        ctx_write.limits = limits;
+        ctx_write.observer = Some(observer);
+
         Self {
-            socket: stream.clone(),
-            ctx_read: Some(ConnectionReadContext::new(stream.clone())),
-            ctx_write: Some(ConnectionWriteContext::new(stream)),
+            socket: stream,
+            ctx_read: Some(ctx_read),
+            ctx_write: Some(ctx_write),
             entity_id: 0,
-            proto_state: 0,
             username,
             connected: false,
             should_tick: false,
@@ -85,14 +116,17 @@ where
                 .normalize()
                 .mul(0.2),
             angle_bias: Rotation3D::around_y(Angle::degrees(rand::random::<f64>() * 10.0 - 5.0)),
-            state: LoginProtoS2C::PROTOCOL_ID,
             uuid: 0,
-            compression_threshold: -1,
+            forwarded_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            protocol: StateMachine::new(),
+            decryptor: None,
+            pending_join: None,
             last_game_time: (0, Instant::now()),
             tps: f64::NAN,
             join_time: None,
             sneaking: false,
             sprinting: false,
+            replay: None,
         }
     }
 
@@ -101,10 +135,14 @@ where
             return Ok(());
         }
 
+        if let Some(replay) = ctx.replay.clone() {
+            return replay::tick(self, &replay, args, ctx);
+        }
+
         self.ctx_write
             .as_mut()
             .ok_or("Connection writer theft")?
-            .write_packets(&mut ctx.g_write_ctx, self.compression_threshold, |writer| {
+            .write_packets(&mut ctx.g_write_ctx, &mut self.protocol, |writer| {
                 if !args.no_move {
                     match args.movement {
                         Movement::Biased => {
@@ -230,6 +268,60 @@ where
     }
 }
 
+impl<S> Player<S>
+where
+    for<'a> &'a S: Write,
+{
+    /// Sends the encryption response once the session server accepted the join it waits on
+    pub fn poll_join(&mut self, ctx: &mut Context) -> Result<(), CommunicationError> {
+        let Some(joined) = self.pending_join.as_ref().and_then(|it| it.joined.as_ref()) else {
+            return Ok(());
+        };
+
+        match joined.try_recv() {
+            Ok(res) => {
+                res?;
+
+                let response = self.pending_join.take().expect("Checked above");
+                self.send_encryption_response(response, ctx)
+            }
+            Err(TryRecvError::Empty) => Ok(()),
+            Err(TryRecvError::Disconnected) => Err("Session join thread stopped".into()),
+        }
+    }
+
+    fn send_encryption_response(
+        &mut self,
+        response: PendingJoin,
+        ctx: &mut Context,
+    ) -> Result<(), CommunicationError> {
+        let ctx_write = self.ctx_write.as_mut().ok_or("Connection writer theft")?;
+
+        // The response itself is sent in the clear
+        ctx_write.write_packet(
+            &EncryptionResponsePacket {
+                shared_secret: &response.encrypted_secret,
+                verify_token: &response.encrypted_token,
+            },
+            &mut ctx.g_write_ctx,
+            &self.protocol,
+        )?;
+
+        let (encryptor, decryptor) = encryption::ciphers(&response.shared_secret);
+        ctx_write.cipher = Some(encryptor);
+
+        match &mut self.ctx_read {
+            // Sent after a join, nothing follows the encryption request until the server has the
+            // response
+            Some(ctx_read) => ctx_read.cipher = Some(decryptor),
+            // Sent while reading, the reader picks it up with `take_decryptor`
+            None => self.decryptor = Some(decryptor),
+        }
+
+        Ok(())
+    }
+}
+
 impl<S> PacketHandler<Context> for Player<S>
 where
     for<'a> &'a S: Write,
@@ -239,19 +331,30 @@ where
         packet: RawPacket,
         ctx: &mut Context,
     ) -> Result<(), CommunicationError> {
-        // TODO Fix name
-        match self.state {
-            LoginProtoS2C::PROTOCOL_ID => self.parse_and_handle_login_proto_s2_c(packet, ctx),
-            PlayProtoS2C::PROTOCOL_ID => self.parse_and_handle_play_proto_s2_c(packet, ctx),
-            _ => Err(ReadError::BadProtocolState.into()),
-        }
+        self.protocol.state().handle_s2c(self, packet, ctx)
     }
 
     fn compression_threshold(&self) -> i32 {
-        self.compression_threshold
+        self.protocol.compression_threshold()
+    }
+
+    fn protocol_state(&self) -> ProtocolState {
+        self.protocol.state()
+    }
+
+    fn take_decryptor(&mut self) -> Option<Decryptor> {
+        self.decryptor.take()
     }
 }
 
+// Bots never ask for the server status
+impl<S> PacketHandlerStatusProtoS2C<Context> for Player<S>
+where
+    for<'a> &'a S: Write,
+{
+    type Error = CommunicationError;
+}
+
 impl<S> PacketHandlerLoginProtoS2C<Context> for Player<S>
 where
     for<'a> &'a S: Write,
@@ -268,20 +371,52 @@ where
 
     fn handle_encryption_request_packet(
         &mut self,
-        _: login::EncryptionRequestPacket,
-        _: &mut Context,
+        packet: login::EncryptionRequestPacket,
+        ctx: &mut Context,
     ) -> Result<(), Self::Error> {
-        unimplemented!()
+        let shared_secret = encryption::generate_shared_secret();
+        let (encrypted_secret, encrypted_token) =
+            encryption::encrypt_response(packet.public_key, &shared_secret, packet.verify_token)?;
+
+        let response = PendingJoin {
+            shared_secret,
+            encrypted_secret,
+            encrypted_token,
+            joined: None,
+        };
+
+        match &ctx.session {
+            Some(session) => {
+                let server_hash =
+                    auth::server_hash(packet.server_id, &shared_secret, packet.public_key);
+
+                self.pending_join = Some(PendingJoin {
+                    joined: Some(session.join(self.uuid, server_hash)),
+                    ..response
+                });
+
+                Ok(())
+            }
+            None => self.send_encryption_response(response, ctx),
+        }
     }
 
     fn handle_login_success_packet(
         &mut self,
         packet: login::LoginSuccessPacket,
-        _: &mut Context,
+        ctx: &mut Context,
     ) -> Result<(), Self::Error> {
+        if packet.uuid != self.uuid {
+            warn!(
+                "Bot {} was assigned uuid {:032x} instead of {:032x}",
+                self.username, packet.uuid, self.uuid
+            );
+            ctx.worker.login_anomalies.fetch_add(1, Ordering::Relaxed);
+        }
+
         self.uuid = packet.uuid;
         self.username = packet.username.to_owned();
-        self.state = PlayProtoS2C::PROTOCOL_ID;
+        self.protocol.transition(ProtocolState::Play)?;
 
         Ok(())
     }
@@ -291,17 +426,39 @@ where
         packet: login::SetCompressionPacket,
         _: &mut Context,
     ) -> Result<(), Self::Error> {
-        self.compression_threshold = packet.threshold;
+        self.protocol.set_compression_threshold(packet.threshold);
 
         Ok(())
     }
 
     fn handle_login_plugin_request_packet(
         &mut self,
-        _: login::LoginPluginRequestPacket,
-        _: &mut Context,
+        packet: login::LoginPluginRequestPacket,
+        ctx: &mut Context,
     ) -> Result<(), Self::Error> {
-        unimplemented!()
+        let bot = BotInfo {
+            username: &self.username,
+            uuid: self.uuid,
+            address: self.forwarded_ip,
+        };
+        let response = ctx
+            .login_plugins
+            .respond(packet.channel, packet.data.into(), &bot);
+
+        self.ctx_write
+            .as_mut()
+            .ok_or("Connection writer theft")?
+            .write_packet(
+                &LoginPluginResponsePacket {
+                    message_id: packet.message_id,
+                    successful: response.is_some(),
+                    data: response.as_deref().unwrap_or_default(),
+                },
+                &mut ctx.g_write_ctx,
+                &self.protocol,
+            )?;
+
+        Ok(())
     }
 }
 
@@ -330,7 +487,7 @@ where
             .write_packet(
                 &c2s::play::KeepAlivePacket { id: packet.id },
                 &mut ctx.g_write_ctx,
-                self.compression_threshold,
+                &self.protocol,
             )?;
 
         Ok(())
@@ -358,7 +515,7 @@ where
                     allow_server_listings: true,
                 },
                 &mut ctx.g_write_ctx,
-                self.compression_threshold,
+                &self.protocol,
             )?;
 
         Ok(())
@@ -391,7 +548,7 @@ where
             .write_packet(
                 &c2s::play::TeleportConfirmPacket { id: packet.id },
                 &mut ctx.g_write_ctx,
-                self.compression_threshold,
+                &self.protocol,
             )?;
 
         self.should_tick = true;
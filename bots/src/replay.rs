@@ -0,0 +1,311 @@
+//! Replays the serverbound play packets of a captured session, so bots behave like the player
+//! that was captured
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context as _;
+use euclid::default::{Point3D, Vector3D};
+use mc_io::capture::{CaptureReader, Record};
+use mc_io::error::CommunicationError;
+use mc_io::state::{Client, ProtocolState};
+use mc_io::PacketWriter;
+use proto::packets::c2s::play::{
+    AnimationPacket, ChatMesssagePacket, ClientSettingsPacket, HeldSlotPacket, KeepAlivePacket,
+    PacketHandlerPlayProtoC2S, PlayProtoC2S, PlayerActionPacket, PositionPacket,
+    PositionRotationPacket, RotationPacket, TeleportConfirmPacket,
+};
+use proto::{Data, Direction, Packet};
+use rand::Rng;
+
+use crate::args::Args;
+use crate::context::Context;
+use crate::player::Player;
+
+// Time between the last packet of the replay and the first packet of the next loop
+const LOOP_GAP: Duration = Duration::from_secs(1);
+
+/// The packets replayed by every bot
+pub struct Replay {
+    packets: Vec<Record>,
+    // The first captured position, movement is replayed relative to where each bot spawned
+    origin: Point3D<f64>,
+    duration: Duration,
+}
+
+impl Replay {
+    /// The capture passed with `--replay`, loaded once and shared by every worker
+    pub fn from_args(args: &Args) -> anyhow::Result<Option<Arc<Self>>> {
+        args.replay
+            .as_deref()
+            .map(Self::load)
+            .transpose()
+            .context("Could not load the replay")
+            .map(|replay| replay.map(Arc::new))
+    }
+
+    /// Loads the serverbound play packets of the capture at `path`. Keep alives, teleport
+    /// confirms and settings are left out as bots send their own, as are unknown packets which
+    /// can't be patched.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).context("Open replay capture")?;
+        let reader = CaptureReader::new(BufReader::new(file)).context("Read replay capture")?;
+
+        let mut packets = Vec::new();
+        for record in reader {
+            let record = record.context("Read replay capture")?;
+
+            if record.direction == Direction::ClientToServer
+                && record.state == ProtocolState::Play
+                && is_replayed(&record)
+            {
+                packets.push(record);
+            }
+        }
+
+        anyhow::ensure!(
+            !packets.is_empty(),
+            "The replay capture has no play packets to replay"
+        );
+
+        let origin = packets
+            .iter()
+            .find_map(position)
+            .unwrap_or_else(Point3D::origin);
+        let duration = packets.last().map(|it| it.at).unwrap_or_default();
+
+        Ok(Self {
+            packets,
+            origin,
+            duration,
+        })
+    }
+}
+
+fn is_replayed(record: &Record) -> bool {
+    let Some(id) = record.id().and_then(|id| u8::try_from(id).ok()) else {
+        return false;
+    };
+
+    PlayProtoC2S::from_id(id).is_some()
+        && id != KeepAlivePacket::PACKET_ID_NUM
+        && id != TeleportConfirmPacket::PACKET_ID_NUM
+        && id != ClientSettingsPacket::PACKET_ID_NUM
+}
+
+fn position(record: &Record) -> Option<Point3D<f64>> {
+    let (&id, mut body) = record.data.split_first()?;
+
+    match id {
+        PositionPacket::PACKET_ID_NUM => PositionPacket::try_decode(&mut body)
+            .ok()
+            .map(|it| Point3D::new(it.x, it.y, it.z)),
+        PositionRotationPacket::PACKET_ID_NUM => PositionRotationPacket::try_decode(&mut body)
+            .ok()
+            .map(|it| Point3D::new(it.x, it.y, it.z)),
+        _ => None,
+    }
+}
+
+/// How far a bot is through the replay
+pub struct ReplayState {
+    // When the current loop started
+    start: Instant,
+    next: usize,
+    // When `next` is sent, relative to `start`
+    next_due: Option<Duration>,
+    last_due: Duration,
+    offset: Vector3D<f64>,
+    jitter: Duration,
+}
+
+impl ReplayState {
+    fn new(replay: &Replay, spawn: Point3D<f64>, args: &Args) -> Self {
+        let mut rng = rand::thread_rng();
+
+        let spread = Vector3D::new(
+            rng.gen_range(-1.0..=1.0) * args.replay_spread,
+            0.0,
+            rng.gen_range(-1.0..=1.0) * args.replay_spread,
+        );
+        let jitter = Duration::from_millis(args.replay_jitter);
+
+        Self {
+            start: Instant::now(),
+            next: 0,
+            next_due: None,
+            last_due: Duration::ZERO,
+            offset: spawn - replay.origin + spread,
+            jitter,
+        }
+    }
+
+    // The packets due by `now`, in the order they were captured
+    fn take_due<'a>(&mut self, replay: &'a Replay, now: Instant) -> Vec<&'a Record> {
+        let mut rng = rand::thread_rng();
+        let mut due = Vec::new();
+
+        loop {
+            if self.next == replay.packets.len() {
+                self.start += replay.duration + LOOP_GAP;
+                self.next = 0;
+                self.last_due = Duration::ZERO;
+            }
+
+            let record = &replay.packets[self.next];
+            // Jitter never reorders packets
+            let at = *self.next_due.get_or_insert_with(|| {
+                (record.at + self.jitter.mul_f64(rng.gen())).max(self.last_due)
+            });
+
+            if self.start + at > now {
+                return due;
+            }
+
+            due.push(record);
+            self.last_due = at;
+            self.next_due = None;
+            self.next += 1;
+        }
+    }
+}
+
+/// Sends the replayed packets that are due, in place of the generated movement and actions
+pub fn tick<S>(
+    player: &mut Player<S>,
+    replay: &Replay,
+    args: &Args,
+    ctx: &mut Context,
+) -> Result<(), CommunicationError>
+where
+    for<'a> &'a S: std::io::Read + std::io::Write,
+{
+    let state = player
+        .replay
+        .get_or_insert_with(|| ReplayState::new(replay, player.position, args));
+    let due = state.take_due(replay, Instant::now());
+
+    if due.is_empty() {
+        return Ok(());
+    }
+
+    let mut patcher = Patcher {
+        offset: state.offset,
+        entity_id: player.entity_id,
+        position: &mut player.position,
+    };
+
+    player
+        .ctx_write
+        .as_mut()
+        .ok_or("Connection writer theft")?
+        .write_packets(&mut ctx.g_write_ctx, &mut player.protocol, |writer| {
+            for record in due {
+                patcher.parse_and_handle_play_proto_c2_s(&record.data[..], writer)?;
+            }
+
+            Ok(())
+        })
+}
+
+// Rewrites the fields of captured packets that have to match this bot
+struct Patcher<'p> {
+    offset: Vector3D<f64>,
+    entity_id: u32,
+    position: &'p mut Point3D<f64>,
+}
+
+impl Patcher<'_> {
+    fn moved(&mut self, x: f64, y: f64, z: f64) -> Point3D<f64> {
+        *self.position = Point3D::new(x, y, z) + self.offset;
+        *self.position
+    }
+}
+
+impl PacketHandlerPlayProtoC2S<PacketWriter<'_, '_, '_, Client>> for Patcher<'_> {
+    type Error = CommunicationError;
+
+    fn handle_chat_messsage_packet(
+        &mut self,
+        packet: ChatMesssagePacket,
+        writer: &mut PacketWriter<Client>,
+    ) -> Result<(), Self::Error> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        writer.write_packet(&ChatMesssagePacket {
+            timestamp,
+            ..packet
+        })
+    }
+
+    fn handle_position_packet(
+        &mut self,
+        packet: PositionPacket,
+        writer: &mut PacketWriter<Client>,
+    ) -> Result<(), Self::Error> {
+        let position = self.moved(packet.x, packet.y, packet.z);
+
+        writer.write_packet(&PositionPacket {
+            x: position.x,
+            y: position.y,
+            z: position.z,
+            ..packet
+        })
+    }
+
+    fn handle_position_rotation_packet(
+        &mut self,
+        packet: PositionRotationPacket,
+        writer: &mut PacketWriter<Client>,
+    ) -> Result<(), Self::Error> {
+        let position = self.moved(packet.x, packet.y, packet.z);
+
+        writer.write_packet(&PositionRotationPacket {
+            x: position.x,
+            y: position.y,
+            z: position.z,
+            ..packet
+        })
+    }
+
+    fn handle_rotation_packet(
+        &mut self,
+        packet: RotationPacket,
+        writer: &mut PacketWriter<Client>,
+    ) -> Result<(), Self::Error> {
+        writer.write_packet(&packet)
+    }
+
+    fn handle_player_action_packet(
+        &mut self,
+        packet: PlayerActionPacket,
+        writer: &mut PacketWriter<Client>,
+    ) -> Result<(), Self::Error> {
+        writer.write_packet(&PlayerActionPacket {
+            entity_id: self.entity_id,
+            ..packet
+        })
+    }
+
+    fn handle_held_slot_packet(
+        &mut self,
+        packet: HeldSlotPacket,
+        writer: &mut PacketWriter<Client>,
+    ) -> Result<(), Self::Error> {
+        writer.write_packet(&packet)
+    }
+
+    fn handle_animation_packet(
+        &mut self,
+        packet: AnimationPacket,
+        writer: &mut PacketWriter<Client>,
+    ) -> Result<(), Self::Error> {
+        writer.write_packet(&packet)
+    }
+}
@@ -0,0 +1,64 @@
+//! Joins with the session server on threads of their own. A join takes a round trip to the
+//! session server, or the whole request timeout when it is unreachable, which would stall every
+//! bot of a worker if it was done on the worker's thread.
+
+use std::thread;
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use mc_io::auth::SessionServer;
+use mc_io::error::AuthError;
+
+// Per worker, the joins of a worker's bots happen at most this many at once
+const JOIN_THREADS: usize = 4;
+
+/// Hands joins to the join threads of a worker, which stop once this is dropped
+pub struct SessionJoiner {
+    jobs: Sender<Job>,
+}
+
+struct Job {
+    profile: u128,
+    server_hash: String,
+    joined: Sender<Result<(), AuthError>>,
+}
+
+impl SessionJoiner {
+    pub fn new(session: SessionServer, access_token: String) -> Self {
+        let (jobs, received) = unbounded::<Job>();
+
+        for _ in 0..JOIN_THREADS {
+            let session = session.clone();
+            let access_token = access_token.clone();
+            let received = received.clone();
+
+            thread::Builder::new()
+                .name("Session join".to_owned())
+                .spawn(move || {
+                    for job in received {
+                        let res = session.join(&access_token, job.profile, &job.server_hash);
+                        // The bot may have been dropped in the meantime
+                        let _ = job.joined.send(res);
+                    }
+                })
+                .expect("Spawn session join thread");
+        }
+
+        Self { jobs }
+    }
+
+    /// Joins `profile` to the server identified by `server_hash`, the result is received once the
+    /// session server answered
+    pub fn join(&self, profile: u128, server_hash: String) -> Receiver<Result<(), AuthError>> {
+        let (joined, received) = bounded(1);
+
+        self.jobs
+            .send(Job {
+                profile,
+                server_hash,
+                joined,
+            })
+            .expect("Join threads outlive the joiner");
+
+        received
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use mc_io::observer::PacketEvent;
+use mc_io::state::ProtocolState;
+use proto::Direction;
+
+const STATES: [ProtocolState; 4] = [
+    ProtocolState::Handshake,
+    ProtocolState::Status,
+    ProtocolState::Login,
+    ProtocolState::Play,
+];
+// Every known packet id fits in a byte
+const IDS: usize = 256;
+
+/// The packets of one type seen so far
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketTypeStats {
+    pub state: ProtocolState,
+    pub id: u8,
+    pub packets: u64,
+    /// Bytes sent over the connection
+    pub bytes: u64,
+}
+
+/// Packet counts and wire bytes per packet type, for each direction and state
+pub struct PacketStats {
+    packets: Box<[AtomicU64]>,
+    bytes: Box<[AtomicU64]>,
+}
+
+impl PacketStats {
+    pub fn new() -> Self {
+        let len = 2 * STATES.len() * IDS;
+
+        Self {
+            packets: (0..len).map(|_| AtomicU64::new(0)).collect(),
+            bytes: (0..len).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Counts `packet`, packets with ids that aren't known to the protocol are ignored
+    pub fn add(&self, packet: &PacketEvent) {
+        let Ok(id) = u8::try_from(packet.id) else {
+            return;
+        };
+        let index = index(packet.direction, packet.state, id);
+
+        self.packets[index].fetch_add(1, Ordering::Relaxed);
+        self.bytes[index].fetch_add(packet.wire_size as u64, Ordering::Relaxed);
+    }
+
+    /// Every packet type that was seen in `direction`
+    pub fn types(&self, direction: Direction) -> impl Iterator<Item = PacketTypeStats> + '_ {
+        STATES
+            .into_iter()
+            .flat_map(|state| (0..=u8::MAX).map(move |id| (state, id)))
+            .filter_map(move |(state, id)| {
+                let index = index(direction, state, id);
+                let packets = self.packets[index].load(Ordering::Relaxed);
+
+                (packets != 0).then(|| PacketTypeStats {
+                    state,
+                    id,
+                    packets,
+                    bytes: self.bytes[index].load(Ordering::Relaxed),
+                })
+            })
+    }
+}
+
+fn index(direction: Direction, state: ProtocolState, id: u8) -> usize {
+    let direction = match direction {
+        Direction::ClientToServer => 0,
+        Direction::ServerToClient => 1,
+    };
+
+    (direction * STATES.len() + state as usize) * IDS + id as usize
+}
+
+impl Default for PacketStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for PacketStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketStats").finish_non_exhaustive()
+    }
+}
@@ -1,7 +1,14 @@
-use std::sync::atomic::AtomicU64;
+use std::fmt::Debug;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
-use crossbeam::channel::{Receiver, Sender};
-use mio::Waker;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use mc_io::observer::{PacketEvent, PacketObserver};
+use mc_io::packet::compression::CompressionStats;
+use proto::Direction;
+
+use crate::stats::PacketStats;
 
 pub enum ConsoleMessage {
     BotConnected,
@@ -21,9 +28,74 @@ pub struct Worker {
     pub packets_rx: AtomicU64,
     pub bytes_tx: AtomicU64,
     pub bytes_rx: AtomicU64,
+    // Logins where the server assigned a different uuid than the one sent
+    pub login_anomalies: AtomicU64,
+    pub packet_stats: PacketStats,
+    // Updated every tick from the worker's global contexts
+    pub compression_tx: Mutex<CompressionStats>,
+    pub compression_rx: Mutex<CompressionStats>,
 
     pub bot_bound: (Sender<BotMessage>, Receiver<BotMessage>),
     pub console_bound: (Sender<ConsoleMessage>, Receiver<ConsoleMessage>),
 
-    pub waker: Option<Waker>,
+    pub waker: Option<Box<dyn Wake>>,
+}
+
+/// Wakes up the bot thread of a worker, so it handles its messages
+pub trait Wake: Debug + Send + Sync {
+    fn wake(&self) -> io::Result<()>;
+}
+
+impl Wake for mio::Waker {
+    fn wake(&self) -> io::Result<()> {
+        mio::Waker::wake(self)
+    }
+}
+
+impl Worker {
+    /// A worker without a waker, which is set up by `bot::setup_bot`
+    pub fn new() -> Self {
+        Self {
+            packets_tx: AtomicU64::new(0),
+            packets_rx: AtomicU64::new(0),
+            bytes_tx: AtomicU64::new(0),
+            bytes_rx: AtomicU64::new(0),
+            login_anomalies: AtomicU64::new(0),
+            packet_stats: PacketStats::new(),
+            compression_tx: Mutex::new(CompressionStats::default()),
+            compression_rx: Mutex::new(CompressionStats::default()),
+            bot_bound: unbounded(),
+            console_bound: unbounded(),
+            waker: None,
+        }
+    }
+
+    /// Sends `message` to the worker's bot thread and wakes it
+    pub fn send(&self, message: BotMessage) {
+        self.bot_bound.0.send(message).expect("Send msg");
+        self.waker
+            .as_ref()
+            .expect("Worker set up")
+            .wake()
+            .expect("Wake");
+    }
+}
+
+// Observes the connections of every bot on the worker
+impl PacketObserver for Worker {
+    fn observe(&self, packet: &PacketEvent) {
+        let packets = match packet.direction {
+            Direction::ClientToServer => &self.packets_tx,
+            Direction::ServerToClient => &self.packets_rx,
+        };
+        packets.fetch_add(1, Ordering::Relaxed);
+
+        self.packet_stats.add(packet);
+    }
+}
+
+impl Default for Worker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
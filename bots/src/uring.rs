@@ -0,0 +1,485 @@
+//! Drives the bots of a worker with io_uring instead of epoll. Each bot keeps one multishot
+//! receive armed that picks buffers from a ring registered with the kernel, and everything the
+//! bots wrote is sent in one batch per loop iteration. Sends are copied into registered buffers
+//! and made with fixed writes, unless every one of them is in use. The bytes still go through
+//! the same codec as on the mio backend, `UringStream` just stands in for the socket.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::slice;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Context;
+use io_uring::types::{CancelBuilder, Fd};
+use io_uring::{cqueue, opcode, squeue, IoUring};
+use log::{info, warn};
+use mc_io::error::CommunicationError;
+
+use crate::bot::{handle_error, Driver};
+use crate::player::Player;
+use crate::replay::Replay;
+use crate::threading::{BotMessage, Wake, Worker};
+use crate::Args;
+
+use self::buf_ring::BufRing;
+use self::send_buffers::SendBuffers;
+use self::stream::UringStream;
+
+mod buf_ring;
+mod send_buffers;
+mod stream;
+
+const ENTRIES: u32 = 4096;
+// Every bot has a multishot receive armed, so completions can come in much faster than
+// submissions
+const COMPLETION_ENTRIES: u32 = 4 * ENTRIES;
+const BUFFERS: u16 = 4096;
+const BUFFER_LEN: usize = 4096;
+const BUFFER_GROUP: u16 = 0;
+// Registered buffers are locked in memory, which counts against `RLIMIT_MEMLOCK`
+const SEND_BUFFERS: u16 = 256;
+const SEND_BUFFER_LEN: usize = 16 * 1024;
+
+const OP_BITS: u32 = 3;
+
+/// The ring of a worker along with what wakes it up
+pub struct UringContext {
+    ring: IoUring,
+    waker: File,
+}
+
+pub fn setup_bot(worker: &mut Worker) -> anyhow::Result<UringContext> {
+    let ring = IoUring::builder()
+        .setup_cqsize(COMPLETION_ENTRIES)
+        .build(ENTRIES)
+        .context("Create io_uring")?;
+
+    // Safety: checked for errors below, and the fd isn't owned by anything else
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Create eventfd");
+    }
+    let waker = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    worker.waker = Some(Box::new(EventFd(
+        waker.try_clone().context("Clone eventfd")?,
+    )));
+
+    Ok(UringContext { ring, waker })
+}
+
+#[derive(Debug)]
+struct EventFd(File);
+
+impl Wake for EventFd {
+    fn wake(&self) -> io::Result<()> {
+        (&self.0).write_all(&1u64.to_ne_bytes())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Wake,
+    Connect,
+    Recv,
+    Send,
+    Cancel,
+}
+
+impl Op {
+    fn user_data(self, token: u64) -> u64 {
+        token << OP_BITS | self as u64
+    }
+
+    fn parse(user_data: u64) -> (u64, Op) {
+        let op = match user_data & ((1 << OP_BITS) - 1) {
+            0 => Op::Wake,
+            1 => Op::Connect,
+            2 => Op::Recv,
+            3 => Op::Send,
+            _ => Op::Cancel,
+        };
+
+        (user_data >> OP_BITS, op)
+    }
+}
+
+struct Connection {
+    player: Player<UringStream>,
+    // Read by the kernel when connecting
+    address: Box<(libc::sockaddr_storage, libc::socklen_t)>,
+    // Operations using the socket or buffers of the bot, which has to stay around until they
+    // completed
+    in_flight: usize,
+    // The registered buffer of the send in flight
+    send_buffer: Option<u16>,
+    closing: bool,
+}
+
+pub fn start(
+    ctx: UringContext,
+    args: &Args,
+    worker: Arc<Worker>,
+    replay: Option<Arc<Replay>>,
+) -> anyhow::Result<()> {
+    let mut buf_ring = BufRing::new(&ctx.ring.submitter(), BUFFERS, BUFFER_LEN, BUFFER_GROUP)
+        .context("Register buffer ring")?;
+    let mut send_buffers = SendBuffers::new(&ctx.ring.submitter(), SEND_BUFFERS, SEND_BUFFER_LEN)
+        .unwrap_or_else(|error| {
+            warn!("Could not register send buffers, sending without them: {error}");
+            SendBuffers::empty()
+        });
+    let mut connections = HashMap::new();
+    // Dropped first, which cancels whatever is still in flight if the loop fails
+    let UringContext { mut ring, waker } = ctx;
+
+    let mut driver = Driver::new(args, worker.clone(), replay)?;
+    let mut next_token = 1;
+    let mut completions = Vec::new();
+    let mut stopping = false;
+
+    push(&mut ring, &poll_waker(&waker))?;
+
+    while !stopping || !connections.is_empty() {
+        ring.submit_and_wait(1).context("Submit")?;
+        completions.extend(
+            ring.completion()
+                .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags())),
+        );
+
+        for (user_data, result, flags) in completions.drain(..) {
+            let (token, op) = Op::parse(user_data);
+
+            if op == Op::Wake {
+                if !cqueue::more(flags) {
+                    push(&mut ring, &poll_waker(&waker))?;
+                }
+                // Resets the counter, the poll only triggers when it goes from zero
+                let _ = (&waker).read(&mut [0; 8]);
+
+                for message in worker.bot_bound.1.try_iter() {
+                    match message {
+                        BotMessage::ConnectBot(_) if stopping => (),
+                        BotMessage::ConnectBot(username) => {
+                            let token = next_token;
+                            next_token += 1;
+
+                            if let Some(mut connection) =
+                                create_bot(args.server.0, username, &driver)
+                            {
+                                let (address, len) = &*connection.address;
+                                let connect = opcode::Connect::new(
+                                    fd(&connection),
+                                    address as *const libc::sockaddr_storage as *const _,
+                                    *len,
+                                )
+                                .build()
+                                .user_data(Op::Connect.user_data(token));
+                                push(&mut ring, &connect)?;
+                                connection.in_flight += 1;
+
+                                connections.insert(token, connection);
+                            }
+                        }
+                        BotMessage::Tick => {
+                            driver.tick(connections.values_mut().map(|it| &mut it.player))?;
+
+                            for (&token, connection) in &mut connections {
+                                send(&mut ring, token, connection, &mut send_buffers)?;
+                            }
+                        }
+                        BotMessage::Stop => {
+                            stopping = true;
+
+                            for connection in connections.values_mut() {
+                                connection.player.kicked = true;
+                            }
+                        }
+                    }
+                }
+
+                continue;
+            }
+
+            let Some(connection) = connections.get_mut(&token) else {
+                // The bot is gone, but its buffer still has to be handed back
+                if let Some(id) = cqueue::buffer_select(flags) {
+                    buf_ring.recycle(id);
+                }
+                continue;
+            };
+
+            match op {
+                Op::Connect => {
+                    connection.in_flight -= 1;
+
+                    let res = if result < 0 {
+                        Err(os_error(result))
+                    } else {
+                        connection.player.socket.socket().set_nodelay(true)
+                    };
+
+                    match res {
+                        _ if connection.closing => (),
+                        Ok(()) => {
+                            driver.login(&mut connection.player);
+                            recv(&mut ring, token, connection, &buf_ring)?;
+                        }
+                        Err(error) => handle_error(&mut connection.player, error.into(), &worker),
+                    }
+                }
+                Op::Recv => {
+                    if !cqueue::more(flags) {
+                        connection.in_flight -= 1;
+                    }
+
+                    if result > 0 {
+                        let id = cqueue::buffer_select(flags).expect("Buffer selected");
+                        let data = buf_ring.buffer(id, result as usize);
+                        connection.player.socket.received(data);
+                        buf_ring.recycle(id);
+
+                        worker.bytes_rx.fetch_add(result as u64, Ordering::Relaxed);
+                        driver.read(&mut connection.player);
+                    }
+
+                    match result {
+                        _ if connection.closing => (),
+                        0 => handle_error(
+                            &mut connection.player,
+                            CommunicationError::Closed,
+                            &worker,
+                        ),
+                        // Every buffer was in use, the receive is armed again below
+                        _ if result == -libc::ENOBUFS => (),
+                        _ if result < 0 => {
+                            handle_error(&mut connection.player, os_error(result).into(), &worker)
+                        }
+                        _ => (),
+                    }
+
+                    if !cqueue::more(flags) {
+                        recv(&mut ring, token, connection, &buf_ring)?;
+                    }
+                }
+                Op::Send => {
+                    connection.in_flight -= 1;
+
+                    if result < 0 {
+                        release_send_buffer(connection, &mut send_buffers);
+
+                        if !connection.closing {
+                            let error = os_error(result).into();
+                            handle_error(&mut connection.player, error, &worker);
+                        }
+                    } else {
+                        worker.bytes_tx.fetch_add(result as u64, Ordering::Relaxed);
+
+                        if let Some(unsent) = connection.player.socket.sent(result as usize) {
+                            push_send(&mut ring, token, connection, &mut send_buffers, unsent)?;
+                        } else {
+                            release_send_buffer(connection, &mut send_buffers);
+                            // Lets the codec write what it held back while the queue was full
+                            driver.write(&mut connection.player)?;
+                        }
+                    }
+                }
+                Op::Wake | Op::Cancel => (),
+            }
+
+            send(&mut ring, token, connection, &mut send_buffers)?;
+        }
+
+        for (&token, connection) in &mut connections {
+            if connection.player.kicked && !connection.closing {
+                connection.closing = true;
+
+                let cancel = opcode::AsyncCancel2::new(CancelBuilder::fd(fd(connection)).all())
+                    .build()
+                    .user_data(Op::Cancel.user_data(token));
+                push(&mut ring, &cancel)?;
+            }
+        }
+
+        connections.retain(|_, connection| !connection.closing || connection.in_flight != 0);
+    }
+
+    buf_ring
+        .unregister(&ring.submitter())
+        .context("Unregister buffer ring")?;
+    send_buffers
+        .unregister(&ring.submitter())
+        .context("Unregister send buffers")?;
+
+    Ok(())
+}
+
+fn create_bot(server: SocketAddr, username: String, driver: &Driver) -> Option<Connection> {
+    info!("Starting Bot: {}", username);
+
+    let domain = match server {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // Safety: checked for errors below, and the fd isn't owned by anything else
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        let error = io::Error::last_os_error();
+        warn!("Could not open socket for Bot {}: {}", username, error);
+        return None;
+    }
+    let socket = TcpStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    Some(Connection {
+        player: driver.create_player(UringStream::new(socket), username),
+        address: Box::new(socket_address(server)),
+        in_flight: 0,
+        send_buffer: None,
+        closing: false,
+    })
+}
+
+fn fd(connection: &Connection) -> Fd {
+    Fd(connection.player.socket.socket().as_raw_fd())
+}
+
+fn poll_waker(waker: &File) -> squeue::Entry {
+    opcode::PollAdd::new(Fd(waker.as_raw_fd()), libc::POLLIN as u32)
+        .multi(true)
+        .build()
+        .user_data(Op::Wake.user_data(0))
+}
+
+// Arms the multishot receive of a bot
+fn recv(
+    ring: &mut IoUring,
+    token: u64,
+    connection: &mut Connection,
+    buf_ring: &BufRing,
+) -> io::Result<()> {
+    if connection.closing || connection.player.kicked {
+        return Ok(());
+    }
+
+    let recv = opcode::RecvMulti::new(fd(connection), buf_ring.group())
+        .build()
+        .user_data(Op::Recv.user_data(token));
+    push(ring, &recv)?;
+    connection.in_flight += 1;
+
+    Ok(())
+}
+
+// Sends whatever a bot wrote, unless a send is in flight already
+fn send(
+    ring: &mut IoUring,
+    token: u64,
+    connection: &mut Connection,
+    send_buffers: &mut SendBuffers,
+) -> io::Result<()> {
+    if connection.closing || connection.player.kicked {
+        return Ok(());
+    }
+
+    match connection.player.socket.start_send() {
+        Some(unsent) => push_send(ring, token, connection, send_buffers, unsent),
+        None => Ok(()),
+    }
+}
+
+// Sends as much of `unsent` as fits into a registered buffer with a fixed write, or all of it
+// from where it is when every buffer is in use
+fn push_send(
+    ring: &mut IoUring,
+    token: u64,
+    connection: &mut Connection,
+    send_buffers: &mut SendBuffers,
+    (buf, len): (*const u8, u32),
+) -> io::Result<()> {
+    if connection.send_buffer.is_none() {
+        connection.send_buffer = send_buffers.take();
+    }
+
+    let send = match connection.send_buffer {
+        Some(id) => {
+            // Safety: the unsent bytes stay put until the send completes
+            let unsent = unsafe { slice::from_raw_parts(buf, len as usize) };
+            let (buf, len) = send_buffers.fill(id, unsent);
+
+            // Unlike the send it can't pass `MSG_NOSIGNAL`, but rust ignores `SIGPIPE` already
+            opcode::WriteFixed::new(fd(connection), buf, len, id).build()
+        }
+        None => opcode::Send::new(fd(connection), buf, len)
+            .flags(libc::MSG_NOSIGNAL)
+            .build(),
+    };
+    push(ring, &send.user_data(Op::Send.user_data(token)))?;
+    connection.in_flight += 1;
+
+    Ok(())
+}
+
+fn release_send_buffer(connection: &mut Connection, send_buffers: &mut SendBuffers) {
+    if let Some(id) = connection.send_buffer.take() {
+        send_buffers.release(id);
+    }
+}
+
+// Queues `entry`, submitting what is queued already if the submission queue is full
+fn push(ring: &mut IoUring, entry: &squeue::Entry) -> io::Result<()> {
+    // Safety: a `Connection` outlives every operation on its socket, address and buffers, and
+    // the buffer ring outlives the loop
+    while unsafe { ring.submission().push(entry) }.is_err() {
+        ring.submit()?;
+    }
+
+    Ok(())
+}
+
+fn os_error(result: i32) -> io::Error {
+    io::Error::from_raw_os_error(-result)
+}
+
+fn socket_address(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // Safety: all zeros is a valid `sockaddr_storage`
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let len = match address {
+        SocketAddr::V4(address) => {
+            let address = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: address.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(address.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // Safety: `sockaddr_storage` is big enough and aligned for every address
+            unsafe { (&mut storage as *mut _ as *mut libc::sockaddr_in).write(address) };
+
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            let address = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: address.port().to_be(),
+                sin6_flowinfo: address.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: address.ip().octets(),
+                },
+                sin6_scope_id: address.scope_id(),
+            };
+            // Safety: as above
+            unsafe { (&mut storage as *mut _ as *mut libc::sockaddr_in6).write(address) };
+
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
@@ -0,0 +1,125 @@
+use std::alloc::{self, Layout};
+use std::io;
+use std::slice;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use io_uring::types::BufRingEntry;
+use io_uring::Submitter;
+
+// The kernel maps the ring, so it has to start on a page
+const RING_ALIGN: usize = 4096;
+
+/// Buffers registered with the kernel that multishot receives pick from. Each completion names
+/// the buffer it filled, which is handed back with `recycle` once its data was copied out.
+pub struct BufRing {
+    ring: *mut BufRingEntry,
+    ring_layout: Layout,
+    // Only accessed through `base` once the kernel can write to it
+    _buffers: Box<[u8]>,
+    base: *mut u8,
+    buffer_len: usize,
+    entries: u16,
+    tail: u16,
+    group: u16,
+}
+
+impl BufRing {
+    /// Registers `entries` buffers of `buffer_len` bytes as buffer group `group`, `entries` has
+    /// to be a power of two
+    pub fn new(
+        submitter: &Submitter,
+        entries: u16,
+        buffer_len: usize,
+        group: u16,
+    ) -> io::Result<Self> {
+        assert!(entries.is_power_of_two());
+
+        let ring_layout = Layout::from_size_align(
+            entries as usize * std::mem::size_of::<BufRingEntry>(),
+            RING_ALIGN,
+        )
+        .expect("Valid layout");
+        // Safety: the layout isn't zero sized
+        let ring = unsafe { alloc::alloc_zeroed(ring_layout) } as *mut BufRingEntry;
+        if ring.is_null() {
+            alloc::handle_alloc_error(ring_layout);
+        }
+
+        let mut buffers = vec![0; entries as usize * buffer_len].into_boxed_slice();
+        let base = buffers.as_mut_ptr();
+
+        let mut buf_ring = Self {
+            ring,
+            ring_layout,
+            _buffers: buffers,
+            base,
+            buffer_len,
+            entries,
+            tail: 0,
+            group,
+        };
+
+        for id in 0..entries {
+            buf_ring.push(id);
+        }
+        buf_ring.publish();
+
+        // Safety: the ring and buffers stay allocated until `unregister` is called or the ring
+        // is torn down
+        unsafe {
+            submitter.register_buf_ring_with_flags(ring as u64, entries, group, 0)?;
+        }
+
+        Ok(buf_ring)
+    }
+
+    pub fn group(&self) -> u16 {
+        self.group
+    }
+
+    /// The first `len` bytes of buffer `id`, as filled by the kernel
+    pub fn buffer(&self, id: u16, len: usize) -> &[u8] {
+        assert!(id < self.entries && len <= self.buffer_len);
+
+        // Safety: the kernel is done with the buffer until it is recycled
+        unsafe { slice::from_raw_parts(self.base.add(id as usize * self.buffer_len), len) }
+    }
+
+    /// Hands buffer `id` back to the kernel
+    pub fn recycle(&mut self, id: u16) {
+        self.push(id);
+        self.publish();
+    }
+
+    /// Unregisters the ring, after which the kernel no longer picks buffers from it
+    pub fn unregister(&self, submitter: &Submitter) -> io::Result<()> {
+        submitter.unregister_buf_ring(self.group)
+    }
+
+    fn push(&mut self, id: u16) {
+        let index = (self.tail & (self.entries - 1)) as usize;
+        let addr = self.base.wrapping_add(id as usize * self.buffer_len);
+
+        // Safety: `index` is within the ring, and the kernel doesn't read entries past the
+        // published tail
+        let entry = unsafe { &mut *self.ring.add(index) };
+        entry.set_addr(addr as u64);
+        entry.set_len(self.buffer_len as u32);
+        entry.set_bid(id);
+
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    fn publish(&self) {
+        // Safety: the tail overlaps the first entry, which is shared with the kernel
+        let tail = unsafe { &*(BufRingEntry::tail(self.ring) as *const AtomicU16) };
+        tail.store(self.tail, Ordering::Release);
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        // Safety: allocated in `new` with the same layout
+        unsafe { alloc::dealloc(self.ring as *mut u8, self.ring_layout) }
+    }
+}
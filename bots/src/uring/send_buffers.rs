@@ -0,0 +1,87 @@
+use std::io;
+use std::slice;
+
+use io_uring::Submitter;
+
+/// Buffers registered with the kernel that sends are copied into, which spares the kernel
+/// pinning the pages of every send. A bot holds on to one while its send is in flight and hands
+/// it back with `release` once everything was sent.
+pub struct SendBuffers {
+    // Only accessed through `base` once the kernel can read from it
+    _buffers: Box<[u8]>,
+    base: *mut u8,
+    buffer_len: usize,
+    count: u16,
+    free: Vec<u16>,
+}
+
+impl SendBuffers {
+    /// Registers `count` buffers of `buffer_len` bytes, their index is the id of the buffer
+    pub fn new(submitter: &Submitter, count: u16, buffer_len: usize) -> io::Result<Self> {
+        let mut buffers = vec![0; count as usize * buffer_len].into_boxed_slice();
+        let base = buffers.as_mut_ptr();
+
+        let iovecs: Vec<_> = (0..count as usize)
+            .map(|id| libc::iovec {
+                iov_base: base.wrapping_add(id * buffer_len).cast(),
+                iov_len: buffer_len,
+            })
+            .collect();
+
+        // Safety: the buffers stay allocated until `unregister` is called or the ring is torn
+        // down
+        unsafe { submitter.register_buffers(&iovecs)? };
+
+        Ok(Self {
+            _buffers: buffers,
+            base,
+            buffer_len,
+            count,
+            free: (0..count).rev().collect(),
+        })
+    }
+
+    /// No buffers at all, every send is made from where the bot queued it
+    pub fn empty() -> Self {
+        Self {
+            _buffers: Box::new([]),
+            base: std::ptr::null_mut(),
+            buffer_len: 0,
+            count: 0,
+            free: Vec::new(),
+        }
+    }
+
+    /// A free buffer, if there is one
+    pub fn take(&mut self) -> Option<u16> {
+        self.free.pop()
+    }
+
+    /// Hands buffer `id` back once the kernel is done with it
+    pub fn release(&mut self, id: u16) {
+        self.free.push(id);
+    }
+
+    /// Copies as much of `data` as fits into buffer `id`, returning what the kernel should send
+    pub fn fill(&mut self, id: u16, data: &[u8]) -> (*const u8, u32) {
+        assert!(id < self.count);
+        let len = data.len().min(self.buffer_len);
+
+        // Safety: `id` is one of the buffers, and the kernel is done with it until it is sent
+        // again
+        let buffer =
+            unsafe { slice::from_raw_parts_mut(self.base.add(id as usize * self.buffer_len), len) };
+        buffer.copy_from_slice(&data[..len]);
+
+        (buffer.as_ptr(), len as u32)
+    }
+
+    /// Unregisters the buffers, after which fixed writes can no longer use them
+    pub fn unregister(&self, submitter: &Submitter) -> io::Result<()> {
+        if self.count > 0 {
+            submitter.unregister_buffers()?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+
+// Writes report `WouldBlock` past this, like a full socket would, until the queue is sent
+const MAX_QUEUED: usize = 256 * 1024;
+// Capacity kept by the receive queue once it is read
+const RETAINED: usize = 64 * 1024;
+
+/// The socket of a bot whose io is done by the worker's ring. Received data is handed over with
+/// `received` and read by the codec like from any other socket, and writes are queued until the
+/// worker sends them with its next batch.
+pub struct UringStream {
+    socket: TcpStream,
+    buffers: RefCell<Buffers>,
+}
+
+#[derive(Default)]
+struct Buffers {
+    received: Vec<u8>,
+    read: usize,
+    queued: Vec<u8>,
+    // Owned by the kernel while a send is in flight
+    sending: Vec<u8>,
+    sent: usize,
+}
+
+impl UringStream {
+    pub fn new(socket: TcpStream) -> Self {
+        Self {
+            socket,
+            buffers: Default::default(),
+        }
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Queues `data` to be read by the codec
+    pub fn received(&self, data: &[u8]) {
+        self.buffers.borrow_mut().received.extend_from_slice(data);
+    }
+
+    /// Moves the queued bytes into a new send, unless one is in flight already. The returned
+    /// bytes stay put until the send completes.
+    pub fn start_send(&self) -> Option<(*const u8, u32)> {
+        let buffers = &mut *self.buffers.borrow_mut();
+
+        if !buffers.sending.is_empty() || buffers.queued.is_empty() {
+            return None;
+        }
+
+        mem::swap(&mut buffers.queued, &mut buffers.sending);
+        buffers.sent = 0;
+
+        Some(buffers.unsent())
+    }
+
+    /// Accounts for `amount` bytes of the send in flight, returning what is left to send
+    pub fn sent(&self, amount: usize) -> Option<(*const u8, u32)> {
+        let buffers = &mut *self.buffers.borrow_mut();
+        buffers.sent += amount;
+
+        if buffers.sent < buffers.sending.len() {
+            Some(buffers.unsent())
+        } else {
+            buffers.sending.clear();
+            buffers.sent = 0;
+
+            None
+        }
+    }
+}
+
+impl Buffers {
+    fn unsent(&self) -> (*const u8, u32) {
+        let unsent = &self.sending[self.sent..];
+        (unsent.as_ptr(), unsent.len() as u32)
+    }
+}
+
+impl Read for &UringStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Buffers { received, read, .. } = &mut *self.buffers.borrow_mut();
+        let available = &received[*read..];
+
+        if available.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        *read += len;
+
+        if *read == received.len() {
+            received.clear();
+            received.shrink_to(RETAINED);
+            *read = 0;
+        }
+
+        Ok(len)
+    }
+}
+
+impl Write for &UringStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let queued = &mut self.buffers.borrow_mut().queued;
+
+        if queued.len() >= MAX_QUEUED {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let mut len = 0;
+        for buf in bufs {
+            queued.extend_from_slice(buf);
+            len += buf.len();
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
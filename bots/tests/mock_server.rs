@@ -0,0 +1,367 @@
+//! Runs the bot worker loop against an in-process mock server and checks what the bots sent
+
+use bots::replay::Replay;
+use bots::threading::{BotMessage, Worker};
+use bots::{bot, Args};
+use clap::Parser;
+use mc_io::capture::{CaptureWriter, Record};
+use mc_io::state::ProtocolState;
+use mock_server::record::BotRecord;
+use mock_server::{Config, MockServer};
+use proto::packets::c2s::play::{
+    KeepAlivePacket, PlayerActionPacket, PositionRotationPacket, TeleportConfirmPacket,
+};
+use proto::packets::s2c;
+use proto::{Direction, Packet};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
+
+const BOTS: usize = 4;
+const RADIUS: f64 = 2.0;
+// How far a single movement update can carry a bot past the radius before it turns around
+const STEP: f64 = 0.2;
+
+// Runs `BOTS` bots against a fresh mock server for `duration`, ticking them like `main` does
+fn run_bots(
+    extra_args: &[&str],
+    config: Config,
+    duration: Duration,
+) -> (Vec<BotRecord>, Arc<Worker>) {
+    let mut server = MockServer::bind("127.0.0.1:0".parse().unwrap(), config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let stop = server.stop_handle();
+    let recorder = server.recorder();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let addr = addr.to_string();
+    let count = BOTS.to_string();
+    let mut args = vec!["bots", &addr, &count, "--no-ui"];
+    args.extend_from_slice(extra_args);
+    let args = Args::parse_from(args);
+    args.validate().unwrap();
+
+    let mut worker = Worker::new();
+    let ctx = bot::setup_bot(&mut worker, &args).unwrap();
+    let replay = Replay::from_args(&args).unwrap();
+    let worker = Arc::new(worker);
+
+    let bot_thread = {
+        let worker = worker.clone();
+        let args = args.clone();
+        thread::spawn(move || bot::start(ctx, &args, worker, replay).unwrap())
+    };
+
+    for i in 0..args.count {
+        worker.send(BotMessage::ConnectBot(format!("Bot{i}")));
+    }
+
+    let tick_duration = Duration::from_millis(args.tick_rate);
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        worker.send(BotMessage::Tick);
+        thread::sleep(tick_duration);
+    }
+
+    // Taken before the bots stop, as closing a socket with unread data resets the connection
+    let records = recorder.records();
+
+    worker.send(BotMessage::Stop);
+    bot_thread.join().unwrap();
+
+    stop.stop().unwrap();
+    server_thread.join().unwrap();
+
+    (records, worker)
+}
+
+#[test]
+fn bots_against_mock_server() {
+    let config = Config {
+        compression_threshold: 64,
+        keep_alive_interval: Duration::from_millis(200),
+        record_packets: true,
+        ..Config::default()
+    };
+    let radius = RADIUS.to_string();
+    // Consistent movement only turns around at the edge, which bounds how far out bots get
+    let args = ["--radius", &radius, "--movement", "consistant"];
+
+    let (records, worker) = run_bots(&args, config, Duration::from_secs(2));
+
+    // Logins completed
+    assert_eq!(records.len(), BOTS);
+    for i in 0..BOTS {
+        let username = format!("Bot{i}");
+        assert!(
+            records
+                .iter()
+                .any(|record| record.username.as_deref() == Some(&username)),
+            "{username} did not log in"
+        );
+    }
+    assert_eq!(worker.login_anomalies.load(Ordering::Relaxed), 0);
+
+    // Every packet was counted by type
+    for (direction, total) in [
+        (Direction::ClientToServer, &worker.packets_tx),
+        (Direction::ServerToClient, &worker.packets_rx),
+    ] {
+        let types: Vec<_> = worker.packet_stats.types(direction).collect();
+        let total = total.load(Ordering::Relaxed);
+
+        assert!(total > 0, "no {direction:?} packets were counted");
+        assert_eq!(types.iter().map(|it| it.packets).sum::<u64>(), total);
+        assert!(types.iter().all(|it| it.bytes > it.packets));
+    }
+    assert!(worker
+        .packet_stats
+        .types(Direction::ServerToClient)
+        .any(|it| it.state == ProtocolState::Play
+            && it.id == s2c::play::KeepAlivePacket::PACKET_ID_NUM));
+
+    for record in &records {
+        let username = record.username.as_deref().unwrap();
+        assert_eq!(
+            record.error, None,
+            "{username} was disconnected with an error"
+        );
+
+        // Keep alives were answered
+        assert!(
+            record.decoded::<KeepAlivePacket>().count() > 0,
+            "{username} did not answer keep alives"
+        );
+
+        // The spawn teleport was confirmed
+        assert!(
+            record
+                .decoded::<TeleportConfirmPacket>()
+                .any(|confirm| confirm.id == 1),
+            "{username} did not confirm the spawn teleport"
+        );
+
+        // Movement stayed within the radius
+        let moves: Vec<_> = record.decoded::<PositionRotationPacket>().collect();
+        assert!(!moves.is_empty(), "{username} did not move");
+        for packet in moves {
+            assert!(
+                packet.x.abs() <= RADIUS + STEP && packet.z.abs() <= RADIUS + STEP,
+                "{username} moved to ({}, {}) outside of the radius",
+                packet.x,
+                packet.z
+            );
+        }
+    }
+}
+
+// A short captured session, starting at (100, 70, -50)
+fn write_replay(path: &Path) {
+    let mut records = Vec::new();
+    let mut push = |at: u64, data: Vec<u8>| {
+        records.push(Record {
+            at: Duration::from_millis(at),
+            direction: Direction::ClientToServer,
+            state: ProtocolState::Play,
+            compressed: false,
+            data,
+        })
+    };
+
+    for step in 0..10 {
+        push(
+            step * 50,
+            encode(&PositionRotationPacket {
+                x: 100.0 + step as f64 * 0.1,
+                y: 70.0,
+                z: -50.0,
+                yaw: 0.0,
+                pitch: 0.0,
+                on_ground: true,
+            }),
+        );
+    }
+    push(
+        500,
+        encode(&PlayerActionPacket {
+            entity_id: 999,
+            action: 0,
+            jump_boost: 0,
+        }),
+    );
+    // Answered live by the bots instead
+    push(550, encode(&KeepAlivePacket { id: 12345 }));
+
+    let mut writer = CaptureWriter::new(File::create(path).unwrap()).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn encode<'a, P: Packet<'a>>(packet: &'a P) -> Vec<u8> {
+    let mut data = vec![0; 1 + packet.expected_size()];
+    data[0] = P::PACKET_ID_NUM;
+    let remaining = packet.encode(&mut data[1..]).len();
+    data.truncate(data.len() - remaining);
+    data
+}
+
+#[test]
+fn bots_replay_capture() {
+    let path = env::temp_dir().join(format!("bots-replay-{}.mccap", process::id()));
+    write_replay(&path);
+
+    let config = Config {
+        keep_alive_interval: Duration::from_millis(200),
+        record_packets: true,
+        ..Config::default()
+    };
+    let (spawn_x, spawn_y, spawn_z) = config.spawn;
+    let replay = path.to_str().unwrap();
+    let args = [
+        "--replay",
+        replay,
+        "--replay-spread",
+        "0",
+        "--replay-jitter",
+        "0",
+    ];
+
+    let (records, _) = run_bots(&args, config, Duration::from_millis(1500));
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(records.len(), BOTS);
+    for record in &records {
+        let username = record.username.as_deref().unwrap();
+        assert_eq!(
+            record.error, None,
+            "{username} was disconnected with an error"
+        );
+
+        // Movement is replayed relative to the spawn
+        let moves: Vec<_> = record.decoded::<PositionRotationPacket>().collect();
+        assert!(moves.len() >= 10, "{username} did not replay movement");
+        for (step, packet) in moves.iter().take(10).enumerate() {
+            assert!((packet.x - (spawn_x + step as f64 * 0.1)).abs() < 1e-9);
+            assert_eq!(packet.y, spawn_y);
+            assert_eq!(packet.z, spawn_z);
+        }
+
+        // The captured entity id and keep alive are replaced by live ones
+        let actions: Vec<_> = record.decoded::<PlayerActionPacket>().collect();
+        assert!(!actions.is_empty(), "{username} did not replay actions");
+        assert!(actions.iter().all(|action| action.entity_id != 999));
+        assert!(record
+            .decoded::<KeepAlivePacket>()
+            .all(|keep_alive| keep_alive.id != 12345));
+    }
+}
+
+#[test]
+fn bots_with_packet_limit() {
+    let config = Config {
+        record_packets: true,
+        ..Config::default()
+    };
+    let duration = Duration::from_secs(2);
+    let args = ["--max-packets-per-bot", "5"];
+
+    let (records, _) = run_bots(&args, config, duration);
+
+    assert_eq!(records.len(), BOTS);
+    for record in &records {
+        let username = record.username.as_deref().unwrap();
+        assert_eq!(
+            record.error, None,
+            "{username} was disconnected with an error"
+        );
+
+        // Unlimited bots send a movement update every tick, on top of the login
+        let sent = record.packets.len();
+        assert!(sent > 0, "{username} sent nothing");
+        assert!(sent <= 1 + 5 * 2, "{username} sent {sent} packets");
+    }
+}
+
+#[test]
+fn bots_over_laggy_link() {
+    let config = Config {
+        keep_alive_interval: Duration::from_millis(200),
+        record_packets: true,
+        ..Config::default()
+    };
+    let args = [
+        "--latency",
+        "100-150",
+        "--jitter",
+        "20",
+        "--stall-interval",
+        "700",
+        "--stall-duration",
+        "200",
+    ];
+
+    let (records, _) = run_bots(&args, config, Duration::from_secs(3));
+
+    assert_eq!(records.len(), BOTS);
+    for record in &records {
+        let username = record.username.as_deref().unwrap();
+        assert_eq!(
+            record.error, None,
+            "{username} was disconnected with an error"
+        );
+
+        // The login start takes one trip to the server, the teleport confirm a round trip more
+        let login_start = &record.packets[0];
+        assert_eq!(login_start.state, ProtocolState::Login);
+        assert!(login_start.at >= Duration::from_millis(100));
+
+        let confirm = record
+            .packets
+            .iter()
+            .find(|packet| packet.decode::<TeleportConfirmPacket>().is_some())
+            .unwrap_or_else(|| panic!("{username} did not confirm the spawn teleport"));
+        assert!(confirm.at >= Duration::from_millis(300));
+
+        assert!(
+            record.decoded::<KeepAlivePacket>().count() > 0,
+            "{username} did not answer keep alives"
+        );
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[test]
+fn bots_over_io_uring() {
+    let config = Config {
+        compression_threshold: 64,
+        keep_alive_interval: Duration::from_millis(200),
+        record_packets: true,
+        ..Config::default()
+    };
+
+    let (records, worker) = run_bots(&["--io-uring"], config, Duration::from_secs(2));
+
+    assert_eq!(records.len(), BOTS);
+    assert!(worker.bytes_tx.load(Ordering::Relaxed) > 0);
+    assert!(worker.bytes_rx.load(Ordering::Relaxed) > 0);
+    for record in &records {
+        let username = record.username.as_deref().unwrap();
+        assert_eq!(
+            record.error, None,
+            "{username} was disconnected with an error"
+        );
+        assert!(
+            record.decoded::<KeepAlivePacket>().count() > 0,
+            "{username} did not answer keep alives"
+        );
+        assert!(
+            record.decoded::<PositionRotationPacket>().count() > 0,
+            "{username} did not move"
+        );
+    }
+}
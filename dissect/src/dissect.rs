@@ -0,0 +1,69 @@
+use std::fmt::Write as _;
+
+use mc_io::capture::Record;
+use proto::primitive::VarInt;
+use proto::{Data, DecodingError};
+
+/// A record along with what is known about its packet
+pub struct Dissected<'a> {
+    /// The capture the record came from
+    pub connection: &'a str,
+    pub record: &'a Record,
+    pub id: Option<u64>,
+    /// `None` for unknown packets
+    pub name: Option<String>,
+    /// The decoded fields, `None` for unknown packets
+    pub fields: Option<Result<String, DecodingError>>,
+}
+
+impl<'a> Dissected<'a> {
+    pub fn new(connection: &'a str, record: &'a Record) -> Self {
+        let mut body = &record.data[..];
+        let id = VarInt::try_decode(&mut body).ok().map(|id| id.0);
+
+        // Every known packet id fits in a byte
+        let known = id.and_then(|id| u8::try_from(id).ok());
+        let name = known.and_then(|id| record.state.packet_name(record.direction, id));
+        let fields = known.and_then(|id| record.state.debug_packet(record.direction, id, body));
+
+        Self {
+            connection,
+            record,
+            id,
+            name,
+            fields,
+        }
+    }
+
+    /// The packet name, or its id when unknown
+    pub fn display_name(&self) -> String {
+        match (&self.name, self.id) {
+            (Some(name), _) => name.clone(),
+            (None, Some(id)) => format!("Unknown(0x{id:02x})"),
+            (None, None) => "<no packet id>".to_owned(),
+        }
+    }
+
+    /// The packet body, after the id
+    pub fn body(&self) -> &'a [u8] {
+        let mut body = &self.record.data[..];
+        let _ = VarInt::try_decode(&mut body);
+        body
+    }
+}
+
+/// Formats up to `max` bytes as hex, noting how many were left out
+pub fn hex(bytes: &[u8], max: usize) -> String {
+    let mut hex = String::with_capacity(bytes.len().min(max) * 3);
+
+    for byte in bytes.iter().take(max) {
+        let _ = write!(hex, "{byte:02x} ");
+    }
+    hex.pop();
+
+    if bytes.len() > max {
+        let _ = write!(hex, " (+{} bytes)", bytes.len() - max);
+    }
+
+    hex
+}
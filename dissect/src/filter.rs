@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+use proto::Direction;
+
+use crate::dissect::Dissected;
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum DirectionFilter {
+    /// Packets sent by the client
+    #[value(alias = "c2s")]
+    Serverbound,
+    /// Packets sent by the server
+    #[value(alias = "s2c")]
+    Clientbound,
+}
+
+impl DirectionFilter {
+    fn direction(self) -> Direction {
+        match self {
+            DirectionFilter::Serverbound => Direction::ClientToServer,
+            DirectionFilter::Clientbound => Direction::ServerToClient,
+        }
+    }
+}
+
+/// Which records are shown, an empty list matches everything
+#[derive(Clone, Default, Debug)]
+pub struct Filter {
+    /// Case insensitive parts of packet names
+    pub names: Vec<String>,
+    pub direction: Option<DirectionFilter>,
+    pub from: Option<Duration>,
+    pub to: Option<Duration>,
+    pub connections: Vec<String>,
+}
+
+impl Filter {
+    /// Whether any record of `connection` could match, so whole captures can be skipped
+    pub fn matches_connection(&self, connection: &str) -> bool {
+        self.connections.is_empty() || self.connections.iter().any(|it| it == connection)
+    }
+
+    pub fn matches(&self, packet: &Dissected) -> bool {
+        let record = packet.record;
+
+        if !self.matches_connection(packet.connection) {
+            return false;
+        }
+
+        if let Some(direction) = self.direction {
+            if record.direction != direction.direction() {
+                return false;
+            }
+        }
+
+        if self.from.is_some_and(|from| record.at < from)
+            || self.to.is_some_and(|to| record.at > to)
+        {
+            return false;
+        }
+
+        if !self.names.is_empty() {
+            let name = packet.display_name().to_lowercase();
+            if !self
+                .names
+                .iter()
+                .any(|it| name.contains(&it.to_lowercase()))
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mc_io::capture::Record;
+    use mc_io::state::ProtocolState;
+    use proto::packets::{c2s, s2c};
+    use proto::Packet;
+
+    fn keep_alive(at: u64, direction: Direction) -> Record {
+        let id = match direction {
+            Direction::ClientToServer => c2s::play::KeepAlivePacket::PACKET_ID_NUM,
+            Direction::ServerToClient => s2c::play::KeepAlivePacket::PACKET_ID_NUM,
+        };
+        let mut data = vec![id];
+        data.extend_from_slice(&5u64.to_be_bytes());
+
+        Record {
+            at: Duration::from_secs(at),
+            direction,
+            state: ProtocolState::Play,
+            compressed: false,
+            data,
+        }
+    }
+
+    #[test]
+    fn everything_by_default() {
+        let record = keep_alive(3, Direction::ClientToServer);
+        assert!(Filter::default().matches(&Dissected::new("0", &record)));
+    }
+
+    #[test]
+    fn by_name() {
+        let record = keep_alive(0, Direction::ServerToClient);
+        let packet = Dissected::new("0", &record);
+        assert_eq!(packet.name.as_deref(), Some("KeepAlivePacket"));
+
+        let filter = |name: &str| Filter {
+            names: vec![name.to_owned()],
+            ..Filter::default()
+        };
+        assert!(filter("keepalive").matches(&packet));
+        assert!(!filter("Chat").matches(&packet));
+    }
+
+    #[test]
+    fn by_direction_time_and_connection() {
+        let record = keep_alive(10, Direction::ClientToServer);
+        let packet = Dissected::new("bot", &record);
+
+        let filter = Filter {
+            direction: Some(DirectionFilter::Serverbound),
+            from: Some(Duration::from_secs(5)),
+            to: Some(Duration::from_secs(10)),
+            connections: vec!["bot".to_owned()],
+            ..Filter::default()
+        };
+        assert!(filter.matches(&packet));
+
+        let clientbound = Filter {
+            direction: Some(DirectionFilter::Clientbound),
+            ..filter.clone()
+        };
+        assert!(!clientbound.matches(&packet));
+
+        let later = Filter {
+            from: Some(Duration::from_secs(11)),
+            ..filter.clone()
+        };
+        assert!(!later.matches(&packet));
+
+        let other = Filter {
+            connections: vec!["other".to_owned()],
+            ..filter
+        };
+        assert!(!other.matches(&packet));
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::Context;
+use clap::Parser;
+use log::{warn, LevelFilter};
+use mc_io::capture::CaptureReader;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::dissect::Dissected;
+use crate::filter::{DirectionFilter, Filter};
+use crate::output::{Format, Output};
+
+mod dissect;
+mod filter;
+mod output;
+
+#[derive(Parser, Clone, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(
+        required = true,
+        help = "The captures to decode, each is a connection named after its file"
+    )]
+    captures: Vec<PathBuf>,
+    #[arg(short, long, value_enum, default_value_t = Format::Table, help = "How packets are printed")]
+    format: Format,
+    #[arg(
+        short,
+        long = "name",
+        help = "Only show packets whose name contains this, can be repeated"
+    )]
+    names: Vec<String>,
+    #[arg(
+        short,
+        long,
+        value_enum,
+        help = "Only show packets sent in this direction"
+    )]
+    direction: Option<DirectionFilter>,
+    #[arg(long, help = "Only show packets from this many seconds into a capture")]
+    from: Option<f64>,
+    #[arg(
+        long,
+        help = "Only show packets up to this many seconds into a capture"
+    )]
+    to: Option<f64>,
+    #[arg(
+        short,
+        long = "connection",
+        help = "Only show the capture with this name, can be repeated"
+    )]
+    connections: Vec<String>,
+    #[arg(
+        long,
+        default_value_t = 64,
+        help = "The most bytes of an undecoded packet to print as hex"
+    )]
+    max_hex: usize,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    let seconds = |secs: Option<f64>| {
+        secs.map(Duration::try_from_secs_f64)
+            .transpose()
+            .context("Times must be positive")
+    };
+    let filter = Filter {
+        names: args.names.clone(),
+        direction: args.direction,
+        from: seconds(args.from)?,
+        to: seconds(args.to)?,
+        connections: args.connections.clone(),
+    };
+
+    let stdout = io::stdout().lock();
+    let mut output = Output::new(BufWriter::new(stdout), args.format, args.max_hex)?;
+
+    let res = args
+        .captures
+        .iter()
+        .try_for_each(|path| dissect_capture(path, &filter, &mut output))
+        .and_then(|()| output.flush());
+
+    match res {
+        // Eg. piped into `head`
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+        res => res.context("Write output"),
+    }
+}
+
+// Prints the matching packets of the capture at `path`, a capture that can't be read is reported
+// and skipped
+fn dissect_capture<W: io::Write>(
+    path: &Path,
+    filter: &Filter,
+    output: &mut Output<W>,
+) -> io::Result<()> {
+    let connection = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !filter.matches_connection(&connection) {
+        return Ok(());
+    }
+
+    let reader = match File::open(path)
+        .map_err(Into::into)
+        .and_then(|file| CaptureReader::new(BufReader::new(file)))
+    {
+        Ok(reader) => reader,
+        Err(err) => {
+            warn!("Could not read {}: {}", path.display(), err);
+            return Ok(());
+        }
+    };
+
+    for record in reader {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                // Eg. the capture was cut short when whatever wrote it was killed
+                warn!("Stopped reading {}: {}", path.display(), err);
+                break;
+            }
+        };
+
+        let packet = Dissected::new(&connection, &record);
+        if filter.matches(&packet) {
+            output.packet(&packet)?;
+        }
+    }
+
+    Ok(())
+}
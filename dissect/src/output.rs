@@ -0,0 +1,115 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use proto::Direction;
+use serde_json::json;
+
+use crate::dissect::{self, Dissected};
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Format {
+    /// One aligned row per packet
+    Table,
+    /// One json object per line
+    Json,
+}
+
+/// Writes dissected packets in a `Format`
+pub struct Output<W> {
+    out: W,
+    format: Format,
+    // Bytes of hex printed for packets that can't be decoded
+    max_hex: usize,
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(out: W, format: Format, max_hex: usize) -> io::Result<Self> {
+        let mut output = Self {
+            out,
+            format,
+            max_hex,
+        };
+
+        if format == Format::Table {
+            writeln!(
+                output.out,
+                "{:>10} {:<8} {:<4} {:<9} {:<28} {:>8}  FIELDS",
+                "TIME", "CONN", "DIR", "STATE", "PACKET", "SIZE"
+            )?;
+        }
+
+        Ok(output)
+    }
+
+    pub fn packet(&mut self, packet: &Dissected) -> io::Result<()> {
+        match self.format {
+            Format::Table => self.table_row(packet),
+            Format::Json => self.json_line(packet),
+        }
+    }
+
+    fn table_row(&mut self, packet: &Dissected) -> io::Result<()> {
+        let record = packet.record;
+
+        let details = match &packet.fields {
+            Some(Ok(fields)) => fields.clone(),
+            Some(Err(error)) => format!("<{error}> {}", dissect::hex(packet.body(), self.max_hex)),
+            None => dissect::hex(packet.body(), self.max_hex),
+        };
+        // Marks packets that were compressed on the wire
+        let size = format!(
+            "{}{}",
+            record.data.len(),
+            if record.compressed { "z" } else { "" }
+        );
+
+        writeln!(
+            self.out,
+            "{:>10.3} {:<8} {:<4} {:<9} {:<28} {:>8}  {}",
+            record.at.as_secs_f64(),
+            packet.connection,
+            arrow(record.direction),
+            format!("{:?}", record.state),
+            packet.display_name(),
+            size,
+            details
+        )
+    }
+
+    fn json_line(&mut self, packet: &Dissected) -> io::Result<()> {
+        let record = packet.record;
+
+        let mut line = json!({
+            "connection": packet.connection,
+            "at": record.at.as_secs_f64(),
+            "direction": arrow(record.direction),
+            "state": format!("{:?}", record.state),
+            "id": packet.id,
+            "name": packet.name,
+            "size": record.data.len(),
+            "compressed": record.compressed,
+        });
+
+        match &packet.fields {
+            Some(Ok(fields)) => line["fields"] = json!(fields),
+            Some(Err(error)) => {
+                line["error"] = json!(error.to_string());
+                line["hex"] = json!(dissect::hex(packet.body(), self.max_hex));
+            }
+            None => line["hex"] = json!(dissect::hex(packet.body(), self.max_hex)),
+        }
+
+        writeln!(self.out, "{line}")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn arrow(direction: Direction) -> &'static str {
+    match direction {
+        Direction::ClientToServer => "C->S",
+        Direction::ServerToClient => "S->C",
+    }
+}
@@ -0,0 +1,219 @@
+#![feature(test)]
+
+extern crate test;
+
+use mc_io::buf::Buffer;
+use mc_io::packet::helpers;
+use mc_io::state::{Client, ProtocolState, StateMachine};
+use mc_io::{ConnectionWriteContext, GlobalWriteContext};
+use proto::packets::c2s::play::{ChatMesssagePacket, PositionRotationPacket};
+use std::cell::Cell;
+use std::io::{self, IoSlice, Write};
+use std::rc::Rc;
+use test::Bencher;
+
+const BOTS: usize = 10_000;
+const COMPRESSION_THRESHOLD: i32 = 256;
+// Left over from a previous tick where the socket filled up
+const PENDING: [u8; 200] = [0; 200];
+
+// Accepts everything, counting syscalls and bytes like a socket with an empty send buffer would
+#[derive(Default)]
+struct CountingSink {
+    writes: Cell<usize>,
+    bytes: Cell<usize>,
+}
+
+impl Write for &CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.set(self.writes.get() + 1);
+        self.bytes.set(self.bytes.get() + buf.len());
+        Ok(buf.len())
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len = bufs.iter().map(|it| it.len()).sum();
+        self.writes.set(self.writes.get() + 1);
+        self.bytes.set(self.bytes.get() + len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Path {
+    // As before vectored writes: the pending bytes are flushed on their own, and the framed
+    // packets are copied into one buffer to be written with `write_slice`
+    CopyThenWrite,
+    // Pending bytes and the framed packets share a single vectored write, straight from where
+    // they were framed
+    Vectored,
+}
+
+struct Bots {
+    sink: Rc<CountingSink>,
+    connections: Vec<ConnectionWriteContext<Rc<CountingSink>>>,
+    ctx: GlobalWriteContext,
+    flattened: Buffer,
+    // Bytes copied on their way to the sink, into `flattened` or an `unwritten_buf`
+    copied: usize,
+}
+
+impl Bots {
+    fn new() -> Self {
+        let sink = Rc::new(CountingSink::default());
+        let connections = (0..BOTS)
+            .map(|_| {
+                let mut connection = ConnectionWriteContext::new(sink.clone());
+                connection.unwritten_buf.copy_from(&PENDING);
+                connection
+            })
+            .collect();
+
+        Self {
+            sink,
+            connections,
+            ctx: GlobalWriteContext::new(),
+            flattened: Buffer::new(),
+            copied: 0,
+        }
+    }
+
+    // One tick worth of packets for every bot. The short chat message is encoded for compression
+    // but ends up under the threshold, the long one is compressed.
+    fn tick(&mut self, path: Path) {
+        let short_message = "a".repeat(220);
+        let long_message = "a chat message that is long enough to be compressed ".repeat(12);
+        let short_chat = chat(&short_message);
+        let long_chat = chat(&long_message);
+        let movement = PositionRotationPacket {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+            yaw: 0.0,
+            pitch: 0.0,
+            on_ground: false,
+        };
+
+        let mut state = StateMachine::<Client>::resume(ProtocolState::Play, COMPRESSION_THRESHOLD);
+
+        for connection in &mut self.connections {
+            match path {
+                Path::CopyThenWrite => {
+                    {
+                        let (write_buf, mut compression_ctx) = self.ctx.compression();
+                        let threshold = COMPRESSION_THRESHOLD;
+                        helpers::write_packet(
+                            &movement,
+                            write_buf,
+                            &mut compression_ctx,
+                            threshold,
+                        )
+                        .unwrap();
+                        helpers::write_packet(
+                            &short_chat,
+                            write_buf,
+                            &mut compression_ctx,
+                            threshold,
+                        )
+                        .unwrap();
+                        helpers::write_packet(
+                            &movement,
+                            write_buf,
+                            &mut compression_ctx,
+                            threshold,
+                        )
+                        .unwrap();
+                        helpers::write_packet(
+                            &long_chat,
+                            write_buf,
+                            &mut compression_ctx,
+                            threshold,
+                        )
+                        .unwrap();
+                    }
+
+                    self.flattened.reset();
+                    for segment in self.ctx.written() {
+                        self.flattened.copy_from(segment);
+                    }
+                    self.copied += self.flattened.len();
+
+                    connection.write_unwritten().unwrap();
+                    connection.write_buffer(&mut self.flattened).unwrap();
+                }
+                Path::Vectored => {
+                    connection
+                        .write_packets(&mut self.ctx, &mut state, |writer| {
+                            writer.write_packet(&movement)?;
+                            writer.write_packet(&short_chat)?;
+                            writer.write_packet(&movement)?;
+                            writer.write_packet(&long_chat)?;
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            }
+
+            // Anything the library copied is left behind in `unwritten_buf`
+            self.copied += connection.unwritten_buf.len();
+            connection.unwritten_buf.reset();
+            connection.unwritten_buf.copy_from(&PENDING);
+        }
+    }
+}
+
+fn chat(message: &str) -> ChatMesssagePacket<'_> {
+    ChatMesssagePacket {
+        message,
+        timestamp: 0,
+        salt: 0,
+        signature: &[],
+        signed_preview: false,
+        seen_messages: Vec::new(),
+        last_seen: None,
+    }
+}
+
+// Reports the bytes written per tick, so the output shows the throughput of each path. The sink
+// makes syscalls free, so the time only covers framing, compressing and copying.
+fn bench_tick(b: &mut Bencher, path: Path) {
+    let mut bots = Bots::new();
+
+    bots.tick(path);
+    b.bytes = bots.sink.bytes.get() as u64;
+
+    b.iter(|| bots.tick(path));
+}
+
+#[bench]
+fn tick_10k_bots_copy_then_write(b: &mut Bencher) {
+    bench_tick(b, Path::CopyThenWrite);
+}
+
+#[bench]
+fn tick_10k_bots_vectored(b: &mut Bencher) {
+    bench_tick(b, Path::Vectored);
+}
+
+// The syscalls and copies of a tick, run with `cargo test -p mc_io --bench write`
+#[test]
+fn vectored_saves_syscalls_and_copies() {
+    let mut copy_then_write = Bots::new();
+    copy_then_write.tick(Path::CopyThenWrite);
+    let mut vectored = Bots::new();
+    vectored.tick(Path::Vectored);
+
+    assert_eq!(vectored.sink.bytes.get(), copy_then_write.sink.bytes.get());
+
+    assert_eq!(copy_then_write.sink.writes.get(), 2 * BOTS);
+    assert_eq!(vectored.sink.writes.get(), BOTS);
+
+    // Every framed packet, including the compressed one
+    let framed = (copy_then_write.sink.bytes.get() - BOTS * PENDING.len()) / BOTS;
+    assert_eq!(copy_then_write.copied, BOTS * framed);
+    assert_eq!(vectored.copied, 0);
+}
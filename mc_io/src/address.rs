@@ -2,6 +2,7 @@ use std::io::{self, ErrorKind};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::str::FromStr;
 
+/// A server address, defaulting to the standard port when none is given
 #[derive(Clone, Debug)]
 pub struct MinecraftAddress(pub SocketAddr);
 
@@ -11,10 +12,23 @@ impl FromStr for MinecraftAddress {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         s.to_socket_addrs()
             .or_else(|_| (s, 25565).to_socket_addrs())?
-            .into_iter()
             .filter(|it| it.is_ipv4())
             .map(MinecraftAddress)
             .next()
             .ok_or_else(|| ErrorKind::NotFound.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_port() {
+        let addr: MinecraftAddress = "127.0.0.1".parse().unwrap();
+        assert_eq!(addr.0, SocketAddr::from(([127, 0, 0, 1], 25565)));
+
+        let addr: MinecraftAddress = "127.0.0.1:25566".parse().unwrap();
+        assert_eq!(addr.0.port(), 25566);
+    }
+}
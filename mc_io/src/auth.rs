@@ -0,0 +1,210 @@
+//! Session server authentication, as done by online mode clients and servers
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::time::Duration;
+
+use crate::error::AuthError;
+
+#[cfg(any(test, feature = "mock-session-server"))]
+pub mod mock;
+
+pub const MOJANG_SESSION_SERVER: &str = "https://sessionserver.mojang.com";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Computes the server hash sent to the session server. This is the SHA-1 digest of the inputs
+/// printed as a signed (two's complement) hex number, without leading zeros.
+pub fn server_hash(server_id: &str, shared_secret: &[u8], public_key: &[u8]) -> String {
+    let mut digest: [u8; 20] = Sha1::new()
+        .chain_update(server_id)
+        .chain_update(shared_secret)
+        .chain_update(public_key)
+        .finalize()
+        .into();
+
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        // Negate the digest
+        let mut carry = true;
+        for byte in digest.iter_mut().rev() {
+            (*byte, carry) = (!*byte).overflowing_add(carry as u8);
+        }
+    }
+
+    let hex: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+    let hex = hex.trim_start_matches('0');
+
+    if negative {
+        format!("-{hex}")
+    } else {
+        hex.to_owned()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameProfile {
+    #[serde(with = "uuid_hex")]
+    pub id: u128,
+    pub name: String,
+    #[serde(default)]
+    pub properties: Vec<ProfileProperty>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileProperty {
+    pub name: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JoinRequest {
+    access_token: String,
+    #[serde(with = "uuid_hex")]
+    selected_profile: u128,
+    server_id: String,
+}
+
+/// A client for the session server found at `base_url`
+#[derive(Clone)]
+pub struct SessionServer {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl SessionServer {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_owned();
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+
+        Self { base_url, agent }
+    }
+
+    pub fn mojang() -> Self {
+        Self::new(MOJANG_SESSION_SERVER)
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Tells the session server that `profile` is joining the server identified by
+    /// `server_hash`. Done by the client before sending the `EncryptionResponsePacket`.
+    pub fn join(
+        &self,
+        access_token: &str,
+        profile: u128,
+        server_hash: &str,
+    ) -> Result<(), AuthError> {
+        self.agent
+            .post(&format!("{}/session/minecraft/join", self.base_url))
+            .send_json(JoinRequest {
+                access_token: access_token.to_owned(),
+                selected_profile: profile,
+                server_id: server_hash.to_owned(),
+            })
+            .map_err(AuthError::from_request)?;
+
+        Ok(())
+    }
+
+    /// Checks that `username` joined the server identified by `server_hash`, returning their
+    /// profile if they did. Done by the server once it has received the shared secret.
+    pub fn has_joined(
+        &self,
+        username: &str,
+        server_hash: &str,
+    ) -> Result<Option<GameProfile>, AuthError> {
+        let response = self
+            .agent
+            .get(&format!("{}/session/minecraft/hasJoined", self.base_url))
+            .query("username", username)
+            .query("serverId", server_hash)
+            .call()
+            .map_err(AuthError::from_request)?;
+
+        if response.status() == 204 {
+            return Ok(None);
+        }
+
+        Ok(Some(response.into_json()?))
+    }
+}
+
+impl std::fmt::Debug for SessionServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionServer")
+            .field("base_url", &self.base_url)
+            .finish_non_exhaustive()
+    }
+}
+
+// Profile ids are sent as hex without dashes
+mod uuid_hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(uuid: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{uuid:032x}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        u128::from_str_radix(&hex.replace('-', ""), 16).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockSessionServer;
+    use super::*;
+
+    #[test]
+    fn known_hashes() {
+        assert_eq!(
+            server_hash("Notch", &[], &[]),
+            "4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48"
+        );
+        assert_eq!(
+            server_hash("jeb_", &[], &[]),
+            "-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1"
+        );
+        assert_eq!(
+            server_hash("simon", &[], &[]),
+            "88e16a1019277b15d58faf0541e11910eb756f6"
+        );
+    }
+
+    #[test]
+    fn join_then_has_joined() {
+        let mock = MockSessionServer::start().unwrap();
+        let session = SessionServer::new(mock.url());
+
+        let hash = server_hash("", &[1; 16], &[2; 162]);
+        let profile = 0x1234_5678_9abc_def0_1234_5678_9abc_def0;
+
+        assert_eq!(session.has_joined("bot_0", &hash).unwrap(), None);
+
+        session.join("token", profile, &hash).unwrap();
+
+        let joined = session.has_joined("bot_0", &hash).unwrap().unwrap();
+        assert_eq!(joined.id, profile);
+        assert_eq!(joined.name, "bot_0");
+
+        assert_eq!(session.has_joined("bot_0", "other").unwrap(), None);
+    }
+
+    #[test]
+    fn rejected_token() {
+        let mock = MockSessionServer::start().unwrap();
+        mock.reject_token("expired");
+        let session = SessionServer::new(mock.url());
+
+        assert!(matches!(
+            session.join("expired", 1, "hash"),
+            Err(AuthError::Rejected(403))
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+//! A stand in for the session server, so online mode logins can be tested without contacting
+//! Mojang. Any access token is accepted unless it was explicitly rejected.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use super::{GameProfile, JoinRequest};
+
+pub struct MockSessionServer {
+    server: Arc<Server>,
+    state: Arc<Mutex<State>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct State {
+    // Server hash to the profile that joined with it
+    joins: HashMap<String, u128>,
+    rejected_tokens: HashSet<String>,
+}
+
+impl MockSessionServer {
+    /// Starts the server on a random local port
+    pub fn start() -> io::Result<Self> {
+        Self::bind("127.0.0.1:0")
+    }
+
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let server = Arc::new(Server::http(addr).map_err(io::Error::other)?);
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let handle = {
+            let server = server.clone();
+            let state = state.clone();
+
+            thread::Builder::new()
+                .name("Mock session server".to_owned())
+                .spawn(move || {
+                    for request in server.incoming_requests() {
+                        if let Err(error) = respond(request, &state) {
+                            log::warn!("Mock session server could not respond: {error}");
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self {
+            server,
+            state,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server
+            .server_addr()
+            .to_ip()
+            .expect("Bound to an ip address")
+    }
+
+    /// The base url to give to `SessionServer::new`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.local_addr())
+    }
+
+    /// Makes joins with `access_token` fail with 403, like an expired session would
+    pub fn reject_token(&self, access_token: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.rejected_tokens.insert(access_token.to_owned());
+    }
+
+    /// The amount of successful joins so far
+    pub fn joins(&self) -> usize {
+        self.state.lock().unwrap().joins.len()
+    }
+}
+
+impl Drop for MockSessionServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn respond(mut request: Request, state: &Mutex<State>) -> io::Result<()> {
+    let url = request.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+    let response = match (request.method(), path) {
+        (Method::Post, "/session/minecraft/join") => {
+            match serde_json::from_reader::<_, JoinRequest>(request.as_reader()) {
+                Ok(join) => {
+                    let mut state = state.lock().unwrap();
+
+                    if state.rejected_tokens.contains(&join.access_token) {
+                        status(403)
+                    } else {
+                        state.joins.insert(join.server_id, join.selected_profile);
+                        status(204)
+                    }
+                }
+                Err(_) => status(400),
+            }
+        }
+        (Method::Get, "/session/minecraft/hasJoined") => {
+            let mut username = None;
+            let mut server_id = None;
+
+            // Neither usernames nor server hashes contain characters that need decoding
+            for (key, value) in query.split('&').filter_map(|it| it.split_once('=')) {
+                match key {
+                    "username" => username = Some(value),
+                    "serverId" => server_id = Some(value),
+                    _ => {}
+                }
+            }
+
+            match (username, server_id) {
+                (Some(username), Some(server_id)) => {
+                    let state = state.lock().unwrap();
+
+                    match state.joins.get(server_id) {
+                        Some(&id) => {
+                            let profile = GameProfile {
+                                id,
+                                name: username.to_owned(),
+                                properties: Vec::new(),
+                            };
+
+                            json(&profile)?
+                        }
+                        None => status(204),
+                    }
+                }
+                _ => status(400),
+            }
+        }
+        _ => status(404),
+    };
+
+    request.respond(response)
+}
+
+fn status(code: u16) -> Response<Cursor<Vec<u8>>> {
+    Response::from_data(Vec::new()).with_status_code(code)
+}
+
+fn json(profile: &GameProfile) -> io::Result<Response<Cursor<Vec<u8>>>> {
+    let content_type =
+        Header::from_bytes("Content-Type", "application/json").expect("Valid header");
+
+    Ok(Response::from_data(serde_json::to_vec(profile)?).with_header(content_type))
+}
@@ -0,0 +1,369 @@
+//! The mccap capture format. A capture is every packet of a connection, decompressed and
+//! decrypted, so it can be analysed or replayed without reassembling a tcp stream.
+//!
+//! A capture starts with `MAGIC` and a version byte, followed by records of
+//! - the time since the capture started in microseconds, as a varint
+//! - a flags byte, the protocol state id in the low 2 bits, `FLAG_SERVERBOUND` and
+//!   `FLAG_COMPRESSED`
+//! - the length of the packet as a varint, then the packet id and body
+
+use std::fmt::Debug;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use proto::direction::PacketDirection;
+use proto::primitive::{var_long, V21};
+use proto::{Data, Direction};
+
+use crate::error::CaptureError;
+use crate::packet::helpers::Framed;
+use crate::state::{ProtocolState, Side};
+use crate::{FramedPacket, RawPacket, MAXIMUM_PACKET_SIZE};
+
+pub const MAGIC: &[u8; 5] = b"MCCAP";
+pub const VERSION: u8 = 1;
+
+const STATE_MASK: u8 = 0b11;
+const FLAG_SERVERBOUND: u8 = 1 << 2;
+const FLAG_COMPRESSED: u8 = 1 << 3;
+
+/// A single captured packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Time since the capture started
+    pub at: Duration,
+    pub direction: Direction,
+    pub state: ProtocolState,
+    /// Whether the packet was compressed on the wire
+    pub compressed: bool,
+    /// The packet id followed by the packet
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    pub fn raw(&self) -> RawPacket<'_> {
+        RawPacket(&self.data)
+    }
+
+    /// The packet id, `None` if the packet is empty or the id isn't a valid varint
+    pub fn id(&self) -> Option<u64> {
+        let mut data = &self.data[..];
+        proto::primitive::VarInt::try_decode(&mut data)
+            .ok()
+            .map(|id| id.0)
+    }
+}
+
+/// Writes records in the mccap format
+#[derive(Debug)]
+pub struct CaptureWriter<W> {
+    out: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Starts a capture by writing the header to `out`
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+
+        Ok(Self { out })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let mut header = [0; 10 + 1 + 10];
+
+        let rest = var_long(record.at.as_micros() as i64).encode(&mut header);
+        let rest = flags(record).encode(rest);
+        let rest = var_long(record.data.len() as i64).encode(rest).len();
+        let header = &header[..header.len() - rest];
+
+        self.out.write_all(header)?;
+        self.out.write_all(&record.data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+fn flags(record: &Record) -> u8 {
+    let mut flags = record.state.id() & STATE_MASK;
+
+    if record.direction == Direction::ClientToServer {
+        flags |= FLAG_SERVERBOUND;
+    }
+    if record.compressed {
+        flags |= FLAG_COMPRESSED;
+    }
+
+    flags
+}
+
+/// Reads records written by a `CaptureWriter`
+#[derive(Debug)]
+pub struct CaptureReader<R> {
+    input: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Reads and checks the header of the capture in `input`
+    pub fn new(mut input: R) -> Result<Self, CaptureError> {
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(CaptureError::BadMagic);
+        }
+
+        let mut version = [0];
+        input.read_exact(&mut version)?;
+        if version[0] != VERSION {
+            return Err(CaptureError::UnsupportedVersion(version[0]));
+        }
+
+        Ok(Self { input })
+    }
+
+    /// The next record, `None` at the end of the capture
+    pub fn read(&mut self) -> Result<Option<Record>, CaptureError> {
+        let Some(at) = read_varint(&mut self.input, true)? else {
+            return Ok(None);
+        };
+
+        let mut flags = [0];
+        self.input.read_exact(&mut flags)?;
+        let flags = flags[0];
+
+        let state = ProtocolState::from_id(flags & STATE_MASK)
+            .ok_or(CaptureError::UnknownState(flags & STATE_MASK))?;
+        let direction = if flags & FLAG_SERVERBOUND != 0 {
+            Direction::ClientToServer
+        } else {
+            Direction::ServerToClient
+        };
+
+        let len = read_varint(&mut self.input, false)?.unwrap_or_default() as usize;
+        if len > MAXIMUM_PACKET_SIZE {
+            return Err(CaptureError::RecordTooLarge(len));
+        }
+
+        let mut data = vec![0; len];
+        self.input.read_exact(&mut data)?;
+
+        Ok(Some(Record {
+            at: Duration::from_micros(at),
+            direction,
+            state,
+            compressed: flags & FLAG_COMPRESSED != 0,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Record, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+// Reads a varlong a byte at a time, `None` if the input ended before it when `eof_ok`
+fn read_varint<R: Read>(input: &mut R, eof_ok: bool) -> Result<Option<u64>, CaptureError> {
+    let mut val = 0;
+
+    for position in 0..10 {
+        let mut byte = [0];
+        match input.read_exact(&mut byte) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof && eof_ok && position == 0 => {
+                return Ok(None)
+            }
+            res => res?,
+        }
+
+        val |= ((byte[0] & 0x7F) as u64) << (position * 7);
+
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(val));
+        }
+    }
+
+    Err(CaptureError::BadVarint)
+}
+
+/// Whether `framed` was compressed, when read with `compression_threshold`
+pub fn is_compressed(framed: &FramedPacket, compression_threshold: i32) -> bool {
+    // A data length of 0 marks an uncompressed packet
+    let mut data = framed.0;
+    compression_threshold > 0 && V21::try_decode(&mut data).is_ok_and(|data_len| data_len.0 != 0)
+}
+
+struct Shared {
+    writer: CaptureWriter<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+/// Records the packets of a connection into a capture. Set as the `recorder` of a
+/// `ConnectionReadContext` and `ConnectionWriteContext` to capture everything they handle.
+/// Clones write to the same capture.
+#[derive(Clone)]
+pub struct Recorder {
+    shared: Arc<Mutex<Shared>>,
+    // The direction of packets read by the side this handle belongs to
+    receives: Direction,
+}
+
+impl Recorder {
+    /// Starts a capture into `out` of a connection seen from side `S`
+    pub fn new<S: Side>(out: impl Write + Send + 'static) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = Box::new(BufWriter::new(out));
+
+        Ok(Self {
+            shared: Arc::new(Mutex::new(Shared {
+                writer: CaptureWriter::new(out)?,
+                start: Instant::now(),
+            })),
+            receives: S::Sends::DIRECTION.opposite(),
+        })
+    }
+
+    /// Records a packet that was sent in `direction` while in `state`
+    pub fn record(
+        &self,
+        direction: Direction,
+        state: ProtocolState,
+        compressed: bool,
+        packet: &RawPacket,
+    ) -> io::Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+
+        let record = Record {
+            at: shared.start.elapsed(),
+            direction,
+            state,
+            compressed,
+            data: packet.0.to_vec(),
+        };
+
+        shared.writer.write(&record)
+    }
+
+    /// Records a packet read as `framed` and decompressed into `packet`
+    pub fn record_read(
+        &self,
+        framed: &FramedPacket,
+        packet: &RawPacket,
+        state: ProtocolState,
+        compression_threshold: i32,
+    ) -> io::Result<()> {
+        let compressed = is_compressed(framed, compression_threshold);
+        self.record(self.receives, state, compressed, packet)
+    }
+
+    /// Records a packet sent in `direction` while in `state`, as `write_packet` framed it
+    pub fn record_write(
+        &self,
+        direction: Direction,
+        state: ProtocolState,
+        framed: &Framed,
+    ) -> io::Result<()> {
+        self.record(
+            direction,
+            state,
+            framed.compressed,
+            &RawPacket(framed.packet),
+        )
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.shared.lock().unwrap().writer.flush()
+    }
+}
+
+impl Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("receives", &self.receives)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(at: u64, direction: Direction, state: ProtocolState, len: usize) -> Record {
+        Record {
+            at: Duration::from_micros(at),
+            direction,
+            state,
+            compressed: len > 200,
+            data: (0..len).map(|it| it as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let records = vec![
+            record(0, Direction::ClientToServer, ProtocolState::Handshake, 17),
+            record(12, Direction::ServerToClient, ProtocolState::Login, 3),
+            record(
+                300_000,
+                Direction::ServerToClient,
+                ProtocolState::Play,
+                5000,
+            ),
+            record(
+                u32::MAX as u64,
+                Direction::ClientToServer,
+                ProtocolState::Status,
+                0,
+            ),
+        ];
+
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let capture = writer.into_inner();
+
+        let read: Vec<_> = CaptureReader::new(&capture[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(matches!(
+            CaptureReader::new(&b"PCAP\x01"[..]),
+            Err(CaptureError::BadMagic)
+        ));
+        assert!(matches!(
+            CaptureReader::new(&b"MCCAP\x09"[..]),
+            Err(CaptureError::UnsupportedVersion(9))
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        writer
+            .write(&record(
+                5,
+                Direction::ClientToServer,
+                ProtocolState::Play,
+                40,
+            ))
+            .unwrap();
+        let capture = writer.into_inner();
+
+        let mut reader = CaptureReader::new(&capture[..capture.len() - 1]).unwrap();
+        assert!(matches!(reader.read(), Err(CaptureError::Io(_))));
+    }
+}
@@ -0,0 +1,81 @@
+//! AES-128-CFB8 stream encryption, along with the RSA exchange used to agree on its key. The
+//! exchange needs the `encryption` feature.
+
+use aes::Aes128;
+use cfb8::cipher::inout::InOutBuf;
+use cfb8::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+
+#[cfg(feature = "encryption")]
+mod exchange;
+
+#[cfg(feature = "encryption")]
+pub use exchange::{encrypt_response, generate_shared_secret, ServerKey};
+
+/// The key (and initial vector) used for both directions of a connection
+pub type SharedSecret = [u8; 16];
+
+#[derive(Clone)]
+pub struct Encryptor(cfb8::Encryptor<Aes128>);
+
+impl Encryptor {
+    pub fn new(shared_secret: &SharedSecret) -> Self {
+        Self(cfb8::Encryptor::new(
+            shared_secret.into(),
+            shared_secret.into(),
+        ))
+    }
+
+    /// Encrypts `buf` in place, continuing the stream from the previous call
+    pub fn encrypt(&mut self, buf: &mut [u8]) {
+        // CFB8 works on single byte blocks, so there is never a tail
+        let (blocks, _) = InOutBuf::from(buf).into_chunks();
+        self.0.encrypt_blocks_inout_mut(blocks);
+    }
+}
+
+#[derive(Clone)]
+pub struct Decryptor(cfb8::Decryptor<Aes128>);
+
+impl Decryptor {
+    pub fn new(shared_secret: &SharedSecret) -> Self {
+        Self(cfb8::Decryptor::new(
+            shared_secret.into(),
+            shared_secret.into(),
+        ))
+    }
+
+    /// Decrypts `buf` in place, continuing the stream from the previous call
+    pub fn decrypt(&mut self, buf: &mut [u8]) {
+        let (blocks, _) = InOutBuf::from(buf).into_chunks();
+        self.0.decrypt_blocks_inout_mut(blocks);
+    }
+}
+
+/// Creates the ciphers for both halves of a connection
+pub fn ciphers(shared_secret: &SharedSecret) -> (Encryptor, Decryptor) {
+    (Encryptor::new(shared_secret), Decryptor::new(shared_secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_roundtrip() {
+        let shared_secret = rand::random();
+        let (mut encryptor, mut decryptor) = ciphers(&shared_secret);
+
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut buffer = data.clone();
+
+        encryptor.encrypt(&mut buffer);
+        assert_ne!(buffer, data);
+
+        // The stream must not depend on how it was split up
+        let (first, second) = buffer.split_at_mut(123);
+        decryptor.decrypt(first);
+        decryptor.decrypt(second);
+
+        assert_eq!(buffer, data);
+    }
+}
@@ -0,0 +1,93 @@
+use rsa::pkcs8::{DecodePublicKey, EncodePublicKey};
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+
+use super::SharedSecret;
+use crate::error::EncryptionError;
+
+// The vanilla server generates a 1024 bit key
+const SERVER_KEY_BITS: usize = 1024;
+
+pub fn generate_shared_secret() -> SharedSecret {
+    rand::random()
+}
+
+/// Encrypts the shared secret and verify token with the public key from an
+/// `EncryptionRequestPacket`, ready to be sent back in an `EncryptionResponsePacket`
+pub fn encrypt_response(
+    public_key: &[u8],
+    shared_secret: &SharedSecret,
+    verify_token: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), EncryptionError> {
+    let public_key = RsaPublicKey::from_public_key_der(public_key)?;
+    let mut rng = rand::thread_rng();
+
+    let shared_secret = public_key.encrypt(&mut rng, Pkcs1v15Encrypt, shared_secret)?;
+    let verify_token = public_key.encrypt(&mut rng, Pkcs1v15Encrypt, verify_token)?;
+
+    Ok((shared_secret, verify_token))
+}
+
+/// The key pair a server uses to receive the shared secret
+#[derive(Clone)]
+pub struct ServerKey {
+    private_key: RsaPrivateKey,
+    public_key: Vec<u8>,
+}
+
+impl ServerKey {
+    pub fn generate() -> Result<Self, EncryptionError> {
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), SERVER_KEY_BITS)?;
+        let public_key = private_key.to_public_key().to_public_key_der()?.into_vec();
+
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
+    /// The DER encoded public key, as sent in the `EncryptionRequestPacket`
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        Ok(self.private_key.decrypt(Pkcs1v15Encrypt, data)?)
+    }
+
+    pub fn decrypt_shared_secret(&self, data: &[u8]) -> Result<SharedSecret, EncryptionError> {
+        let shared_secret = self.decrypt(data)?;
+
+        shared_secret
+            .as_slice()
+            .try_into()
+            .map_err(|_| EncryptionError::BadSharedSecret(shared_secret.len()))
+    }
+}
+
+impl std::fmt::Debug for ServerKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerKey").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Too slow
+    fn key_exchange() {
+        let key = ServerKey::generate().unwrap();
+        let shared_secret = generate_shared_secret();
+        let verify_token = [1, 2, 3, 4];
+
+        let (encrypted_secret, encrypted_token) =
+            encrypt_response(key.public_key(), &shared_secret, &verify_token).unwrap();
+
+        assert_eq!(
+            key.decrypt_shared_secret(&encrypted_secret).unwrap(),
+            shared_secret
+        );
+        assert_eq!(key.decrypt(&encrypted_token).unwrap(), verify_token);
+    }
+}
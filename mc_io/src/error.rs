@@ -1,4 +1,4 @@
-use libdeflater::{CompressionError, DecompressionError};
+use crate::state::ProtocolState;
 use proto::DecodingError;
 use std::io;
 use thiserror::Error;
@@ -17,6 +17,14 @@ pub enum CommunicationError {
     Write(#[from] WriteError),
     #[error("Read error: {0}")]
     Read(#[from] ReadError),
+    #[cfg(feature = "encryption")]
+    #[error("Encryption error: {0}")]
+    Encryption(#[from] EncryptionError),
+    #[cfg(feature = "auth")]
+    #[error("Authentication error: {0}")]
+    Auth(#[from] AuthError),
+    #[error("Can not switch from the {0:?} state to {1:?}")]
+    BadTransition(ProtocolState, ProtocolState),
     #[error("Internal error: {0}")]
     InternalError(String),
 }
@@ -25,26 +33,117 @@ pub enum CommunicationError {
 pub enum WriteError {
     #[error("Packet size exceeded limit")]
     PacketTooLarge,
+    #[error("Unwritten data exceeded the high water mark ({0} bytes buffered)")]
+    Backpressure(usize),
+    #[error("{0} can not be sent in the {1:?} state")]
+    BadProtocolState(String, ProtocolState),
     #[error("Compression error: {0}")]
     Compression(#[from] CompressionError),
 }
 
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("The compressed packet did not fit in its buffer")]
+    InsufficientSpace,
+    #[error("{0}")]
+    Failed(String),
+}
+
 #[derive(Error, Debug)]
 pub enum ReadError {
     #[error("Packet size exceeded limit")]
     PacketTooLarge,
     #[error("Packet with size 0 was received")]
     ZeroSizedPacket,
+    #[error("Unread data exceeded the high water mark ({0} bytes buffered)")]
+    Backpressure(usize),
     #[error("Decompression error: {0}")]
     Decompression(#[from] DecompressionError),
     #[error("A received packet was compressed when it shouldn't have been")]
     BadlyCompressed,
     #[error("Received a packet while in an unknown protocol status")]
     BadProtocolState,
+    #[error("Handshake asked for the unknown state {0}")]
+    UnknownState(u8),
     #[error("Received an unparseable packet: {0}")]
     BadPacket(#[from] DecodingError),
 }
 
+#[derive(Error, Debug)]
+pub enum DecompressionError {
+    #[error("Invalid compressed data")]
+    BadData,
+    #[error("The packet decompressed to more than its data length")]
+    InsufficientSpace,
+}
+
+#[cfg(feature = "encryption")]
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("Invalid public key: {0}")]
+    PublicKey(#[from] rsa::pkcs8::spki::Error),
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::Error),
+    #[error("Shared secret was {0} bytes instead of 16")]
+    BadSharedSecret(usize),
+}
+
+#[cfg(feature = "auth")]
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Request to the session server failed: {0}")]
+    Request(Box<ureq::Error>),
+    #[error("Session server rejected the request with status {0}")]
+    Rejected(u16),
+    #[error("Unreadable session server response: {0}")]
+    BadResponse(#[from] io::Error),
+}
+
+#[cfg(feature = "auth")]
+impl AuthError {
+    pub(crate) fn from_request(error: ureq::Error) -> Self {
+        match error {
+            ureq::Error::Status(status, _) => AuthError::Rejected(status),
+            error => AuthError::Request(Box::new(error)),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error("io with the capture failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not an mccap capture")]
+    BadMagic,
+    #[error("Unsupported capture version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Record has the unknown state {0}")]
+    UnknownState(u8),
+    #[error("Record of {0} bytes exceeded the packet size limit")]
+    RecordTooLarge(usize),
+    #[error("Record header contained an invalid varint")]
+    BadVarint,
+}
+
+#[cfg(feature = "status")]
+#[derive(Error, Debug)]
+pub enum StatusError {
+    #[error("io with the server failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Communication(#[from] CommunicationError),
+    #[error("Connection was closed before the status was received")]
+    Closed,
+    #[error("Timed out waiting for the status")]
+    TimedOut,
+    #[error("Expected the status response, received packet {0:#04x}")]
+    UnexpectedPacket(u8),
+    #[error("Unreadable status response: {0}")]
+    BadResponse(#[from] DecodingError),
+    #[error("Unreadable status json: {0}")]
+    BadJson(#[from] serde_json::Error),
+}
+
 impl From<DecodingError> for CommunicationError {
     fn from(value: DecodingError) -> Self {
         CommunicationError::Read(value.into())
@@ -1,5 +1,7 @@
 pub(crate) mod read;
 pub(crate) mod write;
 
+#[cfg(test)]
+pub(crate) mod mock;
 #[cfg(test)]
 mod tests;
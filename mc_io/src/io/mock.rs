@@ -0,0 +1,167 @@
+// An in-memory duplex stream that can be scripted to misbehave like a real non-blocking socket
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+
+#[derive(Debug)]
+pub enum ReadStep {
+    /// Hands out the bytes, split across as many reads as `read_chunk` requires
+    Data(Vec<u8>),
+    Error(ErrorKind),
+    Eof,
+}
+
+#[derive(Debug)]
+pub enum WriteStep {
+    /// Accepts at most this many bytes in a single call, `0` reports `WouldBlock`
+    Accept(usize),
+    Error(ErrorKind),
+}
+
+/// Reads pop `ReadStep`s off the read script and report `WouldBlock` once it runs dry.
+/// Writes consume `WriteStep`s first, then accept up to `write_chunk` bytes per call
+/// until `write_capacity` is used up, after which they report `WouldBlock`.
+#[derive(Debug, Default)]
+pub struct MockStream {
+    inner: RefCell<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    read_script: VecDeque<ReadStep>,
+    read_chunk: Option<usize>,
+    read_calls: usize,
+
+    write_script: VecDeque<WriteStep>,
+    write_chunk: Option<usize>,
+    write_capacity: Option<usize>,
+    write_calls: usize,
+
+    written: Vec<u8>,
+}
+
+impl MockStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_read_chunk(self, chunk: usize) -> Self {
+        self.inner.borrow_mut().read_chunk = Some(chunk);
+        self
+    }
+
+    pub fn with_write_chunk(self, chunk: usize) -> Self {
+        self.inner.borrow_mut().write_chunk = Some(chunk);
+        self
+    }
+
+    pub fn with_write_capacity(self, capacity: usize) -> Self {
+        self.inner.borrow_mut().write_capacity = Some(capacity);
+        self
+    }
+
+    pub fn push_read(&self, step: ReadStep) {
+        self.inner.borrow_mut().read_script.push_back(step);
+    }
+
+    pub fn push_data(&self, data: &[u8]) {
+        self.push_read(ReadStep::Data(data.to_vec()));
+    }
+
+    pub fn push_write(&self, step: WriteStep) {
+        self.inner.borrow_mut().write_script.push_back(step);
+    }
+
+    /// Lets the peer accept `amount` more bytes, simulating the socket draining
+    pub fn grow_write_capacity(&self, amount: usize) {
+        let mut inner = self.inner.borrow_mut();
+        inner.write_capacity = inner.write_capacity.map(|it| it + amount);
+    }
+
+    pub fn set_write_capacity(&self, capacity: Option<usize>) {
+        self.inner.borrow_mut().write_capacity = capacity;
+    }
+
+    pub fn written(&self) -> Vec<u8> {
+        self.inner.borrow().written.clone()
+    }
+
+    pub fn read_calls(&self) -> usize {
+        self.inner.borrow().read_calls
+    }
+
+    pub fn write_calls(&self) -> usize {
+        self.inner.borrow().write_calls
+    }
+}
+
+impl Read for &MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.borrow_mut();
+        inner.read_calls += 1;
+
+        let chunk = inner.read_chunk.unwrap_or(usize::MAX);
+        match inner.read_script.front_mut() {
+            Some(ReadStep::Data(data)) => {
+                let amount = data.len().min(buf.len()).min(chunk);
+                buf[..amount].copy_from_slice(&data[..amount]);
+                data.drain(..amount);
+
+                if data.is_empty() {
+                    inner.read_script.pop_front();
+                }
+
+                Ok(amount)
+            }
+            Some(ReadStep::Error(kind)) => {
+                let kind = *kind;
+                inner.read_script.pop_front();
+
+                Err(kind.into())
+            }
+            Some(ReadStep::Eof) => Ok(0),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for &MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.borrow_mut();
+        inner.write_calls += 1;
+
+        let limit = match inner.write_script.pop_front() {
+            Some(WriteStep::Accept(amount)) => amount,
+            Some(WriteStep::Error(kind)) => return Err(kind.into()),
+            None => inner.write_chunk.unwrap_or(usize::MAX),
+        };
+        let limit = limit.min(inner.write_capacity.unwrap_or(usize::MAX));
+
+        if limit == 0 && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let amount = buf.len().min(limit);
+        inner.written.extend_from_slice(&buf[..amount]);
+        if let Some(ref mut capacity) = inner.write_capacity {
+            *capacity -= amount;
+        }
+
+        Ok(amount)
+    }
+
+    // Gathers like `writev` does, so a vectored write counts as a single call
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let gathered = bufs
+            .iter()
+            .flat_map(|it| it.iter())
+            .copied()
+            .collect::<Vec<_>>();
+        self.write(&gathered)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
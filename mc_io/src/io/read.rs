@@ -2,6 +2,7 @@ use proto::primitive::V21;
 use proto::Data;
 
 use crate::buf::Buffer;
+use crate::encryption::Decryptor;
 use crate::error::{CommunicationError, ReadError};
 use crate::{
     CompressionReadContext, ConnectionReadContext, FramedPacket, GlobalReadContext,
@@ -20,14 +21,26 @@ pub fn read<D, S, F>(
 where
     D: Deref<Target = S>,
     for<'a> &'a S: Read,
-    F: FnMut(&FramedPacket, CompressionReadContext) -> Result<(), CommunicationError>,
+    // Called with each packet and how many bytes it took on the wire
+    F: FnMut(
+        &FramedPacket,
+        usize,
+        CompressionReadContext,
+    ) -> Result<Option<Decryptor>, CommunicationError>,
 {
     let GlobalReadContext {
         read_buf,
         compression_buf,
         decompressor,
+        compression_stats,
     } = ctx;
-    let ConnectionReadContext { socket, unread_buf } = connection;
+    let ConnectionReadContext {
+        socket,
+        unread_buf,
+        limits,
+        cipher,
+        ..
+    } = connection;
 
     read_buf.reset();
 
@@ -35,25 +48,67 @@ where
     read_buf.copy_from(unread_buf.get_written());
     unread_buf.reset();
 
-    while let ReadResult::Read(..) = socket_read(D::deref(socket), read_buf)? {
+    // Restored bytes are decoded first, they may have been read by `read_available`
+    loop {
         compression_buf.reset();
 
         while let DecodeResult::Packet(packet, network_len) = next_packet(read_buf.get_written())? {
             let compression_ctx = CompressionReadContext {
                 compression_buf,
                 decompressor,
+                stats: compression_stats,
             };
 
-            (handler)(&packet, compression_ctx)?;
+            let decryptor = (handler)(&packet, network_len, compression_ctx)?;
 
             read_buf.advance_read(network_len);
+
+            if let Some(mut decryptor) = decryptor {
+                // Everything after this packet was already read, but is encrypted
+                decryptor.decrypt(read_buf.get_written_mut());
+                *cipher = Some(decryptor);
+            }
         }
 
         read_buf.consume(0);
+
+        if let ReadResult::WouldBlock = socket_read(D::deref(socket), read_buf, cipher)? {
+            break;
+        }
     }
 
     // Copy any unprocessed bytes into the `unread` buffer for future processing
     unread_buf.copy_from(read_buf.get_written());
+    unread_buf.shrink(limits.retained_capacity);
+    limits
+        .check(unread_buf.len())
+        .map_err(ReadError::Backpressure)?;
+
+    Ok(())
+}
+
+/// Reads everything available into the `unread` buffer without decoding it, so the first bytes
+/// of a connection can be looked at before they are framed
+pub(crate) fn read_available<D, S>(
+    connection: &mut ConnectionReadContext<D>,
+) -> Result<(), CommunicationError>
+where
+    D: Deref<Target = S>,
+    for<'a> &'a S: Read,
+{
+    let ConnectionReadContext {
+        socket,
+        unread_buf,
+        limits,
+        cipher,
+        ..
+    } = connection;
+
+    while let ReadResult::Read(..) = socket_read(D::deref(socket), unread_buf, cipher)? {
+        limits
+            .check(unread_buf.len())
+            .map_err(ReadError::Backpressure)?;
+    }
 
     Ok(())
 }
@@ -66,6 +121,7 @@ enum ReadResult {
 fn socket_read<S: Read>(
     mut socket: S,
     buffer: &mut Buffer,
+    cipher: &mut Option<Decryptor>,
 ) -> Result<ReadResult, CommunicationError> {
     let unwritten = buffer.get_unwritten(PROBE_LEN);
 
@@ -82,11 +138,12 @@ fn socket_read<S: Read>(
         }
     };
 
-    // SAFETY: We just put `read` bytes into the buffer
-    unsafe {
-        buffer.advance_write(read);
+    if let Some(cipher) = cipher {
+        cipher.decrypt(&mut unwritten[..read]);
     }
 
+    buffer.advance_write(read);
+
     Ok(ReadResult::Read(read))
 }
 
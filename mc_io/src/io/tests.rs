@@ -1,260 +1,1245 @@
-// use helpers::{Chunked, EOF2WB};
-//
-// mod helpers {
-//     use std::io::{self, ErrorKind, Read, Write};
-//
-//     pub struct EOF2WB<I: Read + Write>(pub I);
-//
-//     impl<I: Read + Write> Read for EOF2WB<I> {
-//         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-//             match self.0.read(buf) {
-//                 Ok(0) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
-//                 other => other,
-//             }
-//         }
-//     }
-//
-//     impl<I: Read + Write> Write for EOF2WB<I> {
-//         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-//             match self.0.write(buf) {
-//                 Ok(0) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
-//                 other => other,
-//             }
-//         }
-//
-//         fn flush(&mut self) -> io::Result<()> {
-//             self.0.flush()
-//         }
-//     }
-//
-//     pub struct Chunked<I: Read + Write>(pub I, pub usize);
-//
-//     impl<I: Read + Write> Read for Chunked<I> {
-//         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-//             let len = usize::min(buf.len(), self.1);
-//             self.0.read(&mut buf[..len])
-//         }
-//     }
-//
-//     impl<I: Read + Write> Write for Chunked<I> {
-//         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-//             let len = usize::min(buf.len(), self.1);
-//             self.0.write(&buf[..len])
-//         }
-//
-//         fn flush(&mut self) -> io::Result<()> {
-//             self.0.flush()
-//         }
-//     }
-// }
-//
-// mod write {
-//     use super::*;
-//     use crate::{Buffer, ConnectionWriteContext, GlobalWriteContext};
-//     use rand::Rng;
-//     use std::{io::Cursor, rc::Rc};
-//
-//     #[test]
-//     fn normal_write() {
-//         let mut socket = Rc::new(EOF2WB(Chunked(Cursor::new(Vec::with_capacity(1000)), 37)));
-//
-//         let mut ctx_g = GlobalWriteContext::new();
-//         let mut ctx_c = ConnectionWriteContext::new(socket);
-//
-//         let mut buffer = Buffer::with_capacity(1000);
-//
-//         let raw_buffer = buffer.get_unwritten(1000);
-//         rand::thread_rng().fill(raw_buffer);
-//         let written = unsafe { buffer.advance_write(1000) }.to_vec();
-//
-//         let mut writeable = true;
-//         write_slice(
-//             &mut socket,
-//             buffer.get_written(),
-//             &mut unwritten,
-//             &mut writeable,
-//         )
-//         .unwrap();
-//
-//         assert!(writeable);
-//         assert!(unwritten.is_empty());
-//         assert_eq!(socket.0 .0.into_inner(), written);
-//     }
-//
-//     #[test]
-//     fn buffered_write() {
-//         let mut socket = EOF2WB(Chunked(Cursor::new([0u8; 500]), 37));
-//         let mut buffer = Buffer::with_capacity(1000);
-//         let mut unwritten = Buffer::with_capacity(500);
-//
-//         let raw_buffer = buffer.get_unwritten(1000);
-//         rand::thread_rng().fill(raw_buffer);
-//         let written = unsafe { buffer.advance_write(1000) }.to_vec();
-//
-//         let mut writeable = true;
-//         write_slice(
-//             &mut socket,
-//             buffer.get_written(),
-//             &mut unwritten,
-//             &mut writeable,
-//         )
-//         .unwrap();
-//
-//         assert!(!writeable);
-//         assert_eq!(socket.0 .0.into_inner(), &written[..500]);
-//         assert_eq!(unwritten.get_written(), &written[500..]);
-//
-//         let socket = EOF2WB(Chunked(Cursor::new(Vec::with_capacity(1000)), 37));
-//         let mut connection_ctx = ConnectionWriteContext::new(socket);
-//         connection_ctx.writeable = true;
-//         connection_ctx.unwritten_buf = unwritten;
-//
-//         write(&mut connection_ctx).unwrap();
-//
-//         let ConnectionWriteContext {
-//             socket,
-//             unwritten_buf: unwritten,
-//             writeable,
-//             ..
-//         } = connection_ctx;
-//
-//         assert!(writeable);
-//         assert!(unwritten.is_empty());
-//         assert_eq!(socket.0 .0.into_inner(), &written[500..]);
-//     }
-//
-//     #[test]
-//     fn full_write() {
-//         let mut socket = EOF2WB(Cursor::new([0u8; 0]));
-//         let mut buffer = Buffer::with_capacity(1000);
-//         let mut unwritten = Buffer::with_capacity(1000);
-//
-//         let raw_buffer = buffer.get_unwritten(1000);
-//         rand::thread_rng().fill(raw_buffer);
-//         let written = unsafe { buffer.advance_write(1000) }.to_vec();
-//
-//         let mut writeable = true;
-//         write_slice(
-//             &mut socket,
-//             buffer.get_written(),
-//             &mut unwritten,
-//             &mut writeable,
-//         )
-//         .unwrap();
-//
-//         assert!(!writeable);
-//         assert!(buffer.is_empty());
-//         assert_eq!(unwritten.get_written(), written);
-//
-//         let socket = EOF2WB(Chunked(Cursor::new(Vec::with_capacity(1000)), 37));
-//         let mut connection_ctx = ConnectionWriteContext::new(socket);
-//         connection_ctx.writeable = true;
-//         connection_ctx.unwritten_buf = unwritten;
-//
-//         write(&mut connection_ctx).unwrap();
-//
-//         let ConnectionWriteContext {
-//             socket,
-//             unwritten_buf: unwritten,
-//             writeable,
-//             ..
-//         } = connection_ctx;
-//
-//         assert!(writeable);
-//         assert!(unwritten.is_empty());
-//         assert_eq!(socket.0 .0.into_inner(), written);
-//     }
-// }
-//
-// mod read {
-//     use super::*;
-//     use crate::{CompressionReadContext, ConnectionReadContext, FramedPacket, GlobalReadContext};
-//     use std::io::Cursor;
-//
-//     #[test]
-//     fn read_packets() {
-//         let stream = &[
-//             // One byte special case test
-//             0x01, 0xFF, // Packets with random data
-//             0x02, 0x05, 0x07, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05, 0x07, 0x01, 0x02, 0x03, 0x04,
-//             0x05, 0x06, 0x07, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05, // Fixed width varints
-//             0x81, 0x80, 0x00, 0xBB, 0x85, 0x80, 0x00, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
-//         ];
-//         let packets = &[
-//             &stream[1..2],
-//             &stream[3..5],
-//             &stream[6..11],
-//             &stream[12..19],
-//             &stream[20..25],
-//             &stream[28..29],
-//             &stream[32..37],
-//         ];
-//
-//         let socket = EOF2WB(Chunked(Cursor::new(stream.to_vec()), 5));
-//         let mut global_ctx = GlobalReadContext::new();
-//         let mut connection_ctx = ConnectionReadContext::new(socket);
-//         let mut received_packets = Vec::new();
-//
-//         read(
-//             &mut global_ctx,
-//             &mut connection_ctx,
-//             |packet: &FramedPacket, _: CompressionReadContext| {
-//                 received_packets.push(packet.0.to_vec());
-//                 Ok(())
-//             },
-//         )
-//         .unwrap();
-//
-//         let ConnectionReadContext { unread_buf, .. } = connection_ctx;
-//
-//         assert_eq!(&packets[..], &received_packets[..]);
-//         assert!(unread_buf.is_empty());
-//     }
-//
-//     #[test]
-//     fn read_partial() {
-//         let mut buffer = [
-//             0x15, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
-//         ];
-//         let (buffer1, buffer2) = buffer.split_at_mut(11);
-//         let socket = EOF2WB(Chunked(Cursor::new(&mut buffer1[..]), 5));
-//
-//         let mut global_ctx = GlobalReadContext::new();
-//         let mut connection_ctx = ConnectionReadContext::new(socket);
-//         let mut received_packets = Vec::new();
-//
-//         read(
-//             &mut global_ctx,
-//             &mut connection_ctx,
-//             |packet: &FramedPacket, _: CompressionReadContext| {
-//                 received_packets.push(packet.0.to_vec());
-//                 Ok(())
-//             },
-//         )
-//         .unwrap();
-//
-//         let ConnectionReadContext { unread_buf, .. } = connection_ctx;
-//
-//         assert!(received_packets.is_empty());
-//         assert_eq!(unread_buf.len(), buffer1.len());
-//
-//         let socket = EOF2WB(Chunked(Cursor::new(&mut buffer2[..]), 5));
-//         let mut connection_ctx = ConnectionReadContext::new(socket);
-//         connection_ctx.unread_buf = unread_buf;
-//
-//         read(
-//             &mut global_ctx,
-//             &mut connection_ctx,
-//             |packet: &FramedPacket, _: CompressionReadContext| {
-//                 received_packets.push(packet.0.to_vec());
-//                 Ok(())
-//             },
-//         )
-//         .unwrap();
-//
-//         let ConnectionReadContext { unread_buf, .. } = connection_ctx;
-//
-//         assert_eq!(&received_packets[..], &[&buffer[1..]]);
-//         assert!(unread_buf.is_empty());
-//     }
-// }
+use super::mock::{MockStream, ReadStep, WriteStep};
+use crate::error::CommunicationError;
+use crate::state::{Client, ProtocolState, StateMachine};
+use crate::{PacketHandler, RawPacket};
+use proto::packets::c2s::play::{ChatMesssagePacket, KeepAlivePacket};
+use proto::{Data, Packet};
+use rand::Rng;
+use std::io::ErrorKind;
+use std::rc::Rc;
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill(&mut bytes[..]);
+    bytes
+}
+
+// The packet id followed by the packet body, as a handler would receive it
+fn encode_raw<'a, P: Packet<'a>>(packet: &'a P) -> Vec<u8> {
+    let mut buffer = vec![0; 1 + packet.expected_size()];
+    buffer[0] = P::PACKET_ID_NUM;
+    let remaining = packet.encode(&mut buffer[1..]).len();
+    buffer.truncate(buffer.len() - remaining);
+    buffer
+}
+
+// A client that has finished logging in
+fn play(compression_threshold: i32) -> StateMachine<Client> {
+    StateMachine::resume(ProtocolState::Play, compression_threshold)
+}
+
+fn chat(message: &str) -> ChatMesssagePacket<'_> {
+    ChatMesssagePacket {
+        message,
+        timestamp: 0,
+        salt: 0,
+        signature: &[],
+        signed_preview: false,
+        seen_messages: Vec::new(),
+        last_seen: None,
+    }
+}
+
+struct Collector {
+    packets: Vec<Vec<u8>>,
+    compression_threshold: i32,
+}
+
+impl Collector {
+    fn new(compression_threshold: i32) -> Self {
+        Self {
+            packets: Vec::new(),
+            compression_threshold,
+        }
+    }
+}
+
+impl PacketHandler<()> for Collector {
+    fn parse_and_handle(
+        &mut self,
+        packet: RawPacket,
+        _: &mut (),
+    ) -> Result<(), CommunicationError> {
+        self.packets.push(packet.0.to_vec());
+        Ok(())
+    }
+
+    fn compression_threshold(&self) -> i32 {
+        self.compression_threshold
+    }
+
+    fn protocol_state(&self) -> ProtocolState {
+        ProtocolState::Play
+    }
+}
+
+mod write {
+    use super::*;
+    use crate::error::WriteError;
+    use crate::{Backpressure, ConnectionWriteContext, GlobalWriteContext};
+    use proto::packets::c2s::handshake::HandshakePacket;
+    use proto::packets::c2s::login::LoginStartPacket;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn normal_write() {
+        let socket = Rc::new(MockStream::new().with_write_chunk(37));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let written = random_bytes(1000);
+        connection_ctx.write_slice(&written).unwrap();
+
+        assert!(connection_ctx.writeable);
+        assert!(connection_ctx.unwritten_buf.is_empty());
+        assert_eq!(socket.written(), written);
+    }
+
+    #[test]
+    fn buffered_write() {
+        let socket = Rc::new(
+            MockStream::new()
+                .with_write_chunk(37)
+                .with_write_capacity(500),
+        );
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let written = random_bytes(1000);
+        connection_ctx.write_slice(&written).unwrap();
+
+        assert!(!connection_ctx.writeable);
+        assert_eq!(socket.written(), &written[..500]);
+        assert_eq!(connection_ctx.unwritten_buf.get_written(), &written[500..]);
+
+        socket.grow_write_capacity(500);
+        connection_ctx.write_unwritten().unwrap();
+
+        assert!(connection_ctx.writeable);
+        assert!(connection_ctx.unwritten_buf.is_empty());
+        assert_eq!(socket.written(), written);
+    }
+
+    #[test]
+    fn full_write() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(0));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let written = random_bytes(1000);
+        connection_ctx.write_slice(&written).unwrap();
+
+        assert!(!connection_ctx.writeable);
+        assert!(socket.written().is_empty());
+        assert_eq!(connection_ctx.unwritten_buf.get_written(), written);
+
+        socket.set_write_capacity(None);
+        connection_ctx.write_unwritten().unwrap();
+
+        assert!(connection_ctx.writeable);
+        assert!(connection_ctx.unwritten_buf.is_empty());
+        assert_eq!(socket.written(), written);
+    }
+
+    #[test]
+    fn partial_flush() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(100));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let written = random_bytes(1000);
+        connection_ctx.write_slice(&written).unwrap();
+
+        socket.grow_write_capacity(300);
+        connection_ctx.write_unwritten().unwrap();
+
+        assert!(!connection_ctx.writeable);
+        assert_eq!(socket.written(), &written[..400]);
+        assert_eq!(connection_ctx.unwritten_buf.get_written(), &written[400..]);
+
+        socket.set_write_capacity(None);
+        connection_ctx.write_unwritten().unwrap();
+
+        assert!(connection_ctx.unwritten_buf.is_empty());
+        assert_eq!(socket.written(), written);
+    }
+
+    #[test]
+    fn vectored_flush() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(100));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let mut written = random_bytes(300);
+        connection_ctx.write_slice(&written).unwrap();
+        assert_eq!(connection_ctx.unwritten_buf.len(), 200);
+
+        socket.set_write_capacity(None);
+        connection_ctx.writeable = true;
+        let write_calls = socket.write_calls();
+
+        let next = random_bytes(300);
+        connection_ctx.write_slice(&next).unwrap();
+        written.extend_from_slice(&next);
+
+        // Pending and new data should go out together
+        assert_eq!(socket.write_calls(), write_calls + 1);
+        assert!(connection_ctx.unwritten_buf.is_empty());
+        assert_eq!(socket.written(), written);
+    }
+
+    #[test]
+    fn vectored_partial() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(100));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let mut written = random_bytes(300);
+        connection_ctx.write_slice(&written).unwrap();
+
+        // Runs out of room part way through the new data
+        socket.grow_write_capacity(250);
+        connection_ctx.writeable = true;
+
+        let next = random_bytes(300);
+        connection_ctx.write_slice(&next).unwrap();
+        written.extend_from_slice(&next);
+
+        assert!(!connection_ctx.writeable);
+        assert_eq!(socket.written(), &written[..350]);
+        assert_eq!(connection_ctx.unwritten_buf.get_written(), &written[350..]);
+    }
+
+    #[test]
+    fn high_water_error() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(0));
+        let mut connection_ctx = ConnectionWriteContext::new(socket);
+        connection_ctx.limits.high_water_mark = 1000;
+
+        connection_ctx.write_slice(&random_bytes(600)).unwrap();
+        let res = connection_ctx.write_slice(&random_bytes(600));
+
+        assert!(matches!(
+            res,
+            Err(CommunicationError::Write(WriteError::Backpressure(1200)))
+        ));
+    }
+
+    #[test]
+    fn high_water_callback() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(0));
+        let mut connection_ctx = ConnectionWriteContext::new(socket);
+
+        let reported = Arc::new(AtomicUsize::new(0));
+        connection_ctx.limits.high_water_mark = 1000;
+        connection_ctx.limits.backpressure = Backpressure::Callback({
+            let reported = reported.clone();
+            Arc::new(move |buffered| reported.store(buffered, Ordering::Relaxed))
+        });
+
+        connection_ctx.write_slice(&random_bytes(600)).unwrap();
+        assert_eq!(reported.load(Ordering::Relaxed), 0);
+
+        connection_ctx.write_slice(&random_bytes(600)).unwrap();
+        assert_eq!(reported.load(Ordering::Relaxed), 1200);
+        assert_eq!(connection_ctx.unwritten_buf.len(), 1200);
+    }
+
+    #[test]
+    fn unwritten_shrinks() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(0));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+        connection_ctx.limits.retained_capacity = 4096;
+
+        let written = random_bytes(100_000);
+        connection_ctx.write_slice(&written).unwrap();
+        assert!(connection_ctx.unwritten_buf.capacity() >= 100_000);
+
+        socket.set_write_capacity(None);
+        connection_ctx.write_unwritten().unwrap();
+
+        assert!(connection_ctx.unwritten_buf.is_empty());
+        assert!(connection_ctx.unwritten_buf.capacity() <= 4096);
+        assert_eq!(socket.written(), written);
+    }
+
+    #[test]
+    fn blocked_write_skips_socket() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(0));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        connection_ctx.write_slice(&random_bytes(10)).unwrap();
+        let write_calls = socket.write_calls();
+
+        connection_ctx.write_slice(&random_bytes(10)).unwrap();
+
+        assert_eq!(socket.write_calls(), write_calls);
+        assert_eq!(connection_ctx.unwritten_buf.len(), 20);
+    }
+
+    #[test]
+    fn interrupted_write() {
+        let socket = Rc::new(MockStream::new());
+        socket.push_write(WriteStep::Error(ErrorKind::Interrupted));
+        socket.push_write(WriteStep::Accept(10));
+        socket.push_write(WriteStep::Error(ErrorKind::Interrupted));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let written = random_bytes(100);
+        connection_ctx.write_slice(&written).unwrap();
+
+        assert!(connection_ctx.writeable);
+        assert!(connection_ctx.unwritten_buf.is_empty());
+        assert_eq!(socket.written(), written);
+    }
+
+    #[test]
+    fn write_error() {
+        let socket = Rc::new(MockStream::new());
+        socket.push_write(WriteStep::Accept(10));
+        socket.push_write(WriteStep::Error(ErrorKind::BrokenPipe));
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let res = connection_ctx.write_slice(&random_bytes(100));
+
+        assert!(
+            matches!(res, Err(CommunicationError::Io(err)) if err.kind() == ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[test]
+    fn backpressure() {
+        let socket = Rc::new(MockStream::new().with_write_capacity(10));
+        let mut global_ctx = GlobalWriteContext::new();
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+
+        let mut expected = Vec::new();
+        for id in 0..100 {
+            let packet = KeepAlivePacket { id };
+            connection_ctx
+                .write_packet(&packet, &mut global_ctx, &play(-1))
+                .unwrap();
+
+            // Uncompressed frames always use a 3 byte length header
+            let raw = encode_raw(&packet);
+            expected.extend_from_slice(&[raw.len() as u8 | 0x80, 0x80, 0x00]);
+            expected.extend_from_slice(&raw);
+        }
+
+        assert!(!connection_ctx.writeable);
+        assert_eq!(socket.written(), &expected[..10]);
+        assert_eq!(connection_ctx.unwritten_buf.get_written(), &expected[10..]);
+
+        // Drain the socket a little at a time, like a slow peer would
+        while !connection_ctx.unwritten_buf.is_empty() {
+            socket.grow_write_capacity(77);
+            connection_ctx.write_unwritten().unwrap();
+        }
+
+        assert_eq!(socket.written(), expected);
+    }
+
+    #[test]
+    fn wrong_state() {
+        let socket = Rc::new(MockStream::new());
+        let mut global_ctx = GlobalWriteContext::new();
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+        let mut state = StateMachine::<Client>::new();
+
+        let res = connection_ctx.write_packets(&mut global_ctx, &mut state, |writer| {
+            writer.write_packet(&KeepAlivePacket { id: 1 })
+        });
+
+        assert!(matches!(
+            res,
+            Err(CommunicationError::Write(WriteError::BadProtocolState(
+                _,
+                ProtocolState::Handshake
+            )))
+        ));
+        assert!(socket.written().is_empty());
+    }
+
+    #[test]
+    fn state_change_mid_write() {
+        let socket = Rc::new(MockStream::new());
+        let mut global_ctx = GlobalWriteContext::new();
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+        let mut state = StateMachine::<Client>::new();
+
+        let handshake = HandshakePacket {
+            protocol_version: 760,
+            server_address: "localhost",
+            server_port: 25565,
+            next_state: ProtocolState::Login.id(),
+        };
+        let login_start = LoginStartPacket {
+            username: "bot",
+            signature_data: None,
+            uuid: None,
+        };
+
+        connection_ctx
+            .write_packets(&mut global_ctx, &mut state, |writer| {
+                writer.write_packet(&handshake)?;
+                writer.state().transition(ProtocolState::Login)?;
+                writer.write_packet(&login_start)?;
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(state.state(), ProtocolState::Login);
+        assert_eq!(socket.write_calls(), 1);
+    }
+}
+
+mod read {
+    use super::*;
+    use crate::error::ReadError;
+    use crate::{CompressionReadContext, ConnectionReadContext, FramedPacket, GlobalReadContext};
+
+    fn read_framed(
+        global_ctx: &mut GlobalReadContext,
+        connection_ctx: &mut ConnectionReadContext<Rc<MockStream>>,
+    ) -> Result<Vec<Vec<u8>>, CommunicationError> {
+        let mut received_packets = Vec::new();
+
+        connection_ctx.read(
+            global_ctx,
+            |packet: &FramedPacket, _: CompressionReadContext| {
+                received_packets.push(packet.0.to_vec());
+                Ok(())
+            },
+        )?;
+
+        Ok(received_packets)
+    }
+
+    #[test]
+    fn read_packets() {
+        let stream = &[
+            // One byte special case test
+            0x01, 0xFF, // Packets with random data
+            0x02, 0x05, 0x07, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05, 0x07, 0x01, 0x02, 0x03, 0x04,
+            0x05, 0x06, 0x07, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05, // Fixed width varints
+            0x81, 0x80, 0x00, 0xBB, 0x85, 0x80, 0x00, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        ];
+        let packets = &[
+            &stream[1..2],
+            &stream[3..5],
+            &stream[6..11],
+            &stream[12..19],
+            &stream[20..25],
+            &stream[28..29],
+            &stream[32..37],
+        ];
+
+        let socket = Rc::new(MockStream::new().with_read_chunk(5));
+        socket.push_data(stream);
+
+        let mut global_ctx = GlobalReadContext::new();
+        let mut connection_ctx = ConnectionReadContext::new(socket);
+
+        let received_packets = read_framed(&mut global_ctx, &mut connection_ctx).unwrap();
+
+        assert_eq!(&packets[..], &received_packets[..]);
+        assert!(connection_ctx.unread_buf.is_empty());
+    }
+
+    #[test]
+    fn read_partial() {
+        let buffer = [
+            0x15, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+        ];
+        let (buffer1, buffer2) = buffer.split_at(11);
+
+        let socket = Rc::new(MockStream::new().with_read_chunk(5));
+        socket.push_data(buffer1);
+
+        let mut global_ctx = GlobalReadContext::new();
+        let mut connection_ctx = ConnectionReadContext::new(socket.clone());
+
+        let received_packets = read_framed(&mut global_ctx, &mut connection_ctx).unwrap();
+
+        assert!(received_packets.is_empty());
+        assert_eq!(connection_ctx.unread_buf.get_written(), buffer1);
+
+        socket.push_data(buffer2);
+        let received_packets = read_framed(&mut global_ctx, &mut connection_ctx).unwrap();
+
+        assert_eq!(&received_packets[..], &[&buffer[1..]]);
+        assert!(connection_ctx.unread_buf.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Too slow
+    fn split_at_every_offset() {
+        let mut stream = Vec::new();
+        let mut packets = Vec::new();
+        for len in [1, 2, 3, 127, 128, 300, 5000] {
+            let packet = random_bytes(len);
+            let mut header = [0; 3];
+            let remaining = proto::primitive::v21(len as u32).encode(&mut header).len();
+            stream.extend_from_slice(&header[..3 - remaining]);
+            stream.extend_from_slice(&packet);
+            packets.push(packet);
+        }
+
+        for split in 0..=stream.len() {
+            let socket = Rc::new(MockStream::new());
+            let mut global_ctx = GlobalReadContext::new();
+            let mut connection_ctx = ConnectionReadContext::new(socket.clone());
+
+            // An empty read would look like the peer closing the connection
+            let (first, second) = stream.split_at(split);
+            if !first.is_empty() {
+                socket.push_data(first);
+            }
+            let mut received_packets = read_framed(&mut global_ctx, &mut connection_ctx).unwrap();
+
+            if !second.is_empty() {
+                socket.push_data(second);
+            }
+            received_packets.extend(read_framed(&mut global_ctx, &mut connection_ctx).unwrap());
+
+            assert_eq!(received_packets, packets, "split at {split}");
+            assert!(connection_ctx.unread_buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn interrupted_read() {
+        let socket = Rc::new(MockStream::new());
+        socket.push_read(ReadStep::Error(ErrorKind::Interrupted));
+        socket.push_data(&[0x02, 0xAA, 0xBB]);
+        socket.push_read(ReadStep::Error(ErrorKind::Interrupted));
+        socket.push_data(&[0x01, 0xCC]);
+
+        let mut global_ctx = GlobalReadContext::new();
+        let mut connection_ctx = ConnectionReadContext::new(socket);
+
+        let received_packets = read_framed(&mut global_ctx, &mut connection_ctx).unwrap();
+
+        assert_eq!(received_packets, [vec![0xAA, 0xBB], vec![0xCC]]);
+    }
+
+    #[test]
+    fn read_closed() {
+        let socket = Rc::new(MockStream::new());
+        socket.push_data(&[0x02, 0xAA, 0xBB]);
+        socket.push_read(ReadStep::Eof);
+
+        let mut global_ctx = GlobalReadContext::new();
+        let mut connection_ctx = ConnectionReadContext::new(socket);
+        let mut received_packets = Vec::new();
+
+        let res = connection_ctx.read(
+            &mut global_ctx,
+            |packet: &FramedPacket, _: CompressionReadContext| {
+                received_packets.push(packet.0.to_vec());
+                Ok(())
+            },
+        );
+
+        assert!(matches!(res, Err(CommunicationError::Closed)));
+        assert_eq!(received_packets, [vec![0xAA, 0xBB]]);
+    }
+
+    #[test]
+    fn read_error() {
+        let socket = Rc::new(MockStream::new());
+        socket.push_read(ReadStep::Error(ErrorKind::ConnectionReset));
+
+        let mut global_ctx = GlobalReadContext::new();
+        let mut connection_ctx = ConnectionReadContext::new(socket);
+
+        let res = read_framed(&mut global_ctx, &mut connection_ctx);
+
+        assert!(
+            matches!(res, Err(CommunicationError::Io(err)) if err.kind() == ErrorKind::ConnectionReset)
+        );
+    }
+
+    #[test]
+    fn unread_high_water() {
+        let socket = Rc::new(MockStream::new());
+        // The start of a 10000 byte packet
+        socket.push_data(&[0x90, 0xCE, 0x00]);
+        socket.push_data(&random_bytes(5000));
+
+        let mut global_ctx = GlobalReadContext::new();
+        let mut connection_ctx = ConnectionReadContext::new(socket);
+        connection_ctx.limits.high_water_mark = 4096;
+
+        let res = read_framed(&mut global_ctx, &mut connection_ctx);
+
+        assert!(matches!(
+            res,
+            Err(CommunicationError::Read(ReadError::Backpressure(5003)))
+        ));
+    }
+
+    #[test]
+    fn packet_too_large() {
+        let socket = Rc::new(MockStream::new());
+        socket.push_data(&[0xFF, 0xFF, 0x7F]);
+
+        let mut global_ctx = GlobalReadContext::new();
+        let mut connection_ctx = ConnectionReadContext::new(socket);
+
+        let res = read_framed(&mut global_ctx, &mut connection_ctx);
+
+        assert!(matches!(
+            res,
+            Err(CommunicationError::Read(
+                crate::error::ReadError::PacketTooLarge
+            ))
+        ));
+    }
+}
+
+mod roundtrip {
+    use super::*;
+    use crate::{
+        ConnectionReadContext, ConnectionWriteContext, GlobalReadContext, GlobalWriteContext,
+        Segment,
+    };
+    use proto::primitive::{VarInt, V21};
+
+    // The `data length` field of every frame in a compressed stream, 0 means uncompressed
+    fn data_lengths(mut stream: &[u8]) -> Vec<usize> {
+        let mut data_lens = Vec::new();
+
+        while !stream.is_empty() {
+            let total_len: usize = V21::try_decode(&mut stream).unwrap().into();
+            let mut frame = &stream[..total_len];
+            data_lens.push(VarInt::try_decode(&mut frame).unwrap().into());
+            stream = &stream[total_len..];
+        }
+
+        data_lens
+    }
+
+    fn roundtrip(compression_threshold: i32, read_chunk: usize) {
+        let long_message = "a".repeat(1000);
+        let random_message: String = (0..500)
+            .map(|_| rand::thread_rng().gen_range('a'..='z'))
+            .collect();
+
+        let keep_alive = KeepAlivePacket { id: 0xDEADBEEF };
+        let short_chat = chat("Hello");
+        let long_chat = chat(&long_message);
+        let random_chat = chat(&random_message);
+
+        let expected = vec![
+            encode_raw(&keep_alive),
+            encode_raw(&long_chat),
+            encode_raw(&short_chat),
+            encode_raw(&random_chat),
+        ];
+
+        let writer_socket = Rc::new(MockStream::new().with_write_chunk(13));
+        let mut global_write_ctx = GlobalWriteContext::new();
+        let mut write_ctx = ConnectionWriteContext::new(writer_socket.clone());
+
+        write_ctx
+            .write_packet(
+                &keep_alive,
+                &mut global_write_ctx,
+                &play(compression_threshold),
+            )
+            .unwrap();
+        write_ctx
+            .write_packets(
+                &mut global_write_ctx,
+                &mut play(compression_threshold),
+                |writer| {
+                    writer.write_packet(&long_chat)?;
+                    writer.write_packet(&short_chat)?;
+                    writer.write_packet(&random_chat)?;
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        let stream = writer_socket.written();
+        if compression_threshold > 0 {
+            let expected_data_lens: Vec<usize> = expected
+                .iter()
+                .map(|it| {
+                    if it.len() >= compression_threshold as usize {
+                        it.len()
+                    } else {
+                        0
+                    }
+                })
+                .collect();
+            assert_eq!(data_lengths(&stream), expected_data_lens);
+        }
+
+        let reader_socket = Rc::new(MockStream::new().with_read_chunk(read_chunk));
+        reader_socket.push_data(&stream);
+
+        let mut global_read_ctx = GlobalReadContext::new();
+        let mut read_ctx = ConnectionReadContext::new(reader_socket);
+        let mut collector = Collector::new(compression_threshold);
+
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut collector, &mut ())
+            .unwrap();
+
+        assert_eq!(collector.packets, expected);
+        assert!(read_ctx.unread_buf.is_empty());
+    }
+
+    #[test]
+    fn uncompressed() {
+        roundtrip(-1, usize::MAX);
+        roundtrip(-1, 1);
+        roundtrip(-1, 7);
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
+    fn compressed() {
+        roundtrip(64, usize::MAX);
+        roundtrip(64, 1);
+        roundtrip(64, 7);
+    }
+
+    #[test]
+    fn compressed_below_threshold() {
+        roundtrip(5000, usize::MAX);
+        roundtrip(5000, 3);
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
+    fn uncompressed_fallback_in_order() {
+        // Expected sizes overestimate varints, so this is encoded for compression but ends up
+        // under the threshold
+        let fallback_message = "c".repeat(70);
+        let long_message = "d".repeat(1000);
+
+        let first = KeepAlivePacket { id: 1 };
+        let fallback_chat = chat(&fallback_message);
+        let second = KeepAlivePacket { id: 2 };
+        let long_chat = chat(&long_message);
+
+        let expected = vec![
+            encode_raw(&first),
+            encode_raw(&fallback_chat),
+            encode_raw(&second),
+            encode_raw(&long_chat),
+        ];
+
+        let writer_socket = Rc::new(MockStream::new());
+        let mut global_write_ctx = GlobalWriteContext::new();
+        let mut write_ctx = ConnectionWriteContext::new(writer_socket.clone());
+
+        write_ctx
+            .write_packets(&mut global_write_ctx, &mut play(100), |writer| {
+                writer.write_packet(&first)?;
+                writer.write_packet(&fallback_chat)?;
+                writer.write_packet(&second)?;
+                writer.write_packet(&long_chat)?;
+                Ok(())
+            })
+            .unwrap();
+
+        assert!(matches!(
+            global_write_ctx.segments[..],
+            [
+                Segment::WriteBuf(_),
+                Segment::CompressionBuf(_),
+                Segment::WriteBuf(_)
+            ]
+        ));
+        assert_eq!(writer_socket.write_calls(), 1);
+
+        let stream = writer_socket.written();
+        assert_eq!(data_lengths(&stream), [0, 0, 0, expected[3].len()]);
+
+        let reader_socket = Rc::new(MockStream::new());
+        reader_socket.push_data(&stream);
+
+        let mut global_read_ctx = GlobalReadContext::new();
+        let mut read_ctx = ConnectionReadContext::new(reader_socket);
+        let mut collector = Collector::new(100);
+
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut collector, &mut ())
+            .unwrap();
+
+        assert_eq!(collector.packets, expected);
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
+    fn compressed_across_reads() {
+        let message = "b".repeat(4000);
+        let packet = chat(&message);
+        let expected = encode_raw(&packet);
+
+        let writer_socket = Rc::new(MockStream::new());
+        let mut global_write_ctx = GlobalWriteContext::new();
+        let mut write_ctx = ConnectionWriteContext::new(writer_socket.clone());
+        write_ctx
+            .write_packet(&packet, &mut global_write_ctx, &play(256))
+            .unwrap();
+        let stream = writer_socket.written();
+
+        let reader_socket = Rc::new(MockStream::new());
+        let mut global_read_ctx = GlobalReadContext::new();
+        let mut read_ctx = ConnectionReadContext::new(reader_socket.clone());
+        let mut collector = Collector::new(256);
+
+        let (first, second) = stream.split_at(stream.len() / 2);
+
+        reader_socket.push_data(first);
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut collector, &mut ())
+            .unwrap();
+        assert!(collector.packets.is_empty());
+        assert_eq!(read_ctx.unread_buf.get_written(), first);
+
+        reader_socket.push_data(second);
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut collector, &mut ())
+            .unwrap();
+        assert_eq!(collector.packets, [expected]);
+    }
+}
+
+mod encryption {
+    use super::*;
+    use crate::encryption::{self, Decryptor};
+    use crate::{
+        ConnectionReadContext, ConnectionWriteContext, GlobalReadContext, GlobalWriteContext,
+    };
+
+    #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
+    fn encrypted_roundtrip() {
+        let long_message = "e".repeat(1000);
+        let keep_alive = KeepAlivePacket { id: 7 };
+        let short_chat = chat("Hello");
+        let long_chat = chat(&long_message);
+
+        let expected = vec![
+            encode_raw(&keep_alive),
+            encode_raw(&short_chat),
+            encode_raw(&long_chat),
+            encode_raw(&keep_alive),
+        ];
+
+        // An uncompressed frame in the compressed format, written as raw bytes
+        let mut short_frame = vec![expected[1].len() as u8 + 1, 0];
+        short_frame.extend_from_slice(&expected[1]);
+
+        let (encryptor, decryptor) = encryption::ciphers(&rand::random());
+
+        // Small writes leave encrypted bytes queued between calls
+        let writer_socket = Rc::new(MockStream::new().with_write_chunk(13));
+        let mut global_write_ctx = GlobalWriteContext::new();
+        let mut write_ctx = ConnectionWriteContext::new(writer_socket.clone());
+        write_ctx.cipher = Some(encryptor);
+
+        write_ctx
+            .write_packet(&keep_alive, &mut global_write_ctx, &play(64))
+            .unwrap();
+        write_ctx.write_slice(&short_frame).unwrap();
+        write_ctx
+            .write_packets(&mut global_write_ctx, &mut play(64), |writer| {
+                writer.write_packet(&long_chat)?;
+                writer.write_packet(&keep_alive)?;
+                Ok(())
+            })
+            .unwrap();
+
+        let stream = writer_socket.written();
+        assert!(!stream
+            .windows(short_frame.len())
+            .any(|it| it == short_frame));
+
+        let reader_socket = Rc::new(MockStream::new().with_read_chunk(7));
+        reader_socket.push_data(&stream);
+
+        let mut global_read_ctx = GlobalReadContext::new();
+        let mut read_ctx = ConnectionReadContext::new(reader_socket);
+        read_ctx.cipher = Some(decryptor);
+        let mut collector = Collector::new(64);
+
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut collector, &mut ())
+            .unwrap();
+
+        assert_eq!(collector.packets, expected);
+        assert!(read_ctx.unread_buf.is_empty());
+    }
+
+    // Enables decryption after the first packet, like a server receiving the encryption response
+    struct Switcher {
+        packets: Vec<Vec<u8>>,
+        decryptor: Option<Decryptor>,
+    }
+
+    impl PacketHandler<()> for Switcher {
+        fn parse_and_handle(
+            &mut self,
+            packet: RawPacket,
+            _: &mut (),
+        ) -> Result<(), CommunicationError> {
+            self.packets.push(packet.0.to_vec());
+            Ok(())
+        }
+
+        fn compression_threshold(&self) -> i32 {
+            -1
+        }
+
+        fn protocol_state(&self) -> ProtocolState {
+            ProtocolState::Play
+        }
+
+        fn take_decryptor(&mut self) -> Option<Decryptor> {
+            self.decryptor.take()
+        }
+    }
+
+    #[test]
+    fn enabled_mid_read() {
+        let first = KeepAlivePacket { id: 1 };
+        let second = chat("Encrypted");
+        let third = KeepAlivePacket { id: 3 };
+
+        let expected = vec![encode_raw(&first), encode_raw(&second), encode_raw(&third)];
+
+        let (encryptor, decryptor) = encryption::ciphers(&rand::random());
+
+        let writer_socket = Rc::new(MockStream::new());
+        let mut global_write_ctx = GlobalWriteContext::new();
+        let mut write_ctx = ConnectionWriteContext::new(writer_socket.clone());
+
+        write_ctx
+            .write_packet(&first, &mut global_write_ctx, &play(-1))
+            .unwrap();
+        write_ctx.cipher = Some(encryptor);
+        write_ctx
+            .write_packets(&mut global_write_ctx, &mut play(-1), |writer| {
+                writer.write_packet(&second)?;
+                writer.write_packet(&third)?;
+                Ok(())
+            })
+            .unwrap();
+
+        // Everything arrives in a single read
+        let reader_socket = Rc::new(MockStream::new());
+        reader_socket.push_data(&writer_socket.written());
+
+        let mut global_read_ctx = GlobalReadContext::new();
+        let mut read_ctx = ConnectionReadContext::new(reader_socket.clone());
+        let mut switcher = Switcher {
+            packets: Vec::new(),
+            decryptor: Some(decryptor),
+        };
+
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut switcher, &mut ())
+            .unwrap();
+
+        assert_eq!(switcher.packets, expected);
+        assert!(read_ctx.cipher.is_some());
+        assert_eq!(reader_socket.read_calls(), 2);
+    }
+}
+
+mod capture {
+    use super::*;
+    use crate::capture::{CaptureReader, Recorder};
+    use crate::state::Server;
+    use crate::{
+        ConnectionReadContext, ConnectionWriteContext, GlobalReadContext, GlobalWriteContext,
+    };
+    use proto::Direction;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    // Lets a test read back what a `Recorder` wrote
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_both_directions() {
+        let small = KeepAlivePacket { id: 7 };
+        let message = "A chat message long enough to be compressed ".repeat(4);
+        let large = chat(&message);
+
+        let capture = Shared::default();
+        let recorder = Recorder::new::<Client>(capture.clone()).unwrap();
+
+        let socket = Rc::new(MockStream::new());
+        let mut global_write_ctx = GlobalWriteContext::new();
+        let mut write_ctx = ConnectionWriteContext::new(socket.clone());
+        write_ctx.recorder = Some(recorder.clone());
+
+        write_ctx
+            .write_packet(&small, &mut global_write_ctx, &play(64))
+            .unwrap();
+        write_ctx
+            .write_packets(&mut global_write_ctx, &mut play(64), |writer| {
+                writer.write_packet(&large)
+            })
+            .unwrap();
+
+        // The same bytes read back by the other side
+        let reader_socket = Rc::new(MockStream::new());
+        reader_socket.push_data(&socket.written());
+
+        let server_capture = Shared::default();
+        let mut global_read_ctx = GlobalReadContext::new();
+        let mut read_ctx = ConnectionReadContext::new(reader_socket);
+        read_ctx.recorder = Some(Recorder::new::<Server>(server_capture.clone()).unwrap());
+        let mut collector = Collector::new(64);
+
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut collector, &mut ())
+            .unwrap();
+
+        recorder.flush().unwrap();
+        read_ctx.recorder.as_ref().unwrap().flush().unwrap();
+
+        let expected = [encode_raw(&small), encode_raw(&large)];
+        for capture in [capture, server_capture] {
+            let bytes = capture.0.lock().unwrap().clone();
+            let records: Vec<_> = CaptureReader::new(&bytes[..])
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+
+            assert_eq!(records.len(), 2);
+            for (record, expected) in records.iter().zip(&expected) {
+                assert_eq!(record.data, *expected);
+                assert_eq!(record.direction, Direction::ClientToServer);
+                assert_eq!(record.state, ProtocolState::Play);
+            }
+            assert!(!records[0].compressed);
+            assert!(records[1].compressed);
+        }
+    }
+}
+
+mod observer {
+    use super::*;
+    use crate::observer::{Observer, PacketEvent, PacketObserver};
+    use crate::state::Server;
+    use crate::{
+        ConnectionReadContext, ConnectionWriteContext, GlobalReadContext, GlobalWriteContext,
+    };
+    use proto::Direction;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Events(Mutex<Vec<PacketEvent>>);
+
+    impl PacketObserver for Events {
+        fn observe(&self, packet: &PacketEvent) {
+            self.0.lock().unwrap().push(*packet);
+        }
+    }
+
+    #[test]
+    fn observes_both_directions() {
+        let small = KeepAlivePacket { id: 7 };
+        let message = "A chat message long enough to be compressed ".repeat(4);
+        let large = chat(&message);
+
+        let written = Arc::new(Events::default());
+        let socket = Rc::new(MockStream::new());
+        let mut global_write_ctx = GlobalWriteContext::new();
+        let mut write_ctx = ConnectionWriteContext::new(socket.clone());
+        write_ctx.observer = Some(Observer::new::<Client>(written.clone()));
+
+        write_ctx
+            .write_packet(&small, &mut global_write_ctx, &play(64))
+            .unwrap();
+        write_ctx
+            .write_packets(&mut global_write_ctx, &mut play(64), |writer| {
+                writer.write_packet(&large)
+            })
+            .unwrap();
+
+        let reader_socket = Rc::new(MockStream::new());
+        reader_socket.push_data(&socket.written());
+
+        let read = Arc::new(Events::default());
+        let mut global_read_ctx = GlobalReadContext::new();
+        let mut read_ctx = ConnectionReadContext::new(reader_socket);
+        read_ctx.observer = Some(Observer::new::<Server>(read.clone()));
+        let mut collector = Collector::new(64);
+
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut collector, &mut ())
+            .unwrap();
+
+        let written = written.0.lock().unwrap().clone();
+        let read = read.0.lock().unwrap().clone();
+        assert_eq!(written, read);
+
+        let expected = [
+            (KeepAlivePacket::PACKET_ID_NUM, encode_raw(&small)),
+            (ChatMesssagePacket::PACKET_ID_NUM, encode_raw(&large)),
+        ];
+        assert_eq!(read.len(), expected.len());
+        for (event, (id, raw)) in read.iter().zip(&expected) {
+            assert_eq!(event.id, *id as u64);
+            assert_eq!(event.direction, Direction::ClientToServer);
+            assert_eq!(event.state, ProtocolState::Play);
+            assert_eq!(event.size, raw.len());
+        }
+
+        let wire_size: usize = read.iter().map(|it| it.wire_size).sum();
+        assert_eq!(wire_size, socket.written().len());
+        // Uncompressed, behind the packet and data length prefixes
+        assert_eq!(read[0].wire_size, 3 + 3 + read[0].size);
+    }
+}
+
+mod compression_stats {
+    use super::*;
+    use crate::{
+        CompressionLevel, ConnectionReadContext, ConnectionWriteContext, GlobalReadContext,
+        GlobalWriteContext,
+    };
+
+    #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
+    fn tracked_in_both_directions() {
+        let small = KeepAlivePacket { id: 7 };
+        let message = "A chat message long enough to be compressed ".repeat(4);
+        let large = chat(&message);
+
+        let socket = Rc::new(MockStream::new());
+        let mut global_write_ctx =
+            GlobalWriteContext::with_compression_level(CompressionLevel::BEST);
+        let mut write_ctx = ConnectionWriteContext::new(socket.clone());
+
+        write_ctx
+            .write_packets(&mut global_write_ctx, &mut play(64), |writer| {
+                writer.write_packet(&small)?;
+                writer.write_packet(&large)
+            })
+            .unwrap();
+
+        let reader_socket = Rc::new(MockStream::new());
+        reader_socket.push_data(&socket.written());
+
+        let mut global_read_ctx = GlobalReadContext::new();
+        let mut read_ctx = ConnectionReadContext::new(reader_socket);
+        let mut collector = Collector::new(64);
+
+        read_ctx
+            .read_packets(&mut global_read_ctx, &mut collector, &mut ())
+            .unwrap();
+
+        let written = global_write_ctx.compression_stats;
+        let read = global_read_ctx.compression_stats;
+
+        assert_eq!(written.compressed_packets, 1);
+        assert_eq!(written.uncompressed_bytes, encode_raw(&large).len() as u64);
+        assert_eq!(written.skipped_packets, 1);
+        assert_eq!(written.skipped_bytes, encode_raw(&small).len() as u64);
+        // The repeated message compresses well at the best level
+        assert!(written.ratio().unwrap() < 0.5);
+
+        for (read, written) in [
+            (read.compressed_packets, written.compressed_packets),
+            (read.uncompressed_bytes, written.uncompressed_bytes),
+            (read.compressed_bytes, written.compressed_bytes),
+            (read.skipped_packets, written.skipped_packets),
+            (read.skipped_bytes, written.skipped_bytes),
+        ] {
+            assert_eq!(read, written);
+        }
+    }
+}
+
+mod rate_limit {
+    use super::*;
+    use crate::rate_limit::RateLimit;
+    use crate::{ConnectionWriteContext, GlobalWriteContext};
+    use std::time::{Duration, Instant};
+
+    const BURST: Duration = Duration::from_millis(100);
+
+    // A limit whose clock only moves with `advance`
+    fn frozen(bytes_per_second: Option<u32>, packets_per_second: Option<u32>) -> RateLimit {
+        let mut rate_limit = RateLimit::new(bytes_per_second, packets_per_second, BURST);
+        rate_limit.clock = Some(Instant::now());
+        rate_limit
+    }
+
+    fn advance<D>(connection_ctx: &mut ConnectionWriteContext<D>, by: Duration) {
+        let clock = &mut connection_ctx.rate_limit.as_mut().unwrap().clock;
+        *clock = clock.map(|now| now + by);
+    }
+
+    #[test]
+    fn bytes_held_back() {
+        let socket = Rc::new(MockStream::new());
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+        connection_ctx.rate_limit = Some(frozen(Some(10_000), None));
+
+        let written = random_bytes(3000);
+        connection_ctx.write_slice(&written).unwrap();
+
+        // Held back bytes don't wait for the socket
+        assert!(connection_ctx.writeable);
+        assert_eq!(socket.written(), &written[..1000]);
+        assert_eq!(connection_ctx.unwritten_buf.get_written(), &written[1000..]);
+
+        advance(&mut connection_ctx, Duration::from_millis(50));
+        connection_ctx.release_limited().unwrap();
+        assert_eq!(socket.written(), &written[..1500]);
+
+        // Never more than the burst, however long it has been
+        advance(&mut connection_ctx, Duration::from_secs(10));
+        connection_ctx.release_limited().unwrap();
+        assert_eq!(socket.written(), &written[..2500]);
+    }
+
+    #[test]
+    fn whole_packets_held_back() {
+        let socket = Rc::new(MockStream::new());
+        let mut global_ctx = GlobalWriteContext::new();
+        let mut connection_ctx = ConnectionWriteContext::new(socket.clone());
+        connection_ctx.rate_limit = Some(frozen(None, Some(20)));
+
+        connection_ctx
+            .write_packets(&mut global_ctx, &mut play(-1), |writer| {
+                for id in 0..5 {
+                    writer.write_packet(&KeepAlivePacket { id })?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        // Two of the five packets fit in the burst
+        let packet_len = global_ctx.packet_sizes[0];
+        assert_eq!(socket.written().len(), 2 * packet_len);
+        assert_eq!(connection_ctx.unwritten_buf.len(), 3 * packet_len);
+
+        advance(&mut connection_ctx, BURST);
+        connection_ctx.release_limited().unwrap();
+        assert_eq!(socket.written().len(), 4 * packet_len);
+
+        advance(&mut connection_ctx, BURST / 2);
+        connection_ctx.release_limited().unwrap();
+        assert_eq!(socket.written().len(), 5 * packet_len);
+    }
+}
@@ -1,8 +1,13 @@
-use crate::error::CommunicationError;
-use crate::{buf::Buffer, ConnectionWriteContext};
-use std::io::{ErrorKind, Write};
+use crate::error::{CommunicationError, WriteError};
+use crate::rate_limit::RateLimit;
+use crate::{buf::Buffer, BufferLimits, ConnectionWriteContext};
+use std::io::{ErrorKind, IoSlice, Write};
+use std::iter;
 use std::ops::Deref;
 
+// Linux caps a single `writev` at 1024 slices, this is plenty for one flush
+const MAX_IO_SLICES: usize = 64;
+
 pub(crate) fn write_unwritten<D, S>(
     connection: &mut ConnectionWriteContext<D>,
 ) -> Result<(), CommunicationError>
@@ -14,6 +19,8 @@ where
         socket,
         unwritten_buf: unwritten,
         writeable,
+        limits,
+        rate_limit,
         ..
     } = connection;
     *writeable = true;
@@ -22,77 +29,161 @@ where
         return Ok(());
     }
 
-    let consumed = write_buf(D::deref(socket), unwritten.get_written(), writeable)?;
+    let consumed = write_bufs(
+        D::deref(socket),
+        iter::once(unwritten.get_written()),
+        writeable,
+        rate_limit.as_mut(),
+    )?;
     unwritten.consume(consumed);
+    unwritten.shrink(limits.retained_capacity);
 
     Ok(())
 }
 
-pub(crate) fn write_slice<S>(
+/// Writes any pending bytes followed by `to_write` using as few syscalls as possible.
+/// Whatever the socket doesn't accept or `rate_limit` holds back is copied into `unwritten`.
+pub(crate) fn write_vectored<'a, S, I>(
     socket: S,
-    mut to_write: &[u8],
+    to_write: I,
     unwritten: &mut Buffer,
     writeable: &mut bool,
+    limits: &BufferLimits,
+    rate_limit: Option<&mut RateLimit>,
 ) -> Result<(), CommunicationError>
 where
     S: Write,
+    I: Iterator<Item = &'a [u8]> + Clone,
 {
-    if to_write.is_empty() {
-        return Ok(());
+    let pending = unwritten.len();
+    let consumed = write_bufs(
+        socket,
+        // Shorten the lifetime of `to_write` to match the borrow of `unwritten`
+        iter::once(unwritten.get_written()).chain(to_write.clone().map(|it| it as &[u8])),
+        writeable,
+        rate_limit,
+    )?;
+
+    if consumed < pending {
+        unwritten.consume(consumed);
+
+        for buf in to_write {
+            unwritten.copy_from(buf);
+        }
+    } else {
+        unwritten.reset();
+
+        let mut skip = consumed - pending;
+        for buf in to_write {
+            let skipped = skip.min(buf.len());
+            skip -= skipped;
+
+            unwritten.copy_from(&buf[skipped..]);
+        }
     }
 
-    let consumed = write_buf(socket, to_write, writeable)?;
-    to_write = &to_write[consumed..];
-    unwritten.copy_from(to_write);
+    unwritten.shrink(limits.retained_capacity);
+    limits
+        .check(unwritten.len())
+        .map_err(WriteError::Backpressure)?;
 
     Ok(())
 }
 
-fn write_buf<S>(
-    mut socket: S,
-    mut buffer: &[u8],
+fn write_bufs<'a, S, I>(
+    socket: S,
+    bufs: I,
     writeable: &mut bool,
+    rate_limit: Option<&mut RateLimit>,
 ) -> Result<usize, CommunicationError>
 where
     S: Write,
+    I: Iterator<Item = &'a [u8]>,
 {
-    if !*writeable || buffer.is_empty() {
+    if !*writeable {
         return Ok(0);
     }
 
+    let Some(rate_limit) = rate_limit else {
+        return write_limited(socket, bufs, writeable, usize::MAX);
+    };
+
+    let now = rate_limit.now();
+    let consumed = write_limited(socket, bufs, writeable, rate_limit.allowance(now))?;
+    rate_limit.written(consumed);
+
+    Ok(consumed)
+}
+
+// Writes no more than `allowance` bytes of `bufs`
+fn write_limited<'a, S, I>(
+    mut socket: S,
+    mut bufs: I,
+    writeable: &mut bool,
+    mut allowance: usize,
+) -> Result<usize, CommunicationError>
+where
+    S: Write,
+    I: Iterator<Item = &'a [u8]>,
+{
+    let mut slices = [IoSlice::new(&[]); MAX_IO_SLICES];
     let mut consume = 0;
+
     loop {
-        match socket_write(&mut socket, buffer)? {
-            WriteResult::Write(new_buffer, consumed) => {
-                buffer = new_buffer;
-                consume += consumed;
+        let mut len = 0;
+        for buf in bufs.by_ref().filter(|it| !it.is_empty()) {
+            if allowance == 0 {
+                break;
             }
-            WriteResult::WouldBlock => {
-                *writeable = false;
+
+            // Only the last buffer is cut short, as the allowance is used up after it
+            let buf = &buf[..buf.len().min(allowance)];
+            allowance -= buf.len();
+
+            slices[len] = IoSlice::new(buf);
+            len += 1;
+
+            if len == MAX_IO_SLICES {
                 break;
             }
-            WriteResult::Empty => break,
+        }
+
+        if len == 0 {
+            break;
+        }
+
+        let mut remaining = &mut slices[..len];
+        while !remaining.is_empty() {
+            match socket_write(&mut socket, remaining)? {
+                WriteResult::Write(consumed) => {
+                    IoSlice::advance_slices(&mut remaining, consumed);
+                    consume += consumed;
+                }
+                WriteResult::WouldBlock => {
+                    *writeable = false;
+                    return Ok(consume);
+                }
+            }
         }
     }
+
     Ok(consume)
 }
 
-enum WriteResult<'a> {
-    Write(&'a [u8], usize),
+enum WriteResult {
+    Write(usize),
     WouldBlock,
-    Empty,
 }
 
-fn socket_write<S: Write>(mut socket: S, buffer: &[u8]) -> Result<WriteResult, CommunicationError> {
-    if buffer.is_empty() {
-        return Ok(WriteResult::Empty);
-    }
-
+fn socket_write<S: Write>(
+    mut socket: S,
+    buffers: &[IoSlice],
+) -> Result<WriteResult, CommunicationError> {
     // Write to the stream once
     loop {
-        match socket.write(buffer) {
+        match socket.write_vectored(buffers) {
             Ok(0) => return Err(CommunicationError::Closed),
-            Ok(amt) => break Ok(WriteResult::Write(&buffer[amt..], amt)),
+            Ok(amt) => break Ok(WriteResult::Write(amt)),
             Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
                 return Ok(WriteResult::WouldBlock)
             }
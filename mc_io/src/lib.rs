@@ -1,22 +1,44 @@
 #![feature(split_array)]
 
 use crate::buf::Buffer;
+use crate::capture::Recorder;
+use crate::encryption::{Decryptor, Encryptor};
 use crate::error::CommunicationError;
 use crate::io::{read, write};
-use libdeflater::{CompressionLvl, Compressor, Decompressor};
+use crate::observer::Observer;
+use crate::rate_limit::RateLimit;
+use crate::state::{Side, StateMachine};
+use packet::compression::{Compress, CompressionStats, Compressor, Decompress, Decompressor};
 use packet::handle;
-use proto::Packet;
+use proto::legacy::{LegacyPingRequest, LEGACY_PING};
+use proto::{Data, DecodingError, Packet};
 use std::fmt::Debug;
 use std::io::{Read, Write};
+use std::iter;
+use std::mem;
 use std::ops::Deref;
+use std::sync::Arc;
 
 // Re-exports
+pub use packet::compression::CompressionLevel;
 pub use packet::handle::PacketHandler;
 
+pub mod address;
+#[cfg(feature = "auth")]
+pub mod auth;
 pub mod buf;
+pub mod capture;
+pub mod encryption;
 pub mod error;
 pub mod io;
+pub mod observer;
 pub mod packet;
+pub mod rate_limit;
+pub mod server;
+pub mod state;
+#[cfg(feature = "status")]
+pub mod status;
+pub mod uuid;
 
 #[derive(Debug)]
 pub struct FramedPacket<'a>(pub &'a [u8]);
@@ -31,11 +53,78 @@ impl<'a> From<RawPacket<'a>> for &'a [u8] {
 
 const MAXIMUM_PACKET_SIZE: usize = 2097148;
 
+/// Bounds how much a per connection buffer may hold and how much memory it keeps once drained
+#[derive(Clone, Debug)]
+pub struct BufferLimits {
+    /// Bytes a buffer may hold before `backpressure` is applied
+    pub high_water_mark: usize,
+    /// Capacity kept by a buffer after it drains, anything above this is released
+    pub retained_capacity: usize,
+    pub backpressure: Backpressure,
+}
+
+impl BufferLimits {
+    pub const fn unbounded() -> Self {
+        Self {
+            high_water_mark: usize::MAX,
+            retained_capacity: usize::MAX,
+            backpressure: Backpressure::Error,
+        }
+    }
+
+    // Returns the amount buffered if the connection should be failed
+    pub(crate) fn check(&self, buffered: usize) -> Result<(), usize> {
+        if buffered <= self.high_water_mark {
+            return Ok(());
+        }
+
+        match self.backpressure {
+            Backpressure::Error => Err(buffered),
+            Backpressure::Callback(ref callback) => {
+                (callback)(buffered);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        Self::unbounded()
+    }
+}
+
+/// What happens when a buffer grows past its high water mark
+#[derive(Clone)]
+pub enum Backpressure {
+    /// Fail the read or write with a `Backpressure` error
+    Error,
+    /// Keep buffering, calling the callback with the amount buffered
+    Callback(Arc<dyn Fn(usize) + Send + Sync>),
+}
+
+impl Debug for Backpressure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backpressure::Error => f.write_str("Error"),
+            Backpressure::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ConnectionReadContext<D> {
     pub socket: D,
     // TODO would smallvec or similar be better?
     pub unread_buf: Buffer,
+    pub limits: BufferLimits,
+    /// Decrypts everything read once set, see `PacketHandler::take_decryptor` for enabling it
+    /// part way through a read
+    pub cipher: Option<Decryptor>,
+    /// Records every packet read with `read_packets` once set
+    pub recorder: Option<Recorder>,
+    /// Observes every packet read with `read_packets` once set
+    pub observer: Option<Observer>,
 }
 
 impl<D, S> ConnectionReadContext<D>
@@ -47,6 +136,10 @@ where
         Self {
             socket,
             unread_buf: Buffer::new(),
+            limits: BufferLimits::default(),
+            cipher: None,
+            recorder: None,
+            observer: None,
         }
     }
 
@@ -56,8 +149,10 @@ where
         handler: &mut H,
         ctx: &mut C,
     ) -> Result<(), CommunicationError> {
-        let handler = handle::create_handler(handler, ctx);
-        self.read(read_ctx, handler)
+        let recorder = self.recorder.clone();
+        let observer = self.observer.clone();
+        let handler = handle::create_handler(handler, ctx, recorder.as_ref(), observer.as_ref());
+        read::read(read_ctx, self, handler)
     }
 
     pub fn read<F>(
@@ -68,14 +163,53 @@ where
     where
         F: FnMut(&FramedPacket, CompressionReadContext) -> Result<(), CommunicationError>,
     {
-        read::read(ctx, self, handler)
+        let mut handler = handler;
+        read::read(ctx, self, |packet, _, read_ctx| {
+            (handler)(packet, read_ctx).map(|()| None)
+        })
     }
+
+    /// Tells a legacy ping apart from a handshake by the first bytes a client sent, for server
+    /// side connections that haven't read anything else yet. What was read stays buffered for
+    /// `read_packets`.
+    pub fn detect_legacy_ping(&mut self) -> Result<Opening, CommunicationError> {
+        read::read_available(self)?;
+
+        let mut data = self.unread_buf.get_written();
+        if data.is_empty() {
+            return Ok(Opening::Incomplete);
+        }
+        if data[0] != LEGACY_PING {
+            return Ok(Opening::Handshake);
+        }
+
+        match LegacyPingRequest::try_decode(&mut data) {
+            Ok(request) => Ok(Opening::LegacyPing(request)),
+            Err(DecodingError::EOF) => Ok(Opening::Incomplete),
+            // A handshake 254 bytes long starts with 0xFE 0x01 too, but isn't followed by
+            // `MC|PingHost`
+            Err(_) => Ok(Opening::Handshake),
+        }
+    }
+}
+
+/// How a client opened its connection, see `ConnectionReadContext::detect_legacy_ping`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Opening {
+    Handshake,
+    LegacyPing(LegacyPingRequest),
+    /// Not enough was read to tell yet
+    Incomplete,
 }
 
 impl<D> Debug for ConnectionReadContext<D> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ConnectionReadContext")
             .field("unread_buf", &self.unread_buf)
+            .field("limits", &self.limits)
+            .field("encrypted", &self.cipher.is_some())
+            .field("recorder", &self.recorder)
+            .field("observer", &self.observer)
             .finish_non_exhaustive()
     }
 }
@@ -86,6 +220,16 @@ pub struct ConnectionWriteContext<D> {
     // TODO would smallvec or similar be better?
     pub unwritten_buf: Buffer,
     pub writeable: bool,
+    pub limits: BufferLimits,
+    /// Encrypts everything written once set
+    pub cipher: Option<Encryptor>,
+    /// Records every packet written with `write_packet` or `write_packets` once set
+    pub recorder: Option<Recorder>,
+    /// Observes every packet written with `write_packet` or `write_packets` once set
+    pub observer: Option<Observer>,
+    /// Limits how fast this connection writes once set, what it holds back is released by
+    /// `release_limited` and `write_unwritten`
+    pub rate_limit: Option<RateLimit>,
 }
 
 impl<D, S> ConnectionWriteContext<D>
@@ -98,46 +242,72 @@ where
             socket,
             unwritten_buf: Buffer::new(),
             writeable: true,
+            limits: BufferLimits::default(),
+            cipher: None,
+            recorder: None,
+            observer: None,
+            rate_limit: None,
         }
     }
 
-    pub fn write_packets<F>(
+    /// Writes the packets written by `packets` with a single flush. The state may be changed
+    /// part way through with `PacketWriter::state`.
+    pub fn write_packets<F, T: Side>(
         &mut self,
         ctx: &mut GlobalWriteContext,
-        compression_threshold: i32,
+        state: &mut StateMachine<T>,
         packets: F,
     ) -> Result<(), CommunicationError>
     where
-        F: FnOnce(&mut PacketWriter) -> Result<(), CommunicationError>,
+        F: FnOnce(&mut PacketWriter<T>) -> Result<(), CommunicationError>,
     {
-        let (write_buf, compression_ctx) = ctx.compression();
-
         {
+            let (write_buf, compression_ctx) = ctx.compression();
             let mut writer = PacketWriter {
                 write_buf,
                 compression_ctx,
-                compression_threshold,
+                state,
+                recorder: self.recorder.as_ref(),
+                observer: self.observer.as_ref(),
             };
             (packets)(&mut writer)?;
         }
 
-        self.write_buffer(write_buf)
+        self.write_framed(ctx)
     }
 
-    pub fn write_packet<'a, P: Packet<'a>>(
+    pub fn write_packet<'a, P: Packet<'a, Dir = T::Sends>, T: Side>(
         &mut self,
         packet: &'a P,
         ctx: &mut GlobalWriteContext,
-        compression_threshold: i32,
+        state: &StateMachine<T>,
     ) -> Result<(), CommunicationError> {
-        let (write_buf, mut compression_ctx) = ctx.compression();
-        packet::helpers::write_packet(
-            packet,
-            write_buf,
-            &mut compression_ctx,
-            compression_threshold,
-        )?;
-        self.write_buffer(write_buf)
+        state.check::<P>()?;
+
+        {
+            let (write_buf, mut compression_ctx) = ctx.compression();
+            let framed = packet::helpers::write_packet(
+                packet,
+                write_buf,
+                &mut compression_ctx,
+                state.compression_threshold(),
+            )?;
+
+            if let Some(recorder) = &self.recorder {
+                recorder.record_write(P::DIRECTION, state.state(), &framed)?;
+            }
+            if let Some(observer) = &self.observer {
+                observer.observe_write(
+                    P::PACKET_ID_NUM,
+                    P::DIRECTION,
+                    state.state(),
+                    framed.wire_size,
+                    framed.size,
+                );
+            }
+        }
+
+        self.write_framed(ctx)
     }
 
     pub fn write_buffer(&mut self, to_write: &mut Buffer) -> Result<(), CommunicationError> {
@@ -145,17 +315,75 @@ where
     }
 
     pub fn write_slice(&mut self, to_write: &[u8]) -> Result<(), CommunicationError> {
-        write::write_slice(
+        self.write_vectored(iter::once(to_write))
+    }
+
+    /// Flushes any unwritten bytes together with `to_write`, in order, with a single vectored
+    /// write where possible
+    pub fn write_vectored<'a, I>(&mut self, to_write: I) -> Result<(), CommunicationError>
+    where
+        I: Iterator<Item = &'a [u8]> + Clone,
+    {
+        if let Some(rate_limit) = &mut self.rate_limit {
+            rate_limit.queue(to_write.clone().map(<[u8]>::len).sum(), iter::empty());
+        }
+
+        if let Some(cipher) = &mut self.cipher {
+            // `to_write` can't be encrypted in place, so it is queued up behind the unwritten
+            // bytes first
+            let start = self.unwritten_buf.len();
+            for buf in to_write {
+                self.unwritten_buf.copy_from(buf);
+            }
+            cipher.encrypt(&mut self.unwritten_buf.get_written_mut()[start..]);
+
+            return self.write_raw(iter::empty());
+        }
+
+        self.write_raw(to_write)
+    }
+
+    // Sends the packets framed in `ctx`, encrypting them in place first
+    fn write_framed(&mut self, ctx: &mut GlobalWriteContext) -> Result<(), CommunicationError> {
+        if let Some(cipher) = &mut self.cipher {
+            ctx.encrypt(cipher);
+        }
+        if let Some(rate_limit) = &mut self.rate_limit {
+            let len = ctx.written().map(<[u8]>::len).sum();
+            rate_limit.queue(len, ctx.packet_sizes.iter().copied());
+        }
+
+        self.write_raw(ctx.written())
+    }
+
+    fn write_raw<'a, I>(&mut self, to_write: I) -> Result<(), CommunicationError>
+    where
+        I: Iterator<Item = &'a [u8]> + Clone,
+    {
+        write::write_vectored(
             D::deref(&self.socket),
             to_write,
             &mut self.unwritten_buf,
             &mut self.writeable,
+            &self.limits,
+            self.rate_limit.as_mut(),
         )
     }
 
     pub fn write_unwritten(&mut self) -> Result<(), CommunicationError> {
         write::write_unwritten(self)
     }
+
+    /// Writes what the rate limit held back and allows by now, for connections that have a
+    /// rate limit and aren't waiting for their socket to become writable. Has to be called
+    /// periodically, as nothing else signals that the limit allows more.
+    pub fn release_limited(&mut self) -> Result<(), CommunicationError> {
+        if self.rate_limit.is_none() || !self.writeable || self.unwritten_buf.is_empty() {
+            return Ok(());
+        }
+
+        write::write_unwritten(self)
+    }
 }
 
 impl<D> Debug for ConnectionWriteContext<D> {
@@ -163,6 +391,11 @@ impl<D> Debug for ConnectionWriteContext<D> {
         f.debug_struct("ConnectionWriteContext")
             .field("unwritten_buf", &self.unwritten_buf)
             .field("writeable", &self.writeable)
+            .field("limits", &self.limits)
+            .field("encrypted", &self.cipher.is_some())
+            .field("recorder", &self.recorder)
+            .field("observer", &self.observer)
+            .field("rate_limit", &self.rate_limit)
             .finish_non_exhaustive()
     }
 }
@@ -172,6 +405,8 @@ pub struct GlobalReadContext {
     pub compression_buf: Buffer,
 
     pub decompressor: Decompressor,
+    /// Of every packet read with this context
+    pub compression_stats: CompressionStats,
 }
 
 impl GlobalReadContext {
@@ -180,6 +415,7 @@ impl GlobalReadContext {
             read_buf: Buffer::new(),
             compression_buf: Buffer::new(),
             decompressor: Decompressor::new(),
+            compression_stats: CompressionStats::default(),
         }
     }
 
@@ -190,6 +426,7 @@ impl GlobalReadContext {
             CompressionReadContext {
                 compression_buf: &mut self.compression_buf,
                 decompressor: &mut self.decompressor,
+                stats: &mut self.compression_stats,
             },
         )
     }
@@ -211,48 +448,111 @@ impl Debug for GlobalReadContext {
         f.debug_struct("GlobalReadContext")
             .field("write_buf", &self.read_buf)
             .field("compression_buf", &self.compression_buf)
+            .field("compression_stats", &self.compression_stats)
             .finish_non_exhaustive()
     }
 }
 
+/// Records which buffer each run of framed packets was written to, so they can be sent in order
+/// without first being copied into a single buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Segment {
+    WriteBuf(usize),
+    CompressionBuf(usize),
+}
+
 pub struct GlobalWriteContext {
     pub write_buf: Buffer,
     pub compression_buf: Buffer,
+    pub segments: Vec<Segment>,
+    /// The wire size of every packet framed since the last reset, in order
+    pub packet_sizes: Vec<usize>,
 
     pub compressor: Option<Compressor>,
+    /// The level the compressor is created with, changing it later has no effect
+    pub compression_level: CompressionLevel,
+    /// Of every packet written with this context
+    pub compression_stats: CompressionStats,
 }
 
 impl GlobalWriteContext {
     pub fn new() -> Self {
+        Self::with_compression_level(CompressionLevel::default())
+    }
+
+    pub fn with_compression_level(compression_level: CompressionLevel) -> Self {
         Self {
             write_buf: Buffer::new(),
             compression_buf: Buffer::new(),
+            segments: Vec::new(),
+            packet_sizes: Vec::new(),
             compressor: None,
+            compression_level,
+            compression_stats: CompressionStats::default(),
         }
     }
 
     pub fn compression(&mut self) -> (&mut Buffer, CompressionWriteContext) {
         self.reset();
 
-        let compressor = if let Some(ref mut compressor) = self.compressor {
-            compressor
-        } else {
-            self.compressor = Some(Compressor::new(CompressionLvl::fastest()));
-            self.compressor.as_mut().unwrap()
-        };
+        let level = self.compression_level;
+        let compressor = self
+            .compressor
+            .get_or_insert_with(|| Compressor::new(level));
 
         (
             &mut self.write_buf,
             CompressionWriteContext {
                 compression_buf: &mut self.compression_buf,
+                segments: &mut self.segments,
+                packet_sizes: &mut self.packet_sizes,
                 compressor,
+                stats: &mut self.compression_stats,
             },
         )
     }
 
+    /// The framed packets written since the last reset, in the order they were written
+    pub fn written(&self) -> impl Iterator<Item = &[u8]> + Clone {
+        let mut write_buf = self.write_buf.get_written();
+        let mut compression_buf = self.compression_buf.get_written();
+
+        self.segments.iter().map(move |segment| {
+            let (buf, len) = match *segment {
+                Segment::WriteBuf(len) => (&mut write_buf, len),
+                Segment::CompressionBuf(len) => (&mut compression_buf, len),
+            };
+
+            let (written, remaining) = buf.split_at(len);
+            *buf = remaining;
+
+            written
+        })
+    }
+
+    // Encrypts the framed packets in the order they will be sent
+    fn encrypt(&mut self, cipher: &mut Encryptor) {
+        let mut write_buf = self.write_buf.get_written_mut();
+        let mut compression_buf = self.compression_buf.get_written_mut();
+
+        for segment in &self.segments {
+            let (buf, len) = match *segment {
+                Segment::WriteBuf(len) => (&mut write_buf, len),
+                Segment::CompressionBuf(len) => (&mut compression_buf, len),
+            };
+
+            let (written, remaining) = mem::take(buf).split_at_mut(len);
+            *buf = remaining;
+
+            cipher.encrypt(written);
+        }
+    }
+
     pub fn reset(&mut self) {
         self.write_buf.reset();
         self.compression_buf.reset();
+        self.segments.clear();
+        self.packet_sizes.clear();
     }
 }
 
@@ -261,6 +561,10 @@ impl Debug for GlobalWriteContext {
         f.debug_struct("GlobalWriteContext")
             .field("write_buf", &self.write_buf)
             .field("compression_buf", &self.compression_buf)
+            .field("segments", &self.segments)
+            .field("packet_sizes", &self.packet_sizes)
+            .field("compression_level", &self.compression_level)
+            .field("compression_stats", &self.compression_stats)
             .finish_non_exhaustive()
     }
 }
@@ -275,47 +579,77 @@ pub struct CompressionReadContext<'a, 'b> {
     pub compression_buf: &'a mut Buffer,
 
     pub decompressor: &'b mut Decompressor,
+    pub stats: &'b mut CompressionStats,
 }
 
 impl Debug for CompressionReadContext<'_, '_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CompressionReadContext")
             .field("compression_buf", &self.compression_buf)
+            .field("stats", &self.stats)
             .finish_non_exhaustive()
     }
 }
 
 pub struct CompressionWriteContext<'a, 'b> {
     pub compression_buf: &'a mut Buffer,
+    pub segments: &'a mut Vec<Segment>,
+    pub packet_sizes: &'a mut Vec<usize>,
 
     pub compressor: &'b mut Compressor,
+    pub stats: &'b mut CompressionStats,
 }
 
 impl Debug for CompressionWriteContext<'_, '_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CompressionWriteContext")
             .field("compression_buf", &self.compression_buf)
+            .field("segments", &self.segments)
+            .field("packet_sizes", &self.packet_sizes)
+            .field("stats", &self.stats)
             .finish_non_exhaustive()
     }
 }
 
-pub struct PacketWriter<'a, 'b, 'c> {
+pub struct PacketWriter<'a, 'b, 'c, S: Side> {
     write_buf: &'a mut Buffer,
     compression_ctx: CompressionWriteContext<'b, 'c>,
-    compression_threshold: i32,
+    state: &'a mut StateMachine<S>,
+    recorder: Option<&'a Recorder>,
+    observer: Option<&'a Observer>,
 }
-impl PacketWriter<'_, '_, '_> {
-    pub fn write_packet<'a, P: Packet<'a>>(
+impl<S: Side> PacketWriter<'_, '_, '_, S> {
+    pub fn write_packet<'a, P: Packet<'a, Dir = S::Sends>>(
         &mut self,
         packet: &'a P,
     ) -> Result<(), CommunicationError> {
-        packet::helpers::write_packet(
+        self.state.check::<P>()?;
+
+        let framed = packet::helpers::write_packet(
             packet,
             self.write_buf,
             &mut self.compression_ctx,
-            self.compression_threshold,
+            self.state.compression_threshold(),
         )?;
 
+        if let Some(recorder) = self.recorder {
+            recorder.record_write(P::DIRECTION, self.state.state(), &framed)?;
+        }
+        if let Some(observer) = self.observer {
+            observer.observe_write(
+                P::PACKET_ID_NUM,
+                P::DIRECTION,
+                self.state.state(),
+                framed.wire_size,
+                framed.size,
+            );
+        }
+
         Ok(())
     }
+
+    /// Packets written after a change to the state use the new state
+    pub fn state(&mut self) -> &mut StateMachine<S> {
+        self.state
+    }
 }
@@ -0,0 +1,90 @@
+//! Hooks for watching every packet a connection reads or writes, eg. to keep statistics
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use proto::direction::PacketDirection;
+use proto::primitive::VarInt;
+use proto::{Data, Direction};
+
+use crate::state::{ProtocolState, Side};
+use crate::RawPacket;
+
+/// A packet that was read or written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketEvent {
+    pub id: u64,
+    pub state: ProtocolState,
+    pub direction: Direction,
+    /// Bytes sent over the connection, including the length prefix and any compression
+    pub wire_size: usize,
+    /// Bytes of the packet id and body once decompressed
+    pub size: usize,
+}
+
+/// Told about every packet read with `read_packets` or written with `write_packet` and
+/// `write_packets`. It is called on the thread doing the io, so it should be cheap.
+pub trait PacketObserver: Send + Sync {
+    fn observe(&self, packet: &PacketEvent);
+}
+
+/// A `PacketObserver` along with which side of the connection it watches. Set as the `observer`
+/// of a `ConnectionReadContext` and `ConnectionWriteContext` to observe everything they handle.
+#[derive(Clone)]
+pub struct Observer {
+    inner: Arc<dyn PacketObserver>,
+    // The direction of packets read by the side this handle belongs to
+    receives: Direction,
+}
+
+impl Observer {
+    /// Observes a connection seen from side `S`
+    pub fn new<S: Side>(observer: Arc<dyn PacketObserver>) -> Self {
+        Self {
+            inner: observer,
+            receives: S::Sends::DIRECTION.opposite(),
+        }
+    }
+
+    /// Observes a packet that took `wire_size` bytes and was decompressed into `packet`. Packets
+    /// without a valid id are left to fail in the handler.
+    pub(crate) fn observe_read(&self, wire_size: usize, packet: &RawPacket, state: ProtocolState) {
+        let mut data = packet.0;
+        let Ok(id) = VarInt::try_decode(&mut data) else {
+            return;
+        };
+
+        self.inner.observe(&PacketEvent {
+            id: id.0,
+            state,
+            direction: self.receives,
+            wire_size,
+            size: packet.0.len(),
+        });
+    }
+
+    pub(crate) fn observe_write(
+        &self,
+        id: u8,
+        direction: Direction,
+        state: ProtocolState,
+        wire_size: usize,
+        size: usize,
+    ) {
+        self.inner.observe(&PacketEvent {
+            id: id as u64,
+            state,
+            direction,
+            wire_size,
+            size,
+        });
+    }
+}
+
+impl Debug for Observer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Observer")
+            .field("receives", &self.receives)
+            .finish_non_exhaustive()
+    }
+}
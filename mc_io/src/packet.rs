@@ -1,4 +1,4 @@
-mod compression;
+pub mod compression;
 pub mod handle;
 pub mod helpers;
 mod lazy_varint;
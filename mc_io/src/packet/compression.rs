@@ -1,41 +1,181 @@
-use libdeflater::{Compressor, Decompressor};
+//! Packet compression. The zlib implementation is picked with cargo features, the first enabled
+//! of `libdeflater` (the default), `zlib-rs` and `miniz_oxide` is used by the contexts.
+
+use std::time::{Duration, Instant};
+
 use proto::primitive::V21;
 use proto::Data;
 
 use crate::packet::lazy_varint::LazyVarint;
 use crate::{
     buf::Buffer,
-    error::{ReadError, WriteError},
+    error::{CompressionError, DecompressionError, ReadError, WriteError},
     MAXIMUM_PACKET_SIZE,
 };
 
-pub fn compress<'a>(
+#[cfg(feature = "libdeflater")]
+pub mod libdeflate;
+#[cfg(feature = "miniz_oxide")]
+pub mod miniz;
+#[cfg(feature = "zlib-rs")]
+pub mod zlib_rs;
+
+#[cfg(feature = "libdeflater")]
+pub type Compressor = libdeflate::LibdeflateCompressor;
+#[cfg(feature = "libdeflater")]
+pub type Decompressor = libdeflate::LibdeflateDecompressor;
+
+#[cfg(all(not(feature = "libdeflater"), feature = "zlib-rs"))]
+pub type Compressor = zlib_rs::ZlibRsCompressor;
+#[cfg(all(not(feature = "libdeflater"), feature = "zlib-rs"))]
+pub type Decompressor = zlib_rs::ZlibRsDecompressor;
+
+#[cfg(all(
+    not(feature = "libdeflater"),
+    not(feature = "zlib-rs"),
+    feature = "miniz_oxide"
+))]
+pub type Compressor = miniz::MinizCompressor;
+#[cfg(all(
+    not(feature = "libdeflater"),
+    not(feature = "zlib-rs"),
+    feature = "miniz_oxide"
+))]
+pub type Decompressor = miniz::MinizDecompressor;
+
+#[cfg(not(any(feature = "libdeflater", feature = "zlib-rs", feature = "miniz_oxide")))]
+compile_error!("mc_io needs one of the `libdeflater`, `zlib-rs` or `miniz_oxide` features");
+
+/// How hard packets are compressed, from 0 (stored without compressing) to 12
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CompressionLevel(u8);
+
+impl CompressionLevel {
+    pub const NONE: Self = Self(0);
+    /// The fastest level that still compresses
+    pub const FASTEST: Self = Self(1);
+    pub const BEST: Self = Self(12);
+
+    pub const fn new(level: u8) -> Option<Self> {
+        if level <= Self::BEST.0 {
+            Some(Self(level))
+        } else {
+            None
+        }
+    }
+
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// The level for zlib implementations, which stop at 9
+    pub fn zlib(self) -> u8 {
+        self.0.min(9)
+    }
+}
+
+impl Default for CompressionLevel {
+    fn default() -> Self {
+        Self::FASTEST
+    }
+}
+
+/// A zlib compressor, reused for every packet compressed with a `GlobalWriteContext`
+pub trait Compress {
+    fn new(level: CompressionLevel) -> Self;
+    /// The most bytes `len` bytes can compress to
+    fn compress_bound(&mut self, len: usize) -> usize;
+    /// Compresses `src` into `dst` as a complete zlib stream, returning its length
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Result<usize, CompressionError>;
+}
+
+/// A zlib decompressor, reused for every packet decompressed with a `GlobalReadContext`
+pub trait Decompress {
+    fn new() -> Self;
+    /// Decompresses the zlib stream `src` into `dst`, returning the decompressed length
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> Result<usize, DecompressionError>;
+}
+
+/// How well compression works on a connection, and what it costs. Kept by the global read and
+/// write contexts for every connection they handle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Packets that went through the compressor or decompressor
+    pub compressed_packets: u64,
+    /// Bytes of those packets before compression or after decompression
+    pub uncompressed_bytes: u64,
+    /// Bytes of those packets when compressed
+    pub compressed_bytes: u64,
+    /// Time spent compressing or decompressing
+    pub time: Duration,
+    /// Packets sent uncompressed as they were below the compression threshold
+    pub skipped_packets: u64,
+    pub skipped_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Compressed bytes per uncompressed byte, `None` before anything was compressed
+    pub fn ratio(&self) -> Option<f64> {
+        (self.uncompressed_bytes != 0)
+            .then(|| self.compressed_bytes as f64 / self.uncompressed_bytes as f64)
+    }
+
+    pub(crate) fn compressed(&mut self, uncompressed: usize, compressed: usize, start: Instant) {
+        self.compressed_packets += 1;
+        self.uncompressed_bytes += uncompressed as u64;
+        self.compressed_bytes += compressed as u64;
+        self.time += start.elapsed();
+    }
+
+    pub(crate) fn skipped(&mut self, len: usize) {
+        self.skipped_packets += 1;
+        self.skipped_bytes += len as u64;
+    }
+}
+
+impl std::ops::AddAssign for CompressionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.compressed_packets += other.compressed_packets;
+        self.uncompressed_bytes += other.uncompressed_bytes;
+        self.compressed_bytes += other.compressed_bytes;
+        self.time += other.time;
+        self.skipped_packets += other.skipped_packets;
+        self.skipped_bytes += other.skipped_bytes;
+    }
+}
+
+pub fn compress<'a, C: Compress>(
     src: &[u8],
     dst: &'a mut Buffer,
-    compressor: &mut Compressor,
+    compressor: &mut C,
+    stats: &mut CompressionStats,
 ) -> Result<&'a [u8], WriteError> {
-    let max_compressed_size = compressor.zlib_compress_bound(src.len());
+    let start = Instant::now();
+    let max_compressed_size = compressor.compress_bound(src.len());
 
     let mut buffer = dst.get_unwritten(3 + 3 + max_compressed_size);
 
     let total_len = LazyVarint::<3>::new(&mut buffer);
     let data_len = LazyVarint::<3>::new(&mut buffer);
 
-    let compressed = compressor.zlib_compress(src, buffer)?;
+    let compressed = compressor.compress(src, buffer)?;
 
     data_len.write(src.len() as i32);
     total_len.write(3 + compressed as i32);
+    stats.compressed(src.len(), compressed, start);
 
-    // SAFETY: We initialized 2 byte length headers and `compressed` bytes of data
-    Ok(unsafe { dst.advance_write(3 + 3 + compressed) })
+    // The 2 length headers and `compressed` bytes of data
+    Ok(dst.advance_write(3 + 3 + compressed))
 }
 
-pub fn decompress<'a>(
+pub fn decompress<'a, D: Decompress>(
     mut src: &'a [u8],
     dst: &'a mut Buffer,
-    decompressor: &mut Decompressor,
+    decompressor: &mut D,
     compression_threshold: i32,
+    stats: &mut CompressionStats,
 ) -> Result<&'a [u8], ReadError> {
+    let start = Instant::now();
     let data_len = V21::try_decode(&mut src)?.into();
 
     if data_len > MAXIMUM_PACKET_SIZE {
@@ -44,15 +184,16 @@ pub fn decompress<'a>(
 
     if data_len >= compression_threshold as usize {
         let buffer = dst.get_unwritten(data_len);
-        let decompressed = decompressor.zlib_decompress(src, buffer)?;
+        let decompressed = decompressor.decompress(src, buffer)?;
 
         if decompressed == data_len {
-            // SAFETY: The decompressor wrote `decompressed` bytes
-            Ok(unsafe { dst.advance_write(decompressed) })
+            stats.compressed(data_len, src.len(), start);
+            Ok(dst.advance_write(decompressed))
         } else {
             Err(ReadError::BadlyCompressed)
         }
     } else if data_len == 0 {
+        stats.skipped(src.len());
         Ok(src)
     } else {
         Err(ReadError::BadlyCompressed)
@@ -63,37 +204,159 @@ pub fn decompress<'a>(
 mod tests {
     use super::*;
     use crate::Buffer;
-    use libdeflater::{CompressionLvl, Compressor, Decompressor};
     use proto::primitive::VarInt;
 
     #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
     fn compression_roundtrip() {
-        let mut compressor = Compressor::new(CompressionLvl::best());
+        for level in [CompressionLevel::NONE, CompressionLevel::BEST] {
+            let mut compressor = Compressor::new(level);
+            let mut decompressor = Decompressor::new();
+
+            do_compression_roundtrip::<10, _, _>(&mut compressor, &mut decompressor);
+            do_compression_roundtrip::<100, _, _>(&mut compressor, &mut decompressor);
+            do_compression_roundtrip::<1000, _, _>(&mut compressor, &mut decompressor);
+            do_compression_roundtrip::<10000, _, _>(&mut compressor, &mut decompressor);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
+    fn default_level_compresses() {
+        assert_eq!(
+            crate::GlobalWriteContext::new().compression_level,
+            CompressionLevel::FASTEST
+        );
+
+        let mut compressor = Compressor::new(CompressionLevel::default());
+        let mut decompressor = Decompressor::new();
+
+        let original = b"A chat message that repeats itself. ".repeat(30);
+        let mut compression_buffer = Buffer::new();
+        let mut stats = CompressionStats::default();
+        let mut compressed = compress(
+            &original,
+            &mut compression_buffer,
+            &mut compressor,
+            &mut stats,
+        )
+        .unwrap();
+        VarInt::try_decode(&mut compressed).unwrap();
+        assert!(compressed.len() < original.len() / 4);
+
+        let mut decompression_buffer = Buffer::new();
+        let decompressed = decompress(
+            compressed,
+            &mut decompression_buffer,
+            &mut decompressor,
+            1,
+            &mut stats,
+        )
+        .unwrap();
+        assert_eq!(decompressed, &original[..]);
+    }
+
+    // Every backend reads what the others write
+    #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
+    fn backends_interoperate() {
+        fn roundtrip<C: Compress, D: Decompress>() {
+            let mut compressor = C::new(CompressionLevel::new(6).unwrap());
+            let mut decompressor = D::new();
+
+            do_compression_roundtrip::<1000, _, _>(&mut compressor, &mut decompressor);
+        }
+
+        fn with_every_decompressor<C: Compress>() {
+            #[cfg(feature = "libdeflater")]
+            roundtrip::<C, libdeflate::LibdeflateDecompressor>();
+            #[cfg(feature = "zlib-rs")]
+            roundtrip::<C, zlib_rs::ZlibRsDecompressor>();
+            #[cfg(feature = "miniz_oxide")]
+            roundtrip::<C, miniz::MinizDecompressor>();
+        }
+
+        #[cfg(feature = "libdeflater")]
+        with_every_decompressor::<libdeflate::LibdeflateCompressor>();
+        #[cfg(feature = "zlib-rs")]
+        with_every_decompressor::<zlib_rs::ZlibRsCompressor>();
+        #[cfg(feature = "miniz_oxide")]
+        with_every_decompressor::<miniz::MinizCompressor>();
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, feature = "libdeflater"), ignore)] // libdeflate is ffi
+    fn corrupt_data() {
+        let mut compressor = Compressor::new(CompressionLevel::BEST);
         let mut decompressor = Decompressor::new();
 
-        do_compression_roundtrip::<10>(&mut compressor, &mut decompressor);
-        do_compression_roundtrip::<100>(&mut compressor, &mut decompressor);
-        do_compression_roundtrip::<1000>(&mut compressor, &mut decompressor);
-        do_compression_roundtrip::<10000>(&mut compressor, &mut decompressor);
+        let original = [7; 500];
+        let mut compression_buffer = Buffer::new();
+        let mut stats = CompressionStats::default();
+        let mut compressed = compress(
+            &original,
+            &mut compression_buffer,
+            &mut compressor,
+            &mut stats,
+        )
+        .unwrap();
+        VarInt::try_decode(&mut compressed).unwrap();
+
+        let mut corrupt = compressed.to_vec();
+        let end = corrupt.len() - 1;
+        corrupt[end] ^= 0xff;
+
+        let mut decompression_buffer = Buffer::new();
+        let res = decompress(
+            &corrupt,
+            &mut decompression_buffer,
+            &mut decompressor,
+            1,
+            &mut stats,
+        );
+        assert!(res.is_err());
     }
 
-    fn do_compression_roundtrip<const DATA_SIZE: usize>(
-        compressor: &mut Compressor,
-        decompressor: &mut Decompressor,
+    fn do_compression_roundtrip<const DATA_SIZE: usize, C: Compress, D: Decompress>(
+        compressor: &mut C,
+        decompressor: &mut D,
     ) {
         let original: [u8; DATA_SIZE] = rand::random();
 
         let mut compression_buffer = Buffer::with_capacity(6 + DATA_SIZE);
-        let mut compressed = compress(&original, &mut compression_buffer, compressor).unwrap();
+        let mut write_stats = CompressionStats::default();
+        let mut compressed = compress(
+            &original,
+            &mut compression_buffer,
+            compressor,
+            &mut write_stats,
+        )
+        .unwrap();
         let compressed_len = compressed.len();
 
-        let total_size = VarInt::try_decode(&mut compressed).unwrap().into();
+        let total_size: usize = VarInt::try_decode(&mut compressed).unwrap().into();
         assert_eq!(compressed.len(), total_size);
 
         let mut decompression_buffer = Buffer::with_capacity(6 + DATA_SIZE);
-        let decompressed =
-            decompress(compressed, &mut decompression_buffer, decompressor, 1).unwrap();
+        let mut read_stats = CompressionStats::default();
+        let decompressed = decompress(
+            compressed,
+            &mut decompression_buffer,
+            decompressor,
+            1,
+            &mut read_stats,
+        )
+        .unwrap();
         assert_eq!(original, decompressed);
         assert_eq!(compression_buffer.len(), compressed_len);
+
+        assert_eq!(write_stats.compressed_packets, 1);
+        assert_eq!(write_stats.uncompressed_bytes, DATA_SIZE as u64);
+        assert_eq!(write_stats.compressed_bytes, compressed_len as u64 - 6);
+        assert_eq!(
+            read_stats.uncompressed_bytes,
+            write_stats.uncompressed_bytes
+        );
+        assert_eq!(read_stats.compressed_bytes, write_stats.compressed_bytes);
     }
 }
@@ -0,0 +1,43 @@
+//! libdeflate, which is the fastest backend but links C code and needs whole buffers
+
+use libdeflater::{CompressionLvl, Compressor, Decompressor};
+
+use super::{Compress, CompressionLevel, Decompress};
+use crate::error::{CompressionError, DecompressionError};
+
+pub struct LibdeflateCompressor(Compressor);
+
+impl Compress for LibdeflateCompressor {
+    fn new(level: CompressionLevel) -> Self {
+        // libdeflate has the same 0 to 12 levels
+        let level = CompressionLvl::new(level.get() as i32).expect("Valid level");
+        Self(Compressor::new(level))
+    }
+
+    fn compress_bound(&mut self, len: usize) -> usize {
+        self.0.zlib_compress_bound(len)
+    }
+
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Result<usize, CompressionError> {
+        self.0
+            .zlib_compress(src, dst)
+            .map_err(|_| CompressionError::InsufficientSpace)
+    }
+}
+
+pub struct LibdeflateDecompressor(Decompressor);
+
+impl Decompress for LibdeflateDecompressor {
+    fn new() -> Self {
+        Self(Decompressor::new())
+    }
+
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> Result<usize, DecompressionError> {
+        self.0.zlib_decompress(src, dst).map_err(|err| match err {
+            libdeflater::DecompressionError::BadData => DecompressionError::BadData,
+            libdeflater::DecompressionError::InsufficientSpace => {
+                DecompressionError::InsufficientSpace
+            }
+        })
+    }
+}
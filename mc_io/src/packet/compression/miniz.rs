@@ -0,0 +1,59 @@
+//! miniz_oxide, which is pure rust so it builds anywhere, eg. for static musl binaries
+
+use miniz_oxide::deflate::core::{
+    compress, create_comp_flags_from_zip_params, CompressorOxide, TDEFLFlush, TDEFLStatus,
+};
+use miniz_oxide::inflate::core::inflate_flags::{
+    TINFL_FLAG_PARSE_ZLIB_HEADER, TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+};
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+
+use super::{Compress, CompressionLevel, Decompress};
+use crate::error::{CompressionError, DecompressionError};
+
+const WINDOW_BITS: i32 = 15;
+
+// Both are tens of kilobytes, which is too much to move around on the stack
+pub struct MinizCompressor(Box<CompressorOxide>);
+
+impl Compress for MinizCompressor {
+    fn new(level: CompressionLevel) -> Self {
+        let flags = create_comp_flags_from_zip_params(level.zlib() as i32, WINDOW_BITS, 0);
+        Self(Box::new(CompressorOxide::new(flags)))
+    }
+
+    fn compress_bound(&mut self, len: usize) -> usize {
+        // The bound zlib's `compressBound` gives
+        len + (len >> 12) + (len >> 14) + (len >> 25) + 13
+    }
+
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Result<usize, CompressionError> {
+        self.0.reset();
+
+        match compress(&mut self.0, src, dst, TDEFLFlush::Finish) {
+            (TDEFLStatus::Done, _, written) => Ok(written),
+            (TDEFLStatus::Okay, ..) => Err(CompressionError::InsufficientSpace),
+            (status, ..) => Err(CompressionError::Failed(format!("{status:?}"))),
+        }
+    }
+}
+
+pub struct MinizDecompressor(Box<DecompressorOxide>);
+
+impl Decompress for MinizDecompressor {
+    fn new() -> Self {
+        Self(Box::default())
+    }
+
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> Result<usize, DecompressionError> {
+        self.0.init();
+
+        let flags = TINFL_FLAG_PARSE_ZLIB_HEADER | TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+        match decompress(&mut self.0, src, dst, 0, flags) {
+            (TINFLStatus::Done, _, written) => Ok(written),
+            (TINFLStatus::HasMoreOutput, ..) => Err(DecompressionError::InsufficientSpace),
+            _ => Err(DecompressionError::BadData),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! zlib-rs, a pure rust port of zlib-ng
+
+use zlib_rs::{Deflate, DeflateFlush, Inflate, InflateFlush, Status};
+
+use super::{Compress, CompressionLevel, Decompress};
+use crate::error::{CompressionError, DecompressionError};
+
+const WINDOW_BITS: u8 = 15;
+
+pub struct ZlibRsCompressor(Deflate);
+
+impl Compress for ZlibRsCompressor {
+    fn new(level: CompressionLevel) -> Self {
+        Self(Deflate::new(level.zlib() as i32, true, WINDOW_BITS))
+    }
+
+    fn compress_bound(&mut self, len: usize) -> usize {
+        zlib_rs::compress_bound(len)
+    }
+
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Result<usize, CompressionError> {
+        self.0.reset();
+
+        match self.0.compress(src, dst, DeflateFlush::Finish) {
+            Ok(Status::StreamEnd) => Ok(self.0.total_out() as usize),
+            Ok(_) => Err(CompressionError::InsufficientSpace),
+            Err(err) => Err(CompressionError::Failed(err.as_str().to_owned())),
+        }
+    }
+}
+
+pub struct ZlibRsDecompressor(Inflate);
+
+impl Decompress for ZlibRsDecompressor {
+    fn new() -> Self {
+        Self(Inflate::new(true, WINDOW_BITS))
+    }
+
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> Result<usize, DecompressionError> {
+        self.0.reset(true);
+
+        match self.0.decompress(src, dst, InflateFlush::Finish) {
+            Ok(Status::StreamEnd) => Ok(self.0.total_out() as usize),
+            // Stopped early, either on a full `dst` or truncated input
+            Ok(_) if self.0.total_out() as usize == dst.len() => {
+                Err(DecompressionError::InsufficientSpace)
+            }
+            _ => Err(DecompressionError::BadData),
+        }
+    }
+}
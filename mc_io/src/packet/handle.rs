@@ -1,4 +1,7 @@
-use crate::{error::CommunicationError, CompressionReadContext, FramedPacket, RawPacket};
+use crate::{
+    capture::Recorder, encryption::Decryptor, error::CommunicationError, observer::Observer,
+    state::ProtocolState, CompressionReadContext, FramedPacket, RawPacket,
+};
 
 use super::helpers;
 
@@ -9,14 +12,46 @@ pub trait PacketHandler<C> {
         ctx: &mut C,
     ) -> Result<(), CommunicationError>;
     fn compression_threshold(&self) -> i32;
+    /// The state packets are read in, only used to record and observe them
+    fn protocol_state(&self) -> ProtocolState;
+
+    /// Called after every packet, a returned decryptor is used for all bytes following that
+    /// packet. Used when the other side may start encrypting before the current read finishes.
+    fn take_decryptor(&mut self) -> Option<Decryptor> {
+        None
+    }
 }
 
 pub(crate) fn create_handler<'a, 'b: 'a, C: 'b, H: PacketHandler<C>>(
     handler: &'a mut H,
     ctx: &'b mut C,
-) -> impl FnMut(&FramedPacket, CompressionReadContext) -> Result<(), CommunicationError> + 'a {
-    move |packet, read_ctx| {
-        let packet = helpers::read_packet(packet, read_ctx, handler.compression_threshold())?;
-        handler.parse_and_handle(packet, ctx)
+    recorder: Option<&'a Recorder>,
+    observer: Option<&'a Observer>,
+) -> impl FnMut(
+    &FramedPacket,
+    usize,
+    CompressionReadContext,
+) -> Result<Option<Decryptor>, CommunicationError>
+       + 'a {
+    move |packet, wire_size, read_ctx| {
+        let compression_threshold = handler.compression_threshold();
+        let raw = helpers::read_packet(packet, read_ctx, compression_threshold)?;
+
+        if let Some(recorder) = recorder {
+            recorder.record_read(
+                packet,
+                &raw,
+                handler.protocol_state(),
+                compression_threshold,
+            )?;
+        }
+
+        if let Some(observer) = observer {
+            observer.observe_read(wire_size, &raw, handler.protocol_state());
+        }
+
+        handler.parse_and_handle(raw, ctx)?;
+
+        Ok(handler.take_decryptor())
     }
 }
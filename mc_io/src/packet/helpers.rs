@@ -4,7 +4,8 @@ use crate::buf::Buffer;
 use crate::error::{ReadError, WriteError};
 use crate::packet::lazy_varint::LazyVarint;
 use crate::{
-    CompressionReadContext, CompressionWriteContext, FramedPacket, RawPacket, MAXIMUM_PACKET_SIZE,
+    CompressionReadContext, CompressionWriteContext, FramedPacket, RawPacket, Segment,
+    MAXIMUM_PACKET_SIZE,
 };
 
 use super::compression;
@@ -69,21 +70,38 @@ fn create_packet_meta<'a>(
     }
 }
 
+/// A framed packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Framed<'a> {
+    /// Bytes including the length prefix and any compression
+    pub wire_size: usize,
+    /// Bytes of the packet id and body
+    pub size: usize,
+    /// Whether the packet was compressed on the wire
+    pub compressed: bool,
+    /// The packet id followed by the packet, as it was before compression
+    pub packet: &'a [u8],
+}
+
+/// Frames `packet` into `packet_buf`, or into `ctx.compression_buf` when it was encoded there and
+/// didn't end up needing compression. Either way, the run is recorded in `ctx.segments` and the
+/// wire size in `ctx.packet_sizes`.
 pub fn write_packet<'a, 'b, P>(
     packet: &'a P,
-    packet_buf: &mut Buffer,
-    ctx: &mut CompressionWriteContext,
+    packet_buf: &'b mut Buffer,
+    ctx: &'b mut CompressionWriteContext,
     compression_threshold: i32,
-) -> Result<(), WriteError>
+) -> Result<Framed<'b>, WriteError>
 where
     P: Packet<'a>,
 {
     let CompressionWriteContext {
         compression_buf,
+        segments,
+        packet_sizes,
         compressor,
-        ..
+        stats,
     } = ctx;
-    compression_buf.reset();
 
     let expected_packet_size = packet.expected_size();
     if expected_packet_size > MAXIMUM_PACKET_SIZE {
@@ -107,38 +125,62 @@ where
     let slice_after_write = packet.encode(&mut write_buf[1..]);
 
     let packet_size = pre_write_len - slice_after_write.len();
+    let framed_size = header_len + packet_size;
 
-    match packet_type {
+    // The encoded packet is borrowed again from where it was left, after the buffers are done with
+    let (wire_size, compressed, framed) = match packet_type {
         PacketType::Compressed(total_len, data_len, dst) => {
             total_len.write(3 + packet_size as i32);
             data_len.write(0);
 
             if let Some(dst) = dst {
                 if packet_size >= compression_threshold as usize {
-                    compression::compress(&write_buf[..packet_size], dst, compressor)?;
+                    let compressed =
+                        compression::compress(&write_buf[..packet_size], dst, *compressor, stats)?
+                            .len();
+                    push_segment(segments, Segment::WriteBuf(compressed));
+
+                    // Compressing only wrote to `packet_buf`, the packet is still unwritten here
+                    let framed = &compression_buf.get_unwritten(framed_size)[..];
+                    (compressed, true, framed)
                 } else {
-                    // SAFETY: We wrote a full packet into compression_buf
-                    let data = unsafe { compression_buf.advance_write(header_len + packet_size) };
-                    packet_buf.copy_from(data);
+                    stats.skipped(packet_size);
+                    push_segment(segments, Segment::CompressionBuf(framed_size));
+                    // The packet is already framed, leave it where it is instead of copying it
+                    let framed = compression_buf.advance_write(framed_size);
+                    (framed_size, false, framed)
                 }
             } else {
-                // SAFETY: We wrote a full packet into packet_buf
-                unsafe {
-                    packet_buf.advance_write(header_len + packet_size);
-                }
+                stats.skipped(packet_size);
+                push_segment(segments, Segment::WriteBuf(framed_size));
+                (framed_size, false, packet_buf.advance_write(framed_size))
             }
         }
         PacketType::Normal(total_len) => {
             total_len.write(packet_size as i32);
 
-            // SAFETY: We wrote a full packet into packet_buf
-            unsafe {
-                packet_buf.advance_write(header_len + packet_size);
-            }
+            push_segment(segments, Segment::WriteBuf(framed_size));
+            (framed_size, false, packet_buf.advance_write(framed_size))
         }
-    }
+    };
+
+    packet_sizes.push(wire_size);
 
-    Ok(())
+    Ok(Framed {
+        wire_size,
+        size: packet_size,
+        compressed,
+        packet: &framed[header_len..],
+    })
+}
+
+// Extends the last run if it is in the same buffer
+fn push_segment(segments: &mut Vec<Segment>, segment: Segment) {
+    match (segments.last_mut(), segment) {
+        (Some(Segment::WriteBuf(len)), Segment::WriteBuf(added))
+        | (Some(Segment::CompressionBuf(len)), Segment::CompressionBuf(added)) => *len += added,
+        _ => segments.push(segment),
+    }
 }
 
 pub fn read_packet<'a>(
@@ -149,7 +191,7 @@ pub fn read_packet<'a>(
     let CompressionReadContext {
         compression_buf,
         decompressor,
-        ..
+        stats,
     } = ctx;
 
     let buffer = if compression_threshold > 0 {
@@ -158,6 +200,7 @@ pub fn read_packet<'a>(
             compression_buf,
             decompressor,
             compression_threshold,
+            stats,
         )?
     } else {
         packet.0
@@ -63,15 +63,13 @@ mod tests {
         write_byte(&mut raw_buffer, 0xFF);
         varint.write(num);
 
-        let mut raw_buffer = unsafe { buffer.advance_write(1 + MAX_WIDTH + 1) };
+        let mut raw_buffer = buffer.advance_write(1 + MAX_WIDTH + 1);
 
         assert_eq!(read_byte(&mut raw_buffer), 0xFF);
-        assert_eq!(
-            num,
-            VarNum::<MAX_WIDTH>::try_decode(&mut raw_buffer)
-                .unwrap()
-                .into()
-        );
+        let decoded: i32 = VarNum::<MAX_WIDTH>::try_decode(&mut raw_buffer)
+            .unwrap()
+            .into();
+        assert_eq!(num, decoded);
         assert_eq!(read_byte(&mut raw_buffer), 0xFF);
     }
 
@@ -0,0 +1,211 @@
+//! Token buckets limiting how fast a connection writes. Whatever a bucket doesn't have the tokens
+//! for stays in the connection's `unwritten_buf`, and is released by later flushes once the
+//! buckets refilled.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Limits the bytes and packets written per second, either may be left unlimited
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+    // Bytes ever queued and written, only counted while a packet limit is set
+    queued: u64,
+    written: u64,
+    // Where the queued packets end, in bytes ever queued
+    packet_ends: VecDeque<u64>,
+    // Stands in for the current time when set, so tests don't have to sleep
+    #[cfg(test)]
+    pub(crate) clock: Option<Instant>,
+}
+
+impl RateLimit {
+    /// Up to `burst` worth of either limit may be written at once, after being idle for that long
+    pub fn new(
+        bytes_per_second: Option<u32>,
+        packets_per_second: Option<u32>,
+        burst: Duration,
+    ) -> Self {
+        let now = Instant::now();
+
+        Self {
+            bytes: bytes_per_second.map(|rate| TokenBucket::new(rate, burst, now)),
+            packets: packets_per_second.map(|rate| TokenBucket::new(rate, burst, now)),
+            queued: 0,
+            written: 0,
+            packet_ends: VecDeque::new(),
+            #[cfg(test)]
+            clock: None,
+        }
+    }
+
+    /// The time allowances are taken at
+    pub(crate) fn now(&self) -> Instant {
+        #[cfg(test)]
+        if let Some(now) = self.clock {
+            return now;
+        }
+
+        Instant::now()
+    }
+
+    /// Accounts for `len` bytes about to be written, made up of packets of `packet_sizes` and
+    /// anything else that doesn't count as a packet after them
+    pub(crate) fn queue(&mut self, len: usize, packet_sizes: impl Iterator<Item = usize>) {
+        if self.packets.is_none() {
+            return;
+        }
+
+        let mut end = self.queued;
+        for size in packet_sizes {
+            end += size as u64;
+            self.packet_ends.push_back(end);
+        }
+        self.queued += len as u64;
+    }
+
+    /// The bytes that may be written at `now`. A packet is only started once there is a token
+    /// for it.
+    pub(crate) fn allowance(&mut self, now: Instant) -> usize {
+        let mut allowance = usize::MAX;
+
+        if let Some(bytes) = &mut self.bytes {
+            allowance = bytes.refill(now) as usize;
+        }
+
+        if let Some(packets) = &mut self.packets {
+            let tokens = packets.refill(now) as usize;
+
+            if tokens < self.packet_ends.len() {
+                let end = match tokens {
+                    0 => self.written,
+                    tokens => self.packet_ends[tokens - 1],
+                };
+                allowance = allowance.min((end - self.written) as usize);
+            }
+        }
+
+        allowance
+    }
+
+    /// Takes the tokens for `len` written bytes
+    pub(crate) fn written(&mut self, len: usize) {
+        if let Some(bytes) = &mut self.bytes {
+            bytes.take(len as f64);
+        }
+
+        if let Some(packets) = &mut self.packets {
+            self.written += len as u64;
+
+            while self
+                .packet_ends
+                .front()
+                .is_some_and(|&end| end <= self.written)
+            {
+                self.packet_ends.pop_front();
+                packets.take(1.0);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    per_second: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    // Starts full. At least one token fits, so a packet limit below one per `burst` still works.
+    fn new(per_second: u32, burst: Duration, now: Instant) -> Self {
+        let per_second = per_second as f64;
+        let capacity = (per_second * burst.as_secs_f64()).max(1.0);
+
+        Self {
+            tokens: capacity,
+            capacity,
+            per_second,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.refilled = now;
+
+        self.tokens
+    }
+
+    fn take(&mut self, tokens: f64) {
+        self.tokens = (self.tokens - tokens).max(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BURST: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn bytes_refill_over_time() {
+        let mut limit = RateLimit::new(Some(10_000), None, BURST);
+        let start = limit.bytes.as_ref().unwrap().refilled;
+
+        assert_eq!(limit.allowance(start), 1000);
+        limit.written(1000);
+        assert_eq!(limit.allowance(start), 0);
+
+        assert_eq!(limit.allowance(start + Duration::from_millis(50)), 500);
+        // Never more than the burst
+        assert_eq!(limit.allowance(start + Duration::from_secs(10)), 1000);
+    }
+
+    #[test]
+    fn packets_are_written_whole() {
+        let mut limit = RateLimit::new(None, Some(20), BURST);
+        let start = limit.packets.as_ref().unwrap().refilled;
+
+        // Two tokens fit in the bucket
+        limit.queue(60, [10, 20, 30].into_iter());
+        assert_eq!(limit.allowance(start), 30);
+
+        // Part of the first packet doesn't take a token yet
+        limit.written(5);
+        assert_eq!(limit.allowance(start), 25);
+        limit.written(25);
+        assert_eq!(limit.allowance(start), 0);
+
+        // A token for every queued packet lifts the limit
+        limit.queue(40, [40].into_iter());
+        assert_eq!(limit.allowance(start + Duration::from_millis(50)), 30);
+        assert_eq!(
+            limit.allowance(start + Duration::from_millis(100)),
+            usize::MAX
+        );
+        limit.written(70);
+
+        // Bytes that aren't packets aren't limited
+        limit.queue(100, [].into_iter());
+        assert_eq!(
+            limit.allowance(start + Duration::from_millis(100)),
+            usize::MAX
+        );
+    }
+
+    #[test]
+    fn slow_packet_limit() {
+        let mut limit = RateLimit::new(None, Some(1), BURST);
+        let start = limit.packets.as_ref().unwrap().refilled;
+
+        limit.queue(30, [10, 10, 10].into_iter());
+        assert_eq!(limit.allowance(start), 10);
+        limit.written(10);
+        assert_eq!(limit.allowance(start + Duration::from_millis(500)), 0);
+        assert_eq!(limit.allowance(start + Duration::from_secs(1)), 10);
+    }
+}
@@ -0,0 +1,528 @@
+//! A mio based server. Connections are accepted and their handshake handled here, every packet
+//! after the handshake is passed to a `ConnectionHandler` created for that connection. Legacy
+//! pings are told apart from handshakes by their first byte and answered by the handler too.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use proto::direction::ServerToClient;
+use proto::legacy::{LegacyPingRequest, LegacyPingResponse};
+use proto::packets::c2s::handshake::{HandshakePacket, PacketHandlerHandshakeProtoC2S};
+use proto::packets::c2s::login::PacketHandlerLoginProtoC2S;
+use proto::packets::c2s::play::PacketHandlerPlayProtoC2S;
+use proto::packets::c2s::status::PacketHandlerStatusProtoC2S;
+use proto::packets::s2c::login::SetCompressionPacket;
+use proto::{Data, Packet};
+
+use crate::encryption::{Decryptor, Encryptor};
+use crate::error::{CommunicationError, ReadError};
+use crate::state::{ProtocolState, Server, StateMachine};
+use crate::{
+    BufferLimits, ConnectionReadContext, ConnectionWriteContext, GlobalReadContext,
+    GlobalWriteContext, Opening, PacketHandler, PacketWriter, RawPacket,
+};
+
+const LISTENER_TOKEN: Token = Token(0);
+const WAKER_TOKEN: Token = Token(1);
+
+/// What the client told us in its handshake
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub server_address: String,
+    pub server_port: u16,
+}
+
+/// Handles the packets of a single connection once its handshake is done
+pub trait ConnectionHandler:
+    for<'a> PacketHandlerStatusProtoC2S<Connection<'a>, Error = CommunicationError>
+    + for<'a> PacketHandlerLoginProtoC2S<Connection<'a>, Error = CommunicationError>
+    + for<'a> PacketHandlerPlayProtoC2S<Connection<'a>, Error = CommunicationError>
+{
+    /// Called after the handshake moved the connection to the status or login state
+    fn handshake(&mut self, _connection: &mut Connection) -> Result<(), CommunicationError> {
+        Ok(())
+    }
+
+    /// Called when a client opened with a legacy ping instead of a handshake. The returned
+    /// status is sent before the connection is closed, which happens without an answer for
+    /// `None`.
+    fn legacy_ping(&mut self, _request: &LegacyPingRequest) -> Option<LegacyPingResponse> {
+        None
+    }
+
+    /// Called with every packet received after the handshake, before it is handled
+    fn received(
+        &mut self,
+        _packet: &RawPacket,
+        _connection: &mut Connection,
+    ) -> Result<(), CommunicationError> {
+        Ok(())
+    }
+
+    /// Called once the connection is gone, with the error that closed it. Connections closed by
+    /// either side without an error get `None`.
+    fn disconnected(&mut self, _addr: SocketAddr, _error: Option<&CommunicationError>) {}
+}
+
+/// A connection as seen by its handler
+pub struct Connection<'a> {
+    state: &'a mut ConnectionState,
+    g_write_ctx: &'a mut GlobalWriteContext,
+}
+
+impl Connection<'_> {
+    pub fn addr(&self) -> SocketAddr {
+        self.state.addr
+    }
+
+    pub fn handshake(&self) -> Option<&Handshake> {
+        self.state.handshake.as_ref()
+    }
+
+    pub fn protocol(&mut self) -> &mut StateMachine<Server> {
+        &mut self.state.protocol
+    }
+
+    pub fn write_packet<'a, P: Packet<'a, Dir = ServerToClient>>(
+        &mut self,
+        packet: &'a P,
+    ) -> Result<(), CommunicationError> {
+        let ConnectionState {
+            protocol,
+            ctx_write,
+            ..
+        } = &mut *self.state;
+
+        ctx_write.write_packet(packet, self.g_write_ctx, protocol)
+    }
+
+    /// See `ConnectionWriteContext::write_packets`
+    pub fn write_packets<F>(&mut self, packets: F) -> Result<(), CommunicationError>
+    where
+        F: FnOnce(&mut PacketWriter<Server>) -> Result<(), CommunicationError>,
+    {
+        let ConnectionState {
+            protocol,
+            ctx_write,
+            ..
+        } = &mut *self.state;
+
+        ctx_write.write_packets(self.g_write_ctx, protocol, packets)
+    }
+
+    /// Sends `SetCompressionPacket` and compresses everything after it, in both directions
+    pub fn enable_compression(&mut self, threshold: i32) -> Result<(), CommunicationError> {
+        self.write_packet(&SetCompressionPacket { threshold })?;
+        self.state.protocol.set_compression_threshold(threshold);
+
+        Ok(())
+    }
+
+    /// Encrypts everything written from now on, and everything read after the current packet
+    pub fn enable_encryption(&mut self, (encryptor, decryptor): (Encryptor, Decryptor)) {
+        self.state.ctx_write.cipher = Some(encryptor);
+        self.state.decryptor = Some(decryptor);
+    }
+
+    /// Closes the connection once everything written to it is flushed. Packets received in the
+    /// meantime are ignored.
+    pub fn close(&mut self) {
+        self.state.closing = true;
+    }
+}
+
+struct ConnectionState {
+    addr: SocketAddr,
+    handshake: Option<Handshake>,
+    protocol: StateMachine<Server>,
+    ctx_write: ConnectionWriteContext<Arc<TcpStream>>,
+    // Handed to the reader by `take_decryptor`
+    decryptor: Option<Decryptor>,
+    // Whether the client opened with a handshake or legacy ping yet
+    opened: bool,
+    closing: bool,
+}
+
+struct Dispatch<'a, H> {
+    handler: &'a mut H,
+    g_write_ctx: &'a mut GlobalWriteContext,
+}
+
+impl<H: ConnectionHandler> PacketHandler<Dispatch<'_, H>> for ConnectionState {
+    fn parse_and_handle(
+        &mut self,
+        packet: RawPacket,
+        ctx: &mut Dispatch<H>,
+    ) -> Result<(), CommunicationError> {
+        if self.closing {
+            return Ok(());
+        }
+
+        let state = self.protocol.state();
+        let handler = &mut *ctx.handler;
+        let mut connection = Connection {
+            state: self,
+            g_write_ctx: ctx.g_write_ctx,
+        };
+
+        if state != ProtocolState::Handshake {
+            handler.received(&packet, &mut connection)?;
+        }
+
+        match state {
+            ProtocolState::Handshake => {
+                connection
+                    .state
+                    .parse_and_handle_handshake_proto_c2_s(packet, &mut ())?;
+
+                // Unknown packets are ignored, so the handshake may not have happened yet
+                if connection.state.handshake.is_some() {
+                    handler.handshake(&mut connection)?;
+                }
+
+                Ok(())
+            }
+            ProtocolState::Status => {
+                handler.parse_and_handle_status_proto_c2_s(packet, &mut connection)
+            }
+            ProtocolState::Login => {
+                handler.parse_and_handle_login_proto_c2_s(packet, &mut connection)
+            }
+            ProtocolState::Play => {
+                handler.parse_and_handle_play_proto_c2_s(packet, &mut connection)
+            }
+        }
+    }
+
+    fn compression_threshold(&self) -> i32 {
+        self.protocol.compression_threshold()
+    }
+
+    fn protocol_state(&self) -> ProtocolState {
+        self.protocol.state()
+    }
+
+    fn take_decryptor(&mut self) -> Option<Decryptor> {
+        self.decryptor.take()
+    }
+}
+
+impl PacketHandlerHandshakeProtoC2S<()> for ConnectionState {
+    type Error = CommunicationError;
+
+    fn handle_handshake_packet(
+        &mut self,
+        packet: HandshakePacket,
+        _: &mut (),
+    ) -> Result<(), Self::Error> {
+        let next_state = ProtocolState::from_id(packet.next_state)
+            .ok_or(ReadError::UnknownState(packet.next_state))?;
+        self.protocol.transition(next_state)?;
+
+        self.handshake = Some(Handshake {
+            protocol_version: packet.protocol_version,
+            server_address: packet.server_address.to_owned(),
+            server_port: packet.server_port,
+        });
+
+        Ok(())
+    }
+}
+
+struct Peer<H> {
+    handler: H,
+    ctx_read: ConnectionReadContext<Arc<TcpStream>>,
+    state: ConnectionState,
+}
+
+impl<H: ConnectionHandler> Peer<H> {
+    // Answers a legacy ping, anything else is left to be read as a handshake
+    fn open(&mut self) -> Result<(), CommunicationError> {
+        match self.ctx_read.detect_legacy_ping()? {
+            Opening::Handshake => self.state.opened = true,
+            Opening::LegacyPing(request) => {
+                self.state.opened = true;
+                self.state.closing = true;
+
+                if let Some(response) = self.handler.legacy_ping(&request) {
+                    let buffer = if request.beta {
+                        response.encode_beta()
+                    } else {
+                        let mut buffer = vec![0; response.expected_size()];
+                        response.encode(&mut buffer);
+                        buffer
+                    };
+                    self.state.ctx_write.write_slice(&buffer)?;
+                }
+            }
+            Opening::Incomplete => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Stops a running `Listener`, from any thread
+#[derive(Clone)]
+pub struct StopHandle {
+    waker: Arc<Waker>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    /// A handle that wakes `registry` with `token` when stopped, for event loops outside of
+    /// `Listener`
+    pub fn new(registry: &Registry, token: Token) -> io::Result<Self> {
+        Ok(Self {
+            waker: Arc::new(Waker::new(registry, token)?),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn stop(&self) -> io::Result<()> {
+        self.stopped.store(true, Ordering::Release);
+        self.waker.wake()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+}
+
+/// Accepts connections and drives them, creating a handler for each with `new_handler`
+pub struct Listener<H, F> {
+    poll: Poll,
+    events: Events,
+    listener: TcpListener,
+    stop: StopHandle,
+    connections: HashMap<Token, Peer<H>>,
+    next_token: usize,
+    new_handler: F,
+    g_read_ctx: GlobalReadContext,
+    g_write_ctx: GlobalWriteContext,
+    /// Limits applied to the buffers of new connections
+    pub limits: BufferLimits,
+}
+
+impl<H, F> Listener<H, F>
+where
+    H: ConnectionHandler,
+    F: FnMut(SocketAddr) -> H,
+{
+    pub fn bind(addr: SocketAddr, new_handler: F) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::bind(addr)?;
+        poll.registry()
+            .register(&mut listener, LISTENER_TOKEN, Interest::READABLE)?;
+
+        let stop = StopHandle::new(poll.registry(), WAKER_TOKEN)?;
+
+        Ok(Self {
+            poll,
+            events: Events::with_capacity(256),
+            listener,
+            stop,
+            connections: HashMap::new(),
+            next_token: WAKER_TOKEN.0 + 1,
+            new_handler,
+            g_read_ctx: GlobalReadContext::new(),
+            g_write_ctx: GlobalWriteContext::new(),
+            limits: BufferLimits::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// The amount of open connections
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Drives the listener until stopped with a `StopHandle`
+    pub fn run(&mut self) -> io::Result<()> {
+        while !self.stop.is_stopped() {
+            self.poll(None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles the events that arrive within `timeout`, for callers that do other work between
+    /// polls, eg. sending keep alives
+    pub fn poll(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let mut events = mem::replace(&mut self.events, Events::with_capacity(0));
+
+        let res = self.handle_events(&mut events, timeout);
+
+        self.events = events;
+        res
+    }
+
+    /// Runs `f` for every connection past the handshake, closing those it fails for or that were
+    /// closed by it
+    pub fn for_each_connection<C>(&mut self, mut f: C)
+    where
+        C: FnMut(&mut H, &mut Connection) -> Result<(), CommunicationError>,
+    {
+        let mut finished = Vec::new();
+
+        for (&token, peer) in &mut self.connections {
+            if peer.state.handshake.is_none() || peer.state.closing {
+                continue;
+            }
+
+            let mut connection = Connection {
+                state: &mut peer.state,
+                g_write_ctx: &mut self.g_write_ctx,
+            };
+
+            match f(&mut peer.handler, &mut connection) {
+                Err(error) => finished.push((token, Some(error))),
+                Ok(()) if peer.state.closing && peer.state.ctx_write.unwritten_buf.is_empty() => {
+                    finished.push((token, None))
+                }
+                Ok(()) => {}
+            }
+        }
+
+        for (token, error) in finished {
+            self.disconnect(token, error);
+        }
+    }
+
+    fn handle_events(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        match self.poll.poll(events, timeout) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => return Ok(()),
+            res => res?,
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER_TOKEN => self.accept()?,
+                WAKER_TOKEN => {}
+                token => self.handle_connection(token, event.is_readable(), event.is_writable()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            let (mut stream, addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    // Eg. running out of file descriptors, which shouldn't stop the server
+                    warn!("Could not accept a connection: {}", err);
+                    // The listener is edge triggered, re-arming it polls the backlog that is
+                    // left again
+                    return self.poll.registry().reregister(
+                        &mut self.listener,
+                        LISTENER_TOKEN,
+                        Interest::READABLE,
+                    );
+                }
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            if let Err(err) = self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                warn!("Could not register the connection from {}: {}", addr, err);
+                continue;
+            }
+            if let Err(err) = stream.set_nodelay(true) {
+                warn!("Could not set nodelay for {}: {}", addr, err);
+            }
+
+            let stream = Arc::new(stream);
+            let mut ctx_read = ConnectionReadContext::new(stream.clone());
+            ctx_read.limits = self.limits.clone();
+            let mut ctx_write = ConnectionWriteContext::new(stream);
+            ctx_write.limits = self.limits.clone();
+
+            let peer = Peer {
+                handler: (self.new_handler)(addr),
+                ctx_read,
+                state: ConnectionState {
+                    addr,
+                    handshake: None,
+                    protocol: StateMachine::new(),
+                    ctx_write,
+                    decryptor: None,
+                    opened: false,
+                    closing: false,
+                },
+            };
+
+            self.connections.insert(token, peer);
+        }
+    }
+
+    fn handle_connection(&mut self, token: Token, readable: bool, writable: bool) {
+        let Some(peer) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        let mut res = Ok(());
+
+        if writable {
+            res = peer.state.ctx_write.write_unwritten();
+        }
+
+        if readable && res.is_ok() && !peer.state.opened {
+            res = peer.open();
+        }
+
+        if readable && res.is_ok() && peer.state.opened && !peer.state.closing {
+            let mut dispatch = Dispatch {
+                handler: &mut peer.handler,
+                g_write_ctx: &mut self.g_write_ctx,
+            };
+
+            res = peer
+                .ctx_read
+                .read_packets(&mut self.g_read_ctx, &mut peer.state, &mut dispatch);
+        }
+
+        match res {
+            Err(CommunicationError::Closed) => self.disconnect(token, None),
+            Err(error) => self.disconnect(token, Some(error)),
+            Ok(()) if peer.state.closing && peer.state.ctx_write.unwritten_buf.is_empty() => {
+                self.disconnect(token, None)
+            }
+            Ok(()) => {}
+        }
+    }
+
+    fn disconnect(&mut self, token: Token, error: Option<CommunicationError>) {
+        // The stream is deregistered when it is dropped
+        if let Some(mut peer) = self.connections.remove(&token) {
+            peer.handler.disconnected(peer.state.addr, error.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,445 @@
+use super::*;
+use crate::state::Client;
+#[cfg(feature = "status")]
+use proto::legacy::LEGACY_PING_PAYLOAD;
+use proto::legacy::{LegacyPingResponse, LEGACY_PING};
+use proto::packets::c2s::handshake::HandshakePacket;
+use proto::packets::c2s::login::LoginStartPacket;
+use proto::packets::c2s::play::KeepAlivePacket;
+use proto::packets::c2s::status::{PingRequestPacket, StatusRequestPacket};
+use proto::packets::s2c;
+use proto::Data;
+use std::io::{Read, Write};
+use std::net::TcpStream as StdTcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+const STATUS: &str = r#"{"version":{"name":"1.19.2","protocol":760},"players":{"max":20,"online":1},"description":"test"}"#;
+
+struct TestHandler {
+    events: mpsc::Sender<String>,
+}
+
+impl TestHandler {
+    fn event(&self, event: String) {
+        let _ = self.events.send(event);
+    }
+}
+
+impl ConnectionHandler for TestHandler {
+    fn handshake(&mut self, connection: &mut Connection) -> Result<(), CommunicationError> {
+        let handshake = connection.handshake().unwrap();
+        self.event(format!("handshake {}", handshake.server_address));
+        Ok(())
+    }
+
+    fn legacy_ping(&mut self, request: &LegacyPingRequest) -> Option<LegacyPingResponse> {
+        let host = request.host.as_ref().map(|host| host.host.as_str());
+        self.event(format!("legacy ping {}", host.unwrap_or("unknown")));
+
+        Some(LegacyPingResponse {
+            protocol_version: 127,
+            version: "1.19.2".to_owned(),
+            motd: "test".to_owned(),
+            players: 1,
+            max_players: 20,
+        })
+    }
+
+    fn disconnected(&mut self, _: SocketAddr, error: Option<&CommunicationError>) {
+        self.event(format!("disconnected {}", error.is_some()));
+    }
+}
+
+impl PacketHandlerStatusProtoC2S<Connection<'_>> for TestHandler {
+    type Error = CommunicationError;
+
+    fn handle_status_request_packet(
+        &mut self,
+        _: StatusRequestPacket,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        connection.write_packet(&s2c::status::StatusResponsePacket { json: STATUS })
+    }
+
+    fn handle_ping_request_packet(
+        &mut self,
+        packet: PingRequestPacket,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        connection.write_packet(&s2c::status::PingResponsePacket {
+            payload: packet.payload,
+        })?;
+        connection.close();
+        Ok(())
+    }
+}
+
+impl PacketHandlerLoginProtoC2S<Connection<'_>> for TestHandler {
+    type Error = CommunicationError;
+
+    fn handle_login_start_packet(
+        &mut self,
+        packet: LoginStartPacket,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        connection.enable_compression(16)?;
+        connection.write_packets(|writer| {
+            writer.write_packet(&s2c::login::LoginSuccessPacket {
+                uuid: 1,
+                username: packet.username,
+                properties: Vec::new(),
+            })?;
+            writer.state().transition(ProtocolState::Play)
+        })
+    }
+}
+
+impl PacketHandlerPlayProtoC2S<Connection<'_>> for TestHandler {
+    type Error = CommunicationError;
+
+    fn handle_keep_alive_packet(
+        &mut self,
+        packet: KeepAlivePacket,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        connection.write_packet(&s2c::play::KeepAlivePacket { id: packet.id })
+    }
+}
+
+// Runs a listener on another thread until the returned handle is stopped
+fn start(events: mpsc::Sender<String>) -> (SocketAddr, StopHandle, thread::JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel();
+
+    let handle = thread::spawn(move || {
+        let mut listener = Listener::bind("127.0.0.1:0".parse().unwrap(), |_| TestHandler {
+            events: events.clone(),
+        })
+        .unwrap();
+
+        sender
+            .send((listener.local_addr().unwrap(), listener.stop_handle()))
+            .unwrap();
+        listener.run().unwrap();
+    });
+
+    let (addr, stop) = receiver.recv().unwrap();
+    (addr, stop, handle)
+}
+
+// A blocking client, which sees a timed out read as the end of the available data
+struct TestClient {
+    ctx_read: ConnectionReadContext<Arc<StdTcpStream>>,
+    ctx_write: ConnectionWriteContext<Arc<StdTcpStream>>,
+    g_read_ctx: GlobalReadContext,
+    g_write_ctx: GlobalWriteContext,
+    protocol: StateMachine<Client>,
+    packets: Vec<Vec<u8>>,
+    closed: bool,
+}
+
+impl TestClient {
+    fn connect(addr: SocketAddr) -> Self {
+        let stream = StdTcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let stream = Arc::new(stream);
+
+        Self {
+            ctx_read: ConnectionReadContext::new(stream.clone()),
+            ctx_write: ConnectionWriteContext::new(stream),
+            g_read_ctx: GlobalReadContext::new(),
+            g_write_ctx: GlobalWriteContext::new(),
+            protocol: StateMachine::new(),
+            packets: Vec::new(),
+            closed: false,
+        }
+    }
+
+    fn handshake(&mut self, next_state: ProtocolState) {
+        let handshake = HandshakePacket {
+            protocol_version: 760,
+            server_address: "localhost",
+            server_port: 25565,
+            next_state: next_state.id(),
+        };
+
+        self.ctx_write
+            .write_packet(&handshake, &mut self.g_write_ctx, &self.protocol)
+            .unwrap();
+        self.protocol.transition(next_state).unwrap();
+    }
+
+    fn write<'a, P: Packet<'a, Dir = proto::direction::ClientToServer>>(&mut self, packet: &'a P) {
+        self.ctx_write
+            .write_packet(packet, &mut self.g_write_ctx, &self.protocol)
+            .unwrap();
+    }
+
+    // Reads until `count` packets arrived in total
+    fn receive(&mut self, count: usize) -> Result<(), CommunicationError> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while self.packets.len() < count {
+            assert!(Instant::now() < deadline, "Timed out waiting for packets");
+
+            let mut collector = Collector {
+                protocol: &mut self.protocol,
+                packets: &mut self.packets,
+            };
+            let res = self
+                .ctx_read
+                .read_packets(&mut self.g_read_ctx, &mut collector, &mut ());
+
+            // Packets read before the connection closed are still handled
+            if let Err(CommunicationError::Closed) = res {
+                self.closed = true;
+                break;
+            }
+            res?;
+        }
+
+        if self.packets.len() < count {
+            return Err(CommunicationError::Closed);
+        }
+
+        Ok(())
+    }
+
+    fn packet<'a, P: Packet<'a>>(&'a self, index: usize) -> P {
+        let mut bytes = &self.packets[index][..];
+        assert_eq!(u8::try_decode(&mut bytes).unwrap(), P::PACKET_ID_NUM);
+        P::try_decode(&mut bytes).unwrap()
+    }
+
+    // Waits for the server to close the connection
+    fn closed(&mut self) -> bool {
+        let count = self.packets.len();
+        self.closed || matches!(self.receive(count + 1), Err(CommunicationError::Closed))
+    }
+}
+
+struct Collector<'a> {
+    protocol: &'a mut StateMachine<Client>,
+    packets: &'a mut Vec<Vec<u8>>,
+}
+
+impl PacketHandler<()> for Collector<'_> {
+    fn parse_and_handle(
+        &mut self,
+        packet: RawPacket,
+        _: &mut (),
+    ) -> Result<(), CommunicationError> {
+        let mut bytes = packet.0;
+
+        if self.protocol.state() == ProtocolState::Login {
+            let id = u8::try_decode(&mut bytes)?;
+
+            if id == s2c::login::SetCompressionPacket::PACKET_ID_NUM {
+                let packet = s2c::login::SetCompressionPacket::try_decode(&mut bytes)?;
+                self.protocol.set_compression_threshold(packet.threshold);
+            } else if id == s2c::login::LoginSuccessPacket::PACKET_ID_NUM {
+                self.protocol.transition(ProtocolState::Play)?;
+            }
+        }
+
+        self.packets.push(packet.0.to_vec());
+        Ok(())
+    }
+
+    fn compression_threshold(&self) -> i32 {
+        self.protocol.compression_threshold()
+    }
+
+    fn protocol_state(&self) -> ProtocolState {
+        self.protocol.state()
+    }
+}
+
+#[test]
+fn status_ping() {
+    let (events, received) = mpsc::channel();
+    let (addr, stop, handle) = start(events);
+
+    let mut client = TestClient::connect(addr);
+    client.handshake(ProtocolState::Status);
+    client.write(&StatusRequestPacket {});
+    client.write(&PingRequestPacket { payload: 42 });
+
+    client.receive(2).unwrap();
+    let status: s2c::status::StatusResponsePacket = client.packet(0);
+    assert_eq!(status.json, STATUS);
+    let pong: s2c::status::PingResponsePacket = client.packet(1);
+    assert_eq!(pong.payload, 42);
+    assert!(client.closed());
+
+    stop.stop().unwrap();
+    handle.join().unwrap();
+
+    let events: Vec<_> = received.try_iter().collect();
+    assert_eq!(events, ["handshake localhost", "disconnected false"]);
+}
+
+#[test]
+#[cfg(feature = "status")]
+fn poll_status() {
+    let (events, _received) = mpsc::channel();
+    let (addr, stop, handle) = start(events);
+
+    let status = crate::status::poll(addr, Duration::from_secs(5)).unwrap();
+    assert_eq!(status.motd, "test");
+    assert_eq!((status.players, status.max_players), (1, 20));
+    assert!(!status.legacy);
+
+    stop.stop().unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "status")]
+fn legacy_ping() {
+    let (events, received) = mpsc::channel();
+    let (addr, stop, handle) = start(events);
+
+    let status = crate::status::poll_legacy(addr, Duration::from_secs(5)).unwrap();
+    assert_eq!(status.motd, "test");
+    assert_eq!(status.protocol_version, 127);
+    assert!(status.legacy);
+
+    // Clients before 1.6 only send the first two bytes
+    let mut client = TestClient::connect(addr);
+    client
+        .ctx_write
+        .write_slice(&[LEGACY_PING, LEGACY_PING_PAYLOAD])
+        .unwrap();
+    assert!(client.closed());
+
+    stop.stop().unwrap();
+    handle.join().unwrap();
+
+    let events: Vec<_> = received.try_iter().collect();
+    assert_eq!(
+        events,
+        [
+            "legacy ping 127.0.0.1",
+            "disconnected false",
+            "legacy ping unknown",
+            "disconnected false"
+        ]
+    );
+}
+
+#[test]
+fn beta_ping() {
+    let (events, received) = mpsc::channel();
+    let (addr, stop, handle) = start(events);
+
+    // Clients before 1.4 send nothing but the first byte
+    let mut client = StdTcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.write_all(&[LEGACY_PING]).unwrap();
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+
+    let text = "test§1§20";
+    let mut expected = vec![0xFF, 0x00, text.encode_utf16().count() as u8];
+    expected.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+    assert_eq!(response, expected);
+
+    stop.stop().unwrap();
+    handle.join().unwrap();
+
+    let events: Vec<_> = received.try_iter().collect();
+    assert_eq!(events, ["legacy ping unknown", "disconnected false"]);
+}
+
+#[test]
+fn handshake_framed_like_a_legacy_ping() {
+    let (events, received) = mpsc::channel();
+    let (addr, stop, handle) = start(events);
+
+    // A handshake of 254 bytes has its length encoded as 0xFE 0x01
+    let address = "a".repeat(246);
+    let handshake = HandshakePacket {
+        protocol_version: 760,
+        server_address: &address,
+        server_port: 25565,
+        next_state: ProtocolState::Status.id(),
+    };
+    let mut buffer = vec![0; handshake.expected_size()];
+    let len = buffer.len() - handshake.encode(&mut buffer).len();
+    assert_eq!(len + 1, 254);
+
+    let mut client = TestClient::connect(addr);
+    client.write(&handshake);
+    client.protocol.transition(ProtocolState::Status).unwrap();
+    client.write(&StatusRequestPacket {});
+
+    client.receive(1).unwrap();
+    let status: s2c::status::StatusResponsePacket = client.packet(0);
+    assert_eq!(status.json, STATUS);
+
+    stop.stop().unwrap();
+    handle.join().unwrap();
+
+    let events: Vec<_> = received.try_iter().collect();
+    assert_eq!(events[0], format!("handshake {address}"));
+}
+
+#[test]
+fn login_with_compression() {
+    let (events, _received) = mpsc::channel();
+    let (addr, stop, handle) = start(events);
+
+    let mut client = TestClient::connect(addr);
+    client.handshake(ProtocolState::Login);
+    client.write(&LoginStartPacket {
+        username: "bot",
+        signature_data: None,
+        uuid: None,
+    });
+
+    client.receive(2).unwrap();
+    let success: s2c::login::LoginSuccessPacket = client.packet(1);
+    assert_eq!(success.username, "bot");
+    assert_eq!(client.protocol.compression_threshold(), 16);
+
+    // Play packets are compressed in both directions
+    client.write(&KeepAlivePacket { id: 7 });
+    client.receive(3).unwrap();
+    let keep_alive: s2c::play::KeepAlivePacket = client.packet(2);
+    assert_eq!(keep_alive.id, 7);
+
+    stop.stop().unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn bad_handshake() {
+    let (events, received) = mpsc::channel();
+    let (addr, stop, handle) = start(events);
+
+    // Play can't be reached from the handshake
+    let handshake = HandshakePacket {
+        protocol_version: 760,
+        server_address: "localhost",
+        server_port: 25565,
+        next_state: ProtocolState::Play.id(),
+    };
+
+    let mut client = TestClient::connect(addr);
+    client.write(&handshake);
+    assert!(client.closed());
+
+    stop.stop().unwrap();
+    handle.join().unwrap();
+
+    assert!(received
+        .try_iter()
+        .any(|event| event == "disconnected true"));
+}
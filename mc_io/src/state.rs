@@ -0,0 +1,327 @@
+//! Tracks the protocol state of a connection, so packets are only sent and handled in the state
+//! they belong to
+
+use proto::direction::{ClientToServer, PacketDirection, ServerToClient};
+use proto::packets::c2s::handshake::{HandshakeProtoC2S, PacketHandlerHandshakeProtoC2S};
+use proto::packets::c2s::login::{LoginProtoC2S, PacketHandlerLoginProtoC2S};
+use proto::packets::c2s::play::{PacketHandlerPlayProtoC2S, PlayProtoC2S};
+use proto::packets::c2s::status::{PacketHandlerStatusProtoC2S, StatusProtoC2S};
+use proto::packets::s2c::login::{LoginProtoS2C, PacketHandlerLoginProtoS2C};
+use proto::packets::s2c::play::{PacketHandlerPlayProtoS2C, PlayProtoS2C};
+use proto::packets::s2c::status::{PacketHandlerStatusProtoS2C, StatusProtoS2C};
+use proto::{DecodingError, Direction, Packet};
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+use crate::error::{CommunicationError, ReadError, WriteError};
+use crate::RawPacket;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolState {
+    Handshake,
+    Status,
+    Login,
+    Play,
+}
+
+impl ProtocolState {
+    /// The id used by the protocol, eg. in `HandshakePacket::next_state`
+    pub const fn id(self) -> u8 {
+        match self {
+            ProtocolState::Handshake => HandshakeProtoC2S::PROTOCOL_ID,
+            ProtocolState::Status => StatusProtoS2C::PROTOCOL_ID,
+            ProtocolState::Login => LoginProtoS2C::PROTOCOL_ID,
+            ProtocolState::Play => PlayProtoS2C::PROTOCOL_ID,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        [
+            ProtocolState::Handshake,
+            ProtocolState::Status,
+            ProtocolState::Login,
+            ProtocolState::Play,
+        ]
+        .into_iter()
+        .find(|state| state.id() == id)
+    }
+
+    /// The name of packet `id` sent in `direction` in this state, `None` if it isn't known
+    pub fn packet_name(self, direction: Direction, id: u8) -> Option<String> {
+        match (direction, self) {
+            (Direction::ClientToServer, ProtocolState::Handshake) => {
+                HandshakeProtoC2S::from_id(id).map(|it| format!("{it:?}"))
+            }
+            (Direction::ClientToServer, ProtocolState::Status) => {
+                StatusProtoC2S::from_id(id).map(|it| format!("{it:?}"))
+            }
+            (Direction::ClientToServer, ProtocolState::Login) => {
+                LoginProtoC2S::from_id(id).map(|it| format!("{it:?}"))
+            }
+            (Direction::ClientToServer, ProtocolState::Play) => {
+                PlayProtoC2S::from_id(id).map(|it| format!("{it:?}"))
+            }
+            (Direction::ServerToClient, ProtocolState::Handshake) => None,
+            (Direction::ServerToClient, ProtocolState::Status) => {
+                StatusProtoS2C::from_id(id).map(|it| format!("{it:?}"))
+            }
+            (Direction::ServerToClient, ProtocolState::Login) => {
+                LoginProtoS2C::from_id(id).map(|it| format!("{it:?}"))
+            }
+            (Direction::ServerToClient, ProtocolState::Play) => {
+                PlayProtoS2C::from_id(id).map(|it| format!("{it:?}"))
+            }
+        }
+    }
+
+    /// Decodes the body of packet `id` sent in `direction` in this state and formats it with
+    /// `Debug`, `None` if the packet isn't known
+    pub fn debug_packet(
+        self,
+        direction: Direction,
+        id: u8,
+        body: &[u8],
+    ) -> Option<Result<String, DecodingError>> {
+        match (direction, self) {
+            (Direction::ClientToServer, ProtocolState::Handshake) => {
+                HandshakeProtoC2S::debug_packet(id, body)
+            }
+            (Direction::ClientToServer, ProtocolState::Status) => {
+                StatusProtoC2S::debug_packet(id, body)
+            }
+            (Direction::ClientToServer, ProtocolState::Login) => {
+                LoginProtoC2S::debug_packet(id, body)
+            }
+            (Direction::ClientToServer, ProtocolState::Play) => {
+                PlayProtoC2S::debug_packet(id, body)
+            }
+            (Direction::ServerToClient, ProtocolState::Handshake) => None,
+            (Direction::ServerToClient, ProtocolState::Status) => {
+                StatusProtoS2C::debug_packet(id, body)
+            }
+            (Direction::ServerToClient, ProtocolState::Login) => {
+                LoginProtoS2C::debug_packet(id, body)
+            }
+            (Direction::ServerToClient, ProtocolState::Play) => {
+                PlayProtoS2C::debug_packet(id, body)
+            }
+        }
+    }
+
+    /// Passes a packet received by a client to the handler for this state
+    pub fn handle_s2c<C, H: ClientHandler<C>>(
+        self,
+        handler: &mut H,
+        packet: RawPacket,
+        ctx: &mut C,
+    ) -> Result<(), CommunicationError> {
+        match self {
+            ProtocolState::Handshake => Err(ReadError::BadProtocolState.into()),
+            ProtocolState::Status => handler.parse_and_handle_status_proto_s2_c(packet, ctx),
+            ProtocolState::Login => handler.parse_and_handle_login_proto_s2_c(packet, ctx),
+            ProtocolState::Play => handler.parse_and_handle_play_proto_s2_c(packet, ctx),
+        }
+    }
+
+    /// Passes a packet received by a server to the handler for this state
+    pub fn handle_c2s<C, H: ServerHandler<C>>(
+        self,
+        handler: &mut H,
+        packet: RawPacket,
+        ctx: &mut C,
+    ) -> Result<(), CommunicationError> {
+        match self {
+            ProtocolState::Handshake => handler.parse_and_handle_handshake_proto_c2_s(packet, ctx),
+            ProtocolState::Status => handler.parse_and_handle_status_proto_c2_s(packet, ctx),
+            ProtocolState::Login => handler.parse_and_handle_login_proto_c2_s(packet, ctx),
+            ProtocolState::Play => handler.parse_and_handle_play_proto_c2_s(packet, ctx),
+        }
+    }
+}
+
+/// Handles every state a client can receive packets in
+pub trait ClientHandler<C>:
+    PacketHandlerStatusProtoS2C<C, Error = CommunicationError>
+    + PacketHandlerLoginProtoS2C<C, Error = CommunicationError>
+    + PacketHandlerPlayProtoS2C<C, Error = CommunicationError>
+{
+}
+
+impl<C, T> ClientHandler<C> for T where
+    T: PacketHandlerStatusProtoS2C<C, Error = CommunicationError>
+        + PacketHandlerLoginProtoS2C<C, Error = CommunicationError>
+        + PacketHandlerPlayProtoS2C<C, Error = CommunicationError>
+{
+}
+
+/// Handles every state a server can receive packets in
+pub trait ServerHandler<C>:
+    PacketHandlerHandshakeProtoC2S<C, Error = CommunicationError>
+    + PacketHandlerStatusProtoC2S<C, Error = CommunicationError>
+    + PacketHandlerLoginProtoC2S<C, Error = CommunicationError>
+    + PacketHandlerPlayProtoC2S<C, Error = CommunicationError>
+{
+}
+
+impl<C, T> ServerHandler<C> for T where
+    T: PacketHandlerHandshakeProtoC2S<C, Error = CommunicationError>
+        + PacketHandlerStatusProtoC2S<C, Error = CommunicationError>
+        + PacketHandlerLoginProtoC2S<C, Error = CommunicationError>
+        + PacketHandlerPlayProtoC2S<C, Error = CommunicationError>
+{
+}
+
+/// Which end of the connection we are. Writers only accept packets going in the direction this
+/// side sends, so sending a packet the wrong way doesn't compile:
+///
+/// ```compile_fail
+/// use mc_io::state::{Client, StateMachine};
+/// use proto::packets::s2c::play::KeepAlivePacket;
+///
+/// let state = StateMachine::<Client>::new();
+/// state.check::<KeepAlivePacket>();
+/// ```
+pub trait Side: Clone + Copy + Debug {
+    /// The direction of the packets this side sends
+    type Sends: PacketDirection;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Client;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Server;
+
+impl Side for Client {
+    type Sends = ClientToServer;
+}
+
+impl Side for Server {
+    type Sends = ServerToClient;
+}
+
+/// Owns the protocol state and compression threshold of a connection
+#[derive(Clone, Debug)]
+pub struct StateMachine<S: Side> {
+    state: ProtocolState,
+    compression_threshold: i32,
+    side: PhantomData<S>,
+}
+
+impl<S: Side> StateMachine<S> {
+    /// A new connection, which starts with the handshake
+    pub const fn new() -> Self {
+        Self::resume(ProtocolState::Handshake, -1)
+    }
+
+    /// A connection that is already past the handshake, eg. one taken over from a proxy
+    pub const fn resume(state: ProtocolState, compression_threshold: i32) -> Self {
+        Self {
+            state,
+            compression_threshold,
+            side: PhantomData,
+        }
+    }
+
+    pub const fn state(&self) -> ProtocolState {
+        self.state
+    }
+
+    pub const fn compression_threshold(&self) -> i32 {
+        self.compression_threshold
+    }
+
+    pub fn set_compression_threshold(&mut self, compression_threshold: i32) {
+        self.compression_threshold = compression_threshold;
+    }
+
+    /// Moves to `next`, only allowing the transitions the protocol has
+    pub fn transition(&mut self, next: ProtocolState) -> Result<(), CommunicationError> {
+        match (self.state, next) {
+            (ProtocolState::Handshake, ProtocolState::Status | ProtocolState::Login)
+            | (ProtocolState::Login, ProtocolState::Play) => {
+                self.state = next;
+                Ok(())
+            }
+            (from, to) => Err(CommunicationError::BadTransition(from, to)),
+        }
+    }
+
+    /// Checks that `P` can be sent in the current state, the direction is already checked by the
+    /// compiler
+    pub fn check<'a, P: Packet<'a, Dir = S::Sends>>(&self) -> Result<(), WriteError> {
+        if P::PROTOCOL_ID == self.state.id() {
+            Ok(())
+        } else {
+            Err(WriteError::BadProtocolState(
+                format!("{:?}", P::PACKET_ID),
+                self.state,
+            ))
+        }
+    }
+}
+
+impl<S: Side> Default for StateMachine<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::packets::c2s::handshake::HandshakePacket;
+    use proto::packets::c2s::play::KeepAlivePacket;
+    use proto::packets::s2c;
+
+    #[test]
+    fn transitions() {
+        let mut state = StateMachine::<Client>::new();
+
+        assert!(state.transition(ProtocolState::Play).is_err());
+        state.transition(ProtocolState::Login).unwrap();
+        assert!(state.transition(ProtocolState::Status).is_err());
+        state.transition(ProtocolState::Play).unwrap();
+        assert!(state.transition(ProtocolState::Handshake).is_err());
+
+        assert_eq!(state.state(), ProtocolState::Play);
+    }
+
+    #[test]
+    fn ids() {
+        for id in 0..4 {
+            assert_eq!(ProtocolState::from_id(id).unwrap().id(), id);
+        }
+        assert_eq!(ProtocolState::from_id(4), None);
+    }
+
+    #[test]
+    fn describe() {
+        let state = ProtocolState::Play;
+        let name = state.packet_name(Direction::ClientToServer, KeepAlivePacket::PACKET_ID_NUM);
+        assert_eq!(name.as_deref(), Some("KeepAlivePacket"));
+        assert_eq!(state.packet_name(Direction::ServerToClient, 0x7f), None);
+
+        let body = 5u64.to_be_bytes();
+        let decoded = state.debug_packet(Direction::ClientToServer, 0x12, &body);
+        assert_eq!(decoded.unwrap().unwrap(), "KeepAlivePacket { id: 5 }");
+        let dirty = state.debug_packet(Direction::ClientToServer, 0x12, &[0; 9]);
+        assert!(dirty.unwrap().is_err());
+    }
+
+    #[test]
+    fn check() {
+        let mut client = StateMachine::<Client>::new();
+        assert!(client.check::<HandshakePacket>().is_ok());
+        assert!(client.check::<KeepAlivePacket>().is_err());
+
+        client.transition(ProtocolState::Login).unwrap();
+        client.transition(ProtocolState::Play).unwrap();
+        assert!(client.check::<HandshakePacket>().is_err());
+        assert!(client.check::<KeepAlivePacket>().is_ok());
+
+        let server = StateMachine::<Server>::resume(ProtocolState::Play, -1);
+        assert!(server.check::<s2c::play::KeepAlivePacket>().is_ok());
+        assert!(server.check::<s2c::login::LoginSuccessPacket>().is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Polls the status of a server like the multiplayer server list does. Servers that don't answer
+//! the status request, like ones from before 1.7 or proxies in front of them, are polled with the
+//! legacy ping instead.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use proto::legacy::{LegacyPingRequest, LegacyPingResponse, PingHost, LEGACY_PROTOCOL_VERSION};
+use proto::packets::c2s::handshake::HandshakePacket;
+use proto::packets::c2s::status::StatusRequestPacket;
+use proto::packets::s2c::status::StatusResponsePacket;
+use proto::primitive::V21;
+use proto::{Data, DecodingError, Packet};
+
+use crate::error::StatusError;
+use crate::state::{Client, ProtocolState, StateMachine};
+use crate::{ConnectionWriteContext, GlobalWriteContext};
+
+// Sent as the protocol version when the client doesn't know what the server runs
+const ANY_PROTOCOL_VERSION: u32 = u32::MAX;
+
+/// What a server shows in the server list
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerStatus {
+    pub protocol_version: i32,
+    pub version: String,
+    /// The description as plain text, formatting codes included
+    pub motd: String,
+    pub players: i32,
+    pub max_players: i32,
+    /// Whether the status was answered to a legacy ping
+    pub legacy: bool,
+}
+
+/// Polls with a status request, then with a legacy ping if that failed. The error of the status
+/// request is returned when both fail.
+pub fn poll(addr: SocketAddr, timeout: Duration) -> Result<ServerStatus, StatusError> {
+    poll_status(addr, timeout).or_else(|error| poll_legacy(addr, timeout).map_err(|_| error))
+}
+
+/// Polls with a handshake followed by a status request, as done since 1.7
+pub fn poll_status(addr: SocketAddr, timeout: Duration) -> Result<ServerStatus, StatusError> {
+    let deadline = Instant::now() + timeout;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut ctx_write = ConnectionWriteContext::new(&stream);
+    let mut g_write_ctx = GlobalWriteContext::new();
+    let mut protocol = StateMachine::<Client>::new();
+
+    let handshake = HandshakePacket {
+        protocol_version: ANY_PROTOCOL_VERSION,
+        server_address: &addr.ip().to_string(),
+        server_port: addr.port(),
+        next_state: ProtocolState::Status.id(),
+    };
+    ctx_write.write_packet(&handshake, &mut g_write_ctx, &protocol)?;
+    protocol.transition(ProtocolState::Status)?;
+    ctx_write.write_packet(&StatusRequestPacket {}, &mut g_write_ctx, &protocol)?;
+    // A write that timed out leaves what it couldn't send unwritten instead of failing
+    if !ctx_write.unwritten_buf.is_empty() {
+        return Err(StatusError::TimedOut);
+    }
+
+    let json = read_response(&stream, deadline, |mut data| {
+        // Status responses are never compressed, as compression is only enabled during login
+        let len: usize = V21::try_decode(&mut data)?.into();
+        if data.len() < len {
+            return Err(DecodingError::EOF);
+        }
+
+        let mut packet = &data[..len];
+        let id = u8::try_decode(&mut packet)?;
+        if id != StatusResponsePacket::PACKET_ID_NUM {
+            return Ok(Err(StatusError::UnexpectedPacket(id)));
+        }
+
+        Ok(Ok(StatusResponsePacket::try_decode(&mut packet)?
+            .json
+            .to_owned()))
+    })??;
+
+    parse_status(&json)
+}
+
+/// Polls with a legacy ping, as done by 1.6 clients
+pub fn poll_legacy(addr: SocketAddr, timeout: Duration) -> Result<ServerStatus, StatusError> {
+    let deadline = Instant::now() + timeout;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let request = LegacyPingRequest {
+        beta: false,
+        host: Some(PingHost {
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            host: addr.ip().to_string(),
+            port: addr.port(),
+        }),
+    };
+    let mut buffer = vec![0; request.expected_size()];
+    request.encode(&mut buffer);
+    stream.write_all(&buffer)?;
+
+    let response = read_response(&stream, deadline, |mut data| {
+        LegacyPingResponse::try_decode(&mut data)
+    })?;
+
+    Ok(ServerStatus {
+        protocol_version: response.protocol_version,
+        version: response.version,
+        motd: response.motd,
+        players: response.players,
+        max_players: response.max_players,
+        legacy: true,
+    })
+}
+
+// Reads until `decode` no longer runs out of data
+fn read_response<T>(
+    mut stream: &TcpStream,
+    deadline: Instant,
+    mut decode: impl FnMut(&[u8]) -> Result<T, DecodingError>,
+) -> Result<T, StatusError> {
+    let mut received = Vec::new();
+    let mut buffer = [0; 4096];
+
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Err(StatusError::TimedOut);
+        }
+        stream.set_read_timeout(Some(timeout))?;
+
+        match stream.read(&mut buffer) {
+            Ok(0) => return Err(StatusError::Closed),
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(StatusError::TimedOut)
+            }
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
+        }
+
+        match decode(&received) {
+            Err(DecodingError::EOF) => continue,
+            res => return Ok(res?),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StatusJson {
+    version: Version,
+    // Servers may hide their players
+    players: Option<Players>,
+    #[serde(default)]
+    description: Value,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    name: String,
+    protocol: i32,
+}
+
+#[derive(Deserialize)]
+struct Players {
+    max: i32,
+    online: i32,
+}
+
+fn parse_status(json: &str) -> Result<ServerStatus, StatusError> {
+    let status: StatusJson = serde_json::from_str(json)?;
+    let (players, max_players) = status
+        .players
+        .map_or((0, 0), |players| (players.online, players.max));
+
+    let mut motd = String::new();
+    plain_text(&status.description, &mut motd);
+
+    Ok(ServerStatus {
+        protocol_version: status.version.protocol,
+        version: status.version.name,
+        motd,
+        players,
+        max_players,
+        legacy: false,
+    })
+}
+
+// Flattens a chat component into its text
+fn plain_text(component: &Value, text: &mut String) {
+    match component {
+        Value::String(string) => text.push_str(string),
+        Value::Array(components) => components.iter().for_each(|it| plain_text(it, text)),
+        Value::Object(object) => {
+            if let Some(Value::String(string)) = object.get("text") {
+                text.push_str(string);
+            }
+            if let Some(extra) = object.get("extra") {
+                plain_text(extra, text);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn chat_component_description() {
+        let status = parse_status(
+            r#"{
+                "version": { "name": "1.19.2", "protocol": 760 },
+                "players": { "max": 20, "online": 2, "sample": [] },
+                "description": { "text": "A ", "extra": ["Minecraft", { "text": " Server" }] }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(status.motd, "A Minecraft Server");
+        assert_eq!((status.players, status.max_players), (2, 20));
+        assert_eq!(status.protocol_version, 760);
+    }
+
+    #[test]
+    fn falls_back_to_legacy_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            // Like a server from before 1.7, which closes the connection on a handshake
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 1]).unwrap();
+            drop(stream);
+
+            let (stream, _) = listener.accept().unwrap();
+            let request = read_response(&stream, Instant::now() + TIMEOUT, |mut data| {
+                LegacyPingRequest::try_decode(&mut data)
+            })
+            .unwrap();
+            assert_eq!(request.host.unwrap().port, addr.port());
+
+            let response = LegacyPingResponse {
+                protocol_version: LEGACY_PROTOCOL_VERSION as i32,
+                version: "1.6.4".to_owned(),
+                motd: "Old".to_owned(),
+                players: 1,
+                max_players: 10,
+            };
+            let mut buffer = vec![0; response.expected_size()];
+            response.encode(&mut buffer);
+            (&stream).write_all(&buffer).unwrap();
+        });
+
+        let status = poll(addr, TIMEOUT).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(
+            status,
+            ServerStatus {
+                protocol_version: 78,
+                version: "1.6.4".to_owned(),
+                motd: "Old".to_owned(),
+                players: 1,
+                max_players: 10,
+                legacy: true,
+            }
+        );
+    }
+}
@@ -0,0 +1,32 @@
+//! Uuids assigned by servers, which don't need the session server
+
+use md5::{Digest, Md5};
+
+/// The uuid an offline mode server assigns to `username`, a version 3 uuid of
+/// `OfflinePlayer:<username>`
+pub fn offline_uuid(username: &str) -> u128 {
+    let mut hash: [u8; 16] = Md5::new()
+        .chain_update("OfflinePlayer:")
+        .chain_update(username)
+        .finalize()
+        .into();
+
+    // Version 3, IETF variant
+    hash[6] = hash[6] & 0x0f | 0x30;
+    hash[8] = hash[8] & 0x3f | 0x80;
+
+    u128::from_be_bytes(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_offline_uuid() {
+        assert_eq!(
+            offline_uuid("Notch"),
+            0xb50ad385_829d_3141_a216_7e7d7539ba7f
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use log::info;
+use mc_io::error::CommunicationError;
+use mc_io::server::{Connection, ConnectionHandler};
+use mc_io::state::ProtocolState;
+use mc_io::uuid;
+use mc_io::RawPacket;
+use proto::legacy::{LegacyPingRequest, LegacyPingResponse, LEGACY_PROTOCOL_VERSION};
+use proto::packets::c2s::login::{LoginStartPacket, PacketHandlerLoginProtoC2S};
+use proto::packets::c2s::play::PacketHandlerPlayProtoC2S;
+use proto::packets::c2s::status::{
+    PacketHandlerStatusProtoC2S, PingRequestPacket, StatusRequestPacket,
+};
+use proto::packets::s2c::login::LoginSuccessPacket;
+use proto::packets::s2c::play::{JoinGamePacket, TeleportPacket, TimePacket};
+use proto::packets::s2c::status::{PingResponsePacket, StatusResponsePacket};
+
+use crate::Shared;
+
+/// Handles a single bot
+pub struct BotConnection {
+    shared: Arc<Shared>,
+    // Index of this connection's record
+    record: usize,
+}
+
+impl BotConnection {
+    pub(crate) fn new(shared: Arc<Shared>, addr: SocketAddr) -> Self {
+        let record = shared.recorder.accepted(addr);
+
+        Self { shared, record }
+    }
+}
+
+impl ConnectionHandler for BotConnection {
+    fn handshake(&mut self, connection: &mut Connection) -> Result<(), CommunicationError> {
+        let handshake = connection.handshake().cloned();
+        self.shared
+            .recorder
+            .update(self.record, |record| record.handshake = handshake);
+
+        Ok(())
+    }
+
+    fn legacy_ping(&mut self, request: &LegacyPingRequest) -> Option<LegacyPingResponse> {
+        let config = &self.shared.config;
+        // Like for the status request, whatever the client runs is supported
+        let protocol = request
+            .host
+            .as_ref()
+            .map_or(LEGACY_PROTOCOL_VERSION, |host| host.protocol_version);
+
+        Some(LegacyPingResponse {
+            protocol_version: protocol as i32,
+            version: "mock_server".to_owned(),
+            motd: config.motd.clone(),
+            players: self.shared.recorder.online() as i32,
+            max_players: config.max_players as i32,
+        })
+    }
+
+    fn received(
+        &mut self,
+        packet: &RawPacket,
+        connection: &mut Connection,
+    ) -> Result<(), CommunicationError> {
+        let state = connection.protocol().state();
+        let keep = self.shared.config.record_packets;
+
+        self.shared.recorder.update(self.record, |record| {
+            record.received(state, packet.0, keep);
+        });
+
+        Ok(())
+    }
+
+    fn disconnected(&mut self, addr: SocketAddr, error: Option<&CommunicationError>) {
+        let username = self.shared.recorder.update(self.record, |record| {
+            record.connected = false;
+            record.error = error.map(|error| error.to_string());
+            record.username.clone()
+        });
+
+        if let Some(username) = username {
+            match error {
+                Some(error) => info!("{} ({}) disconnected: {}", username, addr, error),
+                None => info!("{} ({}) disconnected", username, addr),
+            }
+        }
+    }
+}
+
+impl PacketHandlerStatusProtoC2S<Connection<'_>> for BotConnection {
+    type Error = CommunicationError;
+
+    fn handle_status_request_packet(
+        &mut self,
+        _: StatusRequestPacket,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        let config = &self.shared.config;
+        let protocol = connection
+            .handshake()
+            .map(|handshake| handshake.protocol_version)
+            .unwrap_or_default();
+
+        let status = serde_json::json!({
+            "version": { "name": "mock_server", "protocol": protocol },
+            "players": { "max": config.max_players, "online": self.shared.recorder.online() },
+            "description": { "text": config.motd },
+        });
+
+        connection.write_packet(&StatusResponsePacket {
+            json: &status.to_string(),
+        })
+    }
+
+    fn handle_ping_request_packet(
+        &mut self,
+        packet: PingRequestPacket,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        connection.write_packet(&PingResponsePacket {
+            payload: packet.payload,
+        })?;
+        connection.close();
+
+        Ok(())
+    }
+}
+
+impl PacketHandlerLoginProtoC2S<Connection<'_>> for BotConnection {
+    type Error = CommunicationError;
+
+    fn handle_login_start_packet(
+        &mut self,
+        packet: LoginStartPacket,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        let config = &self.shared.config;
+        let entity_id = self.shared.next_entity_id.fetch_add(1, Ordering::Relaxed);
+        let world_age = self.shared.world_age.load(Ordering::Relaxed);
+
+        self.shared.recorder.update(self.record, |record| {
+            record.username = Some(packet.username.to_owned());
+        });
+        info!("{} ({}) logged in", packet.username, connection.addr());
+
+        if config.compression_threshold >= 0 {
+            connection.enable_compression(config.compression_threshold)?;
+        }
+
+        connection.write_packets(|writer| {
+            // Offline mode servers ignore the uuid sent by the client
+            writer.write_packet(&LoginSuccessPacket {
+                uuid: uuid::offline_uuid(packet.username),
+                username: packet.username,
+                properties: Vec::new(),
+            })?;
+            writer.state().transition(ProtocolState::Play)?;
+
+            // Bots only read the entity id, so the rest of the packet is left out
+            writer.write_packet(&JoinGamePacket {
+                entity_id,
+                remaining: (&[][..]).into(),
+            })?;
+
+            let (x, y, z) = config.spawn;
+            writer.write_packet(&TeleportPacket {
+                x,
+                y,
+                z,
+                yaw: 0.0,
+                pitch: 0.0,
+                flags: 0,
+                id: 1,
+                dismount: false,
+            })?;
+
+            writer.write_packet(&TimePacket {
+                world_age,
+                time_of_day: (world_age % 24000) as i64,
+            })
+        })
+    }
+}
+
+// Play packets are only recorded
+impl PacketHandlerPlayProtoC2S<Connection<'_>> for BotConnection {
+    type Error = CommunicationError;
+}
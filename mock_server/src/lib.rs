@@ -0,0 +1,179 @@
+//! A minimal server for testing bots without a real one. It answers status pings, logs bots in
+//! in offline mode and keeps them in an empty world, recording every packet they send.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mc_io::error::CommunicationError;
+use mc_io::server::{Connection, Listener, StopHandle};
+use mc_io::state::ProtocolState;
+use proto::packets::s2c::play::{KeepAlivePacket, TimePacket};
+
+use crate::connection::BotConnection;
+use crate::record::Recorder;
+
+mod connection;
+pub mod record;
+
+// Vanilla servers send the time once a second
+const TIME_UPDATE_INTERVAL: u64 = 20;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Packets at least this large are compressed, negative to disable compression
+    pub compression_threshold: i32,
+    /// Simulated ticks per second, which is what bots will measure. Has to be positive.
+    pub tps: f64,
+    pub keep_alive_interval: Duration,
+    pub motd: String,
+    pub max_players: usize,
+    pub spawn: (f64, f64, f64),
+    /// Keep every packet received, not just the amount of each
+    pub record_packets: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            compression_threshold: 256,
+            tps: 20.0,
+            keep_alive_interval: Duration::from_secs(15),
+            motd: "A mock server".to_owned(),
+            max_players: 10000,
+            spawn: (0.0, 64.0, 0.0),
+            record_packets: false,
+        }
+    }
+}
+
+pub(crate) struct Shared {
+    config: Config,
+    recorder: Recorder,
+    next_entity_id: AtomicU32,
+    world_age: AtomicU64,
+}
+
+type NewConnection = Box<dyn FnMut(SocketAddr) -> BotConnection + Send>;
+
+pub struct MockServer {
+    listener: Listener<BotConnection, NewConnection>,
+    shared: Arc<Shared>,
+    tick_length: Duration,
+    next_tick: Instant,
+    next_keep_alive: Instant,
+    last_time_update: u64,
+}
+
+impl MockServer {
+    pub fn bind(addr: SocketAddr, config: Config) -> io::Result<Self> {
+        // Every tick has to last some time, but not forever
+        let tick_length = Duration::try_from_secs_f64(1.0 / config.tps)
+            .ok()
+            .filter(|length| !length.is_zero())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`tps` of {} is out of range", config.tps),
+                )
+            })?;
+
+        let shared = Arc::new(Shared {
+            config,
+            recorder: Recorder::default(),
+            next_entity_id: AtomicU32::new(1),
+            world_age: AtomicU64::new(0),
+        });
+
+        let new_connection: NewConnection = {
+            let shared = shared.clone();
+            Box::new(move |addr| BotConnection::new(shared.clone(), addr))
+        };
+
+        let now = Instant::now();
+
+        Ok(Self {
+            listener: Listener::bind(addr, new_connection)?,
+            tick_length,
+            next_tick: now,
+            next_keep_alive: now + shared.config.keep_alive_interval,
+            last_time_update: 0,
+            shared,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.listener.stop_handle()
+    }
+
+    pub fn recorder(&self) -> Recorder {
+        self.shared.recorder.clone()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.shared.config
+    }
+
+    /// Serves connections until stopped with a `StopHandle`
+    pub fn run(&mut self) -> io::Result<()> {
+        let stop = self.stop_handle();
+
+        while !stop.is_stopped() {
+            let timeout = self.next_tick.saturating_duration_since(Instant::now());
+            self.listener.poll(Some(timeout))?;
+
+            self.tick();
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        let now = Instant::now();
+
+        // Ticks missed while busy are caught up on, like a lagging server would
+        let mut world_age = self.shared.world_age.load(Ordering::Relaxed);
+        while self.next_tick <= now {
+            world_age += 1;
+            self.next_tick += self.tick_length;
+        }
+        self.shared.world_age.store(world_age, Ordering::Relaxed);
+
+        if world_age >= self.last_time_update + TIME_UPDATE_INTERVAL {
+            self.last_time_update = world_age;
+
+            let time = TimePacket {
+                world_age,
+                time_of_day: (world_age % 24000) as i64,
+            };
+            self.broadcast(|connection| connection.write_packet(&time));
+        }
+
+        if now >= self.next_keep_alive {
+            self.next_keep_alive = now + self.shared.config.keep_alive_interval;
+
+            let keep_alive = KeepAlivePacket { id: world_age };
+            self.broadcast(|connection| connection.write_packet(&keep_alive));
+        }
+    }
+
+    // Runs `f` for every connection in the play state
+    fn broadcast<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Connection) -> Result<(), CommunicationError>,
+    {
+        self.listener.for_each_connection(|_, connection| {
+            if connection.protocol().state() == ProtocolState::Play {
+                f(connection)
+            } else {
+                Ok(())
+            }
+        });
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::Context;
+use clap::Parser;
+use log::{info, LevelFilter};
+use mock_server::{Config, MockServer};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const NAME: &str = env!("CARGO_PKG_NAME");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Parser, Clone, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(default_value = "127.0.0.1:25565", help = "The address to listen on")]
+    address: SocketAddr,
+    #[arg(
+        short,
+        long,
+        default_value_t = 256,
+        allow_negative_numbers = true,
+        help = "Packets at least this large are compressed, -1 disables compression"
+    )]
+    compression_threshold: i32,
+    #[arg(long, default_value_t = 20.0, help = "The simulated ticks per second")]
+    tps: f64,
+    #[arg(
+        long,
+        default_value_t = 15,
+        help = "Time in seconds between keep alives"
+    )]
+    keep_alive_interval: u64,
+    #[arg(
+        long,
+        default_value = "A mock server",
+        help = "The motd shown in the server list"
+    )]
+    motd: String,
+    #[arg(
+        long,
+        help = "Write what each bot sent to this file as json when stopped"
+    )]
+    record: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    let config = Config {
+        compression_threshold: args.compression_threshold,
+        tps: args.tps,
+        keep_alive_interval: Duration::from_secs(args.keep_alive_interval),
+        motd: args.motd.clone(),
+        ..Config::default()
+    };
+
+    let mut server = MockServer::bind(args.address, config).context("Bind listener")?;
+    let recorder = server.recorder();
+
+    let stop = server.stop_handle();
+    ctrlc::set_handler(move || {
+        let _ = stop.stop();
+    })
+    .context("Set ctrl-c handler")?;
+
+    info!("Starting {} - {}", NAME, VERSION);
+    info!(
+        "Listening on {}",
+        server.local_addr().context("Local address")?
+    );
+
+    server.run().context("Run server")?;
+
+    if let Some(path) = args.record {
+        let summary = serde_json::to_string_pretty(&recorder.summary())?;
+        fs::write(&path, summary).context("Write record")?;
+        info!("Wrote record to {}", path.display());
+    }
+
+    Ok(())
+}
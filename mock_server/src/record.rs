@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mc_io::server::Handshake;
+use mc_io::state::ProtocolState;
+use proto::{Direction, Packet};
+use serde::Serialize;
+
+/// A packet sent by a bot
+#[derive(Clone, Debug)]
+pub struct Received {
+    pub state: ProtocolState,
+    /// Time since the connection was accepted
+    pub at: Duration,
+    /// The packet id followed by the packet
+    pub data: Vec<u8>,
+}
+
+impl Received {
+    pub fn id(&self) -> u8 {
+        // Every known packet id fits in a single byte varint
+        self.data[0]
+    }
+
+    pub fn name(&self) -> String {
+        packet_name(self.state, self.id())
+    }
+
+    /// The packet as `P`, if it is one
+    pub fn decode<'a, P: Packet<'a>>(&'a self) -> Option<P> {
+        if P::DIRECTION != Direction::ClientToServer
+            || P::PROTOCOL_ID != self.state.id()
+            || P::PACKET_ID_NUM != self.id()
+        {
+            return None;
+        }
+
+        let mut data = &self.data[1..];
+        P::try_decode(&mut data).ok()
+    }
+}
+
+/// Everything a single connection did
+#[derive(Clone, Debug)]
+pub struct BotRecord {
+    pub addr: SocketAddr,
+    pub handshake: Option<Handshake>,
+    pub username: Option<String>,
+    pub connected: bool,
+    /// The error the connection was closed with, if any
+    pub error: Option<String>,
+    /// The amount of each packet received, by name
+    pub counts: BTreeMap<String, usize>,
+    /// Every packet received, only kept if `Config::record_packets` is set
+    pub packets: Vec<Received>,
+    accepted: Instant,
+}
+
+impl BotRecord {
+    /// Every recorded packet of type `P`
+    pub fn decoded<'a, P: Packet<'a> + 'a>(&'a self) -> impl Iterator<Item = P> + 'a {
+        self.packets.iter().filter_map(Received::decode)
+    }
+
+    pub fn count(&self, name: &str) -> usize {
+        self.counts.get(name).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn received(&mut self, state: ProtocolState, data: &[u8], keep: bool) {
+        let received = Received {
+            state,
+            at: self.accepted.elapsed(),
+            data: data.to_vec(),
+        };
+
+        *self.counts.entry(received.name()).or_default() += 1;
+
+        if keep {
+            self.packets.push(received);
+        }
+    }
+}
+
+/// A serializable overview of a `BotRecord`
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub addr: SocketAddr,
+    pub username: Option<String>,
+    pub connected: bool,
+    pub error: Option<String>,
+    pub counts: BTreeMap<String, usize>,
+}
+
+/// Records what every connection sends. Clones share the same records.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    records: Arc<Mutex<Vec<BotRecord>>>,
+}
+
+impl Recorder {
+    // Returns the index of the new record
+    pub(crate) fn accepted(&self, addr: SocketAddr) -> usize {
+        let mut records = self.records.lock().unwrap();
+
+        records.push(BotRecord {
+            addr,
+            handshake: None,
+            username: None,
+            connected: true,
+            error: None,
+            counts: BTreeMap::new(),
+            packets: Vec::new(),
+            accepted: Instant::now(),
+        });
+
+        records.len() - 1
+    }
+
+    pub(crate) fn update<T>(&self, index: usize, f: impl FnOnce(&mut BotRecord) -> T) -> T {
+        f(&mut self.records.lock().unwrap()[index])
+    }
+
+    pub fn records(&self) -> Vec<BotRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// The latest connection that logged in as `username`
+    pub fn bot(&self, username: &str) -> Option<BotRecord> {
+        let records = self.records.lock().unwrap();
+
+        records
+            .iter()
+            .rev()
+            .find(|record| record.username.as_deref() == Some(username))
+            .cloned()
+    }
+
+    /// The amount of connections that logged in and are still connected
+    pub fn online(&self) -> usize {
+        let records = self.records.lock().unwrap();
+
+        records
+            .iter()
+            .filter(|record| record.connected && record.username.is_some())
+            .count()
+    }
+
+    pub fn summary(&self) -> Vec<Summary> {
+        let records = self.records.lock().unwrap();
+
+        records
+            .iter()
+            .map(|record| Summary {
+                addr: record.addr,
+                username: record.username.clone(),
+                connected: record.connected,
+                error: record.error.clone(),
+                counts: record.counts.clone(),
+            })
+            .collect()
+    }
+}
+
+/// The name of a packet sent by a client in `state`
+pub fn packet_name(state: ProtocolState, id: u8) -> String {
+    state
+        .packet_name(Direction::ClientToServer, id)
+        .unwrap_or_else(|| format!("Unknown{state:?}(0x{id:02x})"))
+}
@@ -0,0 +1,345 @@
+//! The server list ping from before 1.7, which some servers and proxies still answer. It isn't
+//! framed like other packets: the client opens with `0xFE 0x01`, or just `0xFE` before 1.4, and
+//! the server answers with a kick packet holding its status as a UTF-16 string, then closes the
+//! connection.
+
+use crate::{Data, DecodingError};
+
+/// The first byte of a legacy ping, no handshake starts with it
+pub const LEGACY_PING: u8 = 0xFE;
+/// The payload of a legacy ping, which asks for the 1.4 style response
+pub const LEGACY_PING_PAYLOAD: u8 = 0x01;
+/// The id of the kick packet the status is sent in
+pub const KICK: u8 = 0xFF;
+/// The protocol version of 1.6.4, the last version to use the legacy ping
+pub const LEGACY_PROTOCOL_VERSION: u8 = 78;
+
+const PLUGIN_MESSAGE: u8 = 0xFA;
+const PING_HOST: &str = "MC|PingHost";
+const RESPONSE_PREFIX: &str = "§1\0";
+const BETA_SEPARATOR: char = '§';
+
+/// A legacy ping. Clients before 1.6 only send `0xFE 0x01`, which decodes without a `host`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LegacyPingRequest {
+    /// Sent as a lone `0xFE` by clients before 1.4, which expect `LegacyPingResponse::encode_beta`
+    pub beta: bool,
+    pub host: Option<PingHost>,
+}
+
+/// The server a 1.6 client pinged, sent in a `MC|PingHost` plugin message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PingHost {
+    pub protocol_version: u8,
+    pub host: String,
+    pub port: u16,
+}
+
+impl<'a> Data<'a> for LegacyPingRequest {
+    fn try_decode(buffer: &mut &'a [u8]) -> Result<Self, DecodingError> {
+        if u8::try_decode(buffer)? != LEGACY_PING {
+            return Err(DecodingError::BadData);
+        }
+
+        // Everything a client sends is written at once, so nothing more means an older client
+        if buffer.is_empty() {
+            return Ok(Self {
+                beta: true,
+                host: None,
+            });
+        }
+
+        if u8::try_decode(buffer)? != LEGACY_PING_PAYLOAD {
+            return Err(DecodingError::BadData);
+        }
+
+        if buffer.is_empty() {
+            return Ok(Self {
+                beta: false,
+                host: None,
+            });
+        }
+
+        if u8::try_decode(buffer)? != PLUGIN_MESSAGE || decode_string(buffer)? != PING_HOST {
+            return Err(DecodingError::BadData);
+        }
+
+        let len = u16::try_decode(buffer)? as usize;
+        if buffer.len() < len {
+            return Err(DecodingError::EOF);
+        }
+        let (mut data, remaining) = buffer.split_at(len);
+        *buffer = remaining;
+
+        let host = PingHost {
+            protocol_version: u8::try_decode(&mut data)?,
+            host: decode_string(&mut data)?,
+            port: i32::try_decode(&mut data)? as u16,
+        };
+
+        if !data.is_empty() {
+            return Err(DecodingError::BadData);
+        }
+
+        Ok(Self {
+            beta: false,
+            host: Some(host),
+        })
+    }
+
+    fn expected_size(&self) -> usize {
+        if self.beta {
+            return 1;
+        }
+
+        2 + self
+            .host
+            .as_ref()
+            .map_or(0, |host| 1 + string_size(PING_HOST) + 2 + host.data_size())
+    }
+
+    fn encode<'b>(&self, buffer: &'b mut [u8]) -> &'b mut [u8] {
+        let buffer = LEGACY_PING.encode(buffer);
+        if self.beta {
+            return buffer;
+        }
+        let buffer = LEGACY_PING_PAYLOAD.encode(buffer);
+
+        let Some(host) = &self.host else {
+            return buffer;
+        };
+
+        let buffer = PLUGIN_MESSAGE.encode(buffer);
+        let buffer = encode_string(PING_HOST, buffer);
+        let buffer = (host.data_size() as u16).encode(buffer);
+        let buffer = host.protocol_version.encode(buffer);
+        let buffer = encode_string(&host.host, buffer);
+        (host.port as i32).encode(buffer)
+    }
+}
+
+impl PingHost {
+    fn data_size(&self) -> usize {
+        1 + string_size(&self.host) + 4
+    }
+}
+
+/// What a server answers a legacy ping with, sent as `§1\0protocol\0version\0motd\0players\0max`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LegacyPingResponse {
+    pub protocol_version: i32,
+    pub version: String,
+    pub motd: String,
+    pub players: i32,
+    pub max_players: i32,
+}
+
+impl LegacyPingResponse {
+    fn text(&self) -> String {
+        format!(
+            "{RESPONSE_PREFIX}{}\0{}\0{}\0{}\0{}",
+            self.protocol_version, self.version, self.motd, self.players, self.max_players
+        )
+    }
+
+    /// The response to a beta ping, `motd§players§max` without the versions
+    pub fn encode_beta(&self) -> Vec<u8> {
+        let text = format!(
+            "{}{BETA_SEPARATOR}{}{BETA_SEPARATOR}{}",
+            self.motd, self.players, self.max_players
+        );
+
+        let mut buffer = vec![0; 1 + string_size(&text)];
+        let rest = KICK.encode(&mut buffer);
+        encode_string(&text, rest);
+
+        buffer
+    }
+}
+
+impl<'a> Data<'a> for LegacyPingResponse {
+    fn try_decode(buffer: &mut &'a [u8]) -> Result<Self, DecodingError> {
+        if u8::try_decode(buffer)? != KICK {
+            return Err(DecodingError::BadData);
+        }
+
+        let text = decode_string(buffer)?;
+        let mut fields = text
+            .strip_prefix(RESPONSE_PREFIX)
+            .ok_or(DecodingError::BadData)?
+            .split('\0');
+        let mut field = || fields.next().ok_or(DecodingError::BadData);
+        let number = |field: &str| field.parse().map_err(|_| DecodingError::BadData);
+
+        let response = Self {
+            protocol_version: number(field()?)?,
+            version: field()?.to_owned(),
+            motd: field()?.to_owned(),
+            players: number(field()?)?,
+            max_players: number(field()?)?,
+        };
+
+        if fields.next().is_some() {
+            return Err(DecodingError::BadData);
+        }
+
+        Ok(response)
+    }
+
+    fn expected_size(&self) -> usize {
+        1 + string_size(&self.text())
+    }
+
+    fn encode<'b>(&self, buffer: &'b mut [u8]) -> &'b mut [u8] {
+        let buffer = KICK.encode(buffer);
+        encode_string(&self.text(), buffer)
+    }
+}
+
+// Strings are UTF-16 prefixed with their length in code units
+fn decode_string(buffer: &mut &[u8]) -> Result<String, DecodingError> {
+    let len = u16::try_decode(buffer)? as usize;
+    if buffer.len() < len * 2 {
+        return Err(DecodingError::EOF);
+    }
+
+    let units = (0..len)
+        .map(|_| u16::try_decode(buffer))
+        .collect::<Result<Vec<_>, _>>()?;
+    String::from_utf16(&units).map_err(|_| DecodingError::BadData)
+}
+
+fn string_size(string: &str) -> usize {
+    2 + string.encode_utf16().count() * 2
+}
+
+fn encode_string<'b>(string: &str, buffer: &'b mut [u8]) -> &'b mut [u8] {
+    let mut buffer = (string.encode_utf16().count() as u16).encode(buffer);
+    for unit in string.encode_utf16() {
+        buffer = unit.encode(buffer);
+    }
+
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip<D: for<'a> Data<'a> + PartialEq + std::fmt::Debug>(data: D) -> Vec<u8> {
+        let mut buffer = vec![0; data.expected_size()];
+        assert!(data.encode(&mut buffer).is_empty());
+
+        let mut slice = &buffer[..];
+        assert_eq!(D::try_decode(&mut slice).unwrap(), data);
+        assert!(slice.is_empty());
+
+        buffer
+    }
+
+    #[test]
+    fn request_roundtrip() {
+        let beta = roundtrip(LegacyPingRequest {
+            beta: true,
+            host: None,
+        });
+        assert_eq!(beta, [0xFE]);
+
+        let old = roundtrip(LegacyPingRequest {
+            beta: false,
+            host: None,
+        });
+        assert_eq!(old, [0xFE, 0x01]);
+
+        let request = roundtrip(LegacyPingRequest {
+            beta: false,
+            host: Some(PingHost {
+                protocol_version: LEGACY_PROTOCOL_VERSION,
+                host: "localhost".to_owned(),
+                port: 25565,
+            }),
+        });
+
+        // As sent by a 1.6.4 client
+        let mut expected = vec![0xFE, 0x01, 0xFA, 0x00, 0x0B];
+        expected.extend("MC|PingHost".bytes().flat_map(|it| [0, it]));
+        expected.extend([0x00, 0x19, 78, 0x00, 0x09]);
+        expected.extend("localhost".bytes().flat_map(|it| [0, it]));
+        expected.extend([0x00, 0x00, 0x63, 0xDD]);
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let response = roundtrip(LegacyPingResponse {
+            protocol_version: 127,
+            version: "1.19.2".to_owned(),
+            motd: "A Minecraft Server".to_owned(),
+            players: 3,
+            max_players: 20,
+        });
+
+        let text = "§1\x00127\x001.19.2\x00A Minecraft Server\x003\x0020";
+        let mut expected = vec![0xFF, 0x00, text.encode_utf16().count() as u8];
+        expected.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn beta_response() {
+        let response = LegacyPingResponse {
+            protocol_version: 127,
+            version: "1.19.2".to_owned(),
+            motd: "A Minecraft Server".to_owned(),
+            players: 3,
+            max_players: 20,
+        };
+
+        let text = "A Minecraft Server§3§20";
+        let mut expected = vec![0xFF, 0x00, text.encode_utf16().count() as u8];
+        expected.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        assert_eq!(response.encode_beta(), expected);
+    }
+
+    #[test]
+    fn incomplete_and_bad_data() {
+        let mut buffer = vec![0; 64];
+        let len = buffer.len()
+            - LegacyPingResponse {
+                protocol_version: 127,
+                version: "1.19.2".to_owned(),
+                motd: "motd".to_owned(),
+                players: 0,
+                max_players: 20,
+            }
+            .encode(&mut buffer)
+            .len();
+
+        assert!(matches!(
+            LegacyPingResponse::try_decode(&mut &buffer[..len - 1]),
+            Err(DecodingError::EOF)
+        ));
+        assert!(matches!(
+            LegacyPingRequest::try_decode(&mut &[0xFE, 0x01, 0xFA, 0x00][..]),
+            Err(DecodingError::EOF)
+        ));
+
+        // A modern handshake
+        assert!(matches!(
+            LegacyPingRequest::try_decode(&mut &[0x10, 0x00][..]),
+            Err(DecodingError::BadData)
+        ));
+        // A handshake longer than 255 bytes, whose length starts with 0xFE too
+        assert!(matches!(
+            LegacyPingRequest::try_decode(&mut &[0xFE, 0x02, 0x00][..]),
+            Err(DecodingError::BadData)
+        ));
+        // The pre 1.4 response, which has no protocol version
+        let mut old = vec![0xFF, 0x00, 0x09];
+        old.extend("motd§0§20".encode_utf16().flat_map(u16::to_be_bytes));
+        assert!(matches!(
+            LegacyPingResponse::try_decode(&mut &old[..]),
+            Err(DecodingError::BadData)
+        ));
+    }
+}
@@ -3,6 +3,7 @@
 
 use std::fmt::Debug;
 
+pub mod legacy;
 pub mod packets;
 pub mod primitive;
 
@@ -18,14 +19,50 @@ pub trait Packet<'a>: Data<'a> + Debug {
     type Proto: Debug;
     const PACKET_ID: Self::Proto;
     const PACKET_ID_NUM: u8;
+    const PROTOCOL_ID: u8;
     const DIRECTION: Direction;
+    /// `DIRECTION` as a type, so it can be checked at compile time
+    type Dir: direction::PacketDirection;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Direction {
     ServerToClient,
     ClientToServer,
 }
 
+impl Direction {
+    pub const fn opposite(self) -> Self {
+        match self {
+            Direction::ServerToClient => Direction::ClientToServer,
+            Direction::ClientToServer => Direction::ServerToClient,
+        }
+    }
+}
+
+/// Marker types for each `Direction`
+pub mod direction {
+    use super::Direction;
+
+    pub trait PacketDirection {
+        const DIRECTION: Direction;
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct ServerToClient;
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct ClientToServer;
+
+    impl PacketDirection for ServerToClient {
+        const DIRECTION: Direction = Direction::ServerToClient;
+    }
+
+    impl PacketDirection for ClientToServer {
+        const DIRECTION: Direction = Direction::ClientToServer;
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum DecodingError {
     #[error("Incomplete buffer")]
@@ -39,7 +76,7 @@ pub enum DecodingError {
 // Mostly taken from Graphite
 #[macro_export]
 macro_rules! define_proto {
-    ($proto_name:ident, $proto_id:expr, $dir:expr => { $( $packet:ident $(<$life:lifetime>)? = $packet_id:expr),* }) => {
+    ($proto_name:ident, $proto_id:expr, Direction::$dir:ident => { $( $packet:ident $(<$life:lifetime>)? = $packet_id:expr),* }) => {
         #[derive(Debug, Eq, PartialEq)]
         #[repr(u8)]
         pub enum $proto_name {
@@ -51,12 +88,40 @@ macro_rules! define_proto {
                 type Proto = $proto_name;
                 const PACKET_ID: $proto_name = $proto_name::$packet;
                 const PACKET_ID_NUM: u8 = $packet_id;
-                const DIRECTION: $crate::Direction = $dir;
+                const PROTOCOL_ID: u8 = $proto_id;
+                const DIRECTION: $crate::Direction = $crate::Direction::$dir;
+                type Dir = $crate::direction::$dir;
             }
         )*
 
         impl $proto_name {
             pub const PROTOCOL_ID: u8 = $proto_id;
+
+            pub fn from_id(id: u8) -> Option<Self> {
+                match id {
+                    $( id if id == $packet_id => Some($proto_name::$packet), )*
+                    _ => None,
+                }
+            }
+
+            /// Decodes the body of packet `id` and formats it with `Debug`, `None` if the packet
+            /// isn't known
+            pub fn debug_packet(id: u8, mut bytes: &[u8]) -> Option<Result<String, $crate::DecodingError>> {
+                match id {
+                    $(
+                        id if id == $packet_id => Some(
+                            <$packet as $crate::Data>::try_decode(&mut bytes).and_then(|packet| {
+                                if bytes.is_empty() {
+                                    Ok(format!("{:?}", packet))
+                                } else {
+                                    Err($crate::DecodingError::DirtyBuffer(stringify!($packet).to_owned()))
+                                }
+                            })
+                        ),
+                    )*
+                    _ => None,
+                }
+            }
         }
 
         paste::paste! {
@@ -109,6 +174,8 @@ macro_rules! define_data {
         }
 
         impl<'a> $crate::Data<'a> for $packet < $( $life )? > {
+            // `buffer` is unused by packets without fields
+            #[allow(unused_variables)]
             fn try_decode(buffer: &mut &'a [u8]) -> Result<Self, $crate::DecodingError> {
                 Ok($packet {
                     $(
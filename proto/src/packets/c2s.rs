@@ -1,5 +1,5 @@
 pub mod handshake {
-    use crate::{define_data, define_proto, primitive::VarInt, Direction};
+    use crate::{define_data, define_proto, primitive::VarInt};
 
     define_proto! {
         HandshakeProtoC2S, 0, Direction::ClientToServer => {
@@ -17,8 +17,29 @@ pub mod handshake {
     }
 }
 
+pub mod status {
+    use crate::{define_data, define_proto};
+
+    define_proto! {
+        StatusProtoC2S, 1, Direction::ClientToServer => {
+            StatusRequestPacket = 0x00,
+            PingRequestPacket = 0x01
+        }
+    }
+
+    define_data! {
+        pub struct StatusRequestPacket {}
+    }
+
+    define_data! {
+        pub struct PingRequestPacket {
+            pub payload: u64
+        }
+    }
+}
+
 pub mod login {
-    use crate::{define_data, define_proto, primitive::Remaining, primitive::VarInt, Direction};
+    use crate::{define_data, define_proto, primitive::Remaining, primitive::VarInt};
 
     define_proto! {
         LoginProtoC2S, 2, Direction::ClientToServer => {
@@ -60,7 +81,7 @@ pub mod login {
 }
 
 pub mod play {
-    use crate::{define_data, define_proto, primitive::VarInt, Direction};
+    use crate::{define_data, define_proto, primitive::VarInt};
 
     define_proto! {
         PlayProtoC2S, 3, Direction::ClientToServer => {
@@ -1,5 +1,5 @@
 pub mod status {
-    use crate::{define_data, define_proto, Direction};
+    use crate::{define_data, define_proto};
 
     define_proto! {
         StatusProtoS2C, 1, Direction::ServerToClient => {
@@ -22,7 +22,7 @@ pub mod status {
 }
 
 pub mod login {
-    use crate::{define_data, define_proto, primitive::Remaining, primitive::VarInt, Direction};
+    use crate::{define_data, define_proto, primitive::Remaining, primitive::VarInt};
 
     define_proto! {
         LoginProtoS2C, 2, Direction::ServerToClient => {
@@ -79,7 +79,7 @@ pub mod login {
 }
 
 pub mod play {
-    use crate::{define_data, define_proto, primitive::Remaining, primitive::VarInt, Direction};
+    use crate::{define_data, define_proto, primitive::Remaining, primitive::VarInt};
 
     define_proto! {
         PlayProtoS2C, 3, Direction::ServerToClient => {
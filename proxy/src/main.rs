@@ -0,0 +1,75 @@
+use anyhow::Context;
+use clap::Parser;
+use log::{info, LevelFilter};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use mc_io::address::MinecraftAddress;
+
+use crate::packet_log::PacketLog;
+use crate::proxy::Proxy;
+
+mod packet_log;
+mod proxy;
+mod session;
+
+const NAME: &str = env!("CARGO_PKG_NAME");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Parser, Clone, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(help = "The server to forward connections to, only offline mode servers are supported")]
+    target: MinecraftAddress,
+    #[arg(
+        short,
+        long,
+        default_value = "127.0.0.1:25566",
+        help = "The address to listen on"
+    )]
+    listen: SocketAddr,
+    #[arg(short, long, help = "Log packets to this file instead of stdout")]
+    output: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 64,
+        help = "The most bytes of an undecoded packet to log as hex"
+    )]
+    max_hex: usize,
+    #[arg(long, help = "Capture each session into <id>.mccap in this directory")]
+    capture: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    let log = match &args.output {
+        Some(path) => PacketLog::file(path, args.max_hex).context("Create output file")?,
+        None => PacketLog::stdout(args.max_hex),
+    };
+
+    let mut proxy = Proxy::bind(args.listen, args.target.0, log).context("Bind listener")?;
+
+    if let Some(dir) = &args.capture {
+        fs::create_dir_all(dir).context("Create capture directory")?;
+        proxy.capture_dir = Some(dir.clone());
+    }
+
+    let stop = proxy.stop_handle();
+    ctrlc::set_handler(move || {
+        let _ = stop.stop();
+    })
+    .context("Set ctrl-c handler")?;
+
+    info!("Starting {} - {}", NAME, VERSION);
+    info!(
+        "Forwarding {} to {}",
+        proxy.local_addr().context("Local address")?,
+        args.target.0
+    );
+
+    proxy.run().context("Run proxy")
+}
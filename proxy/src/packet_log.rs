@@ -0,0 +1,94 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use mc_io::state::ProtocolState;
+use proto::primitive::VarInt;
+use proto::{Data, Direction};
+
+/// Writes a line for every packet proxied
+pub struct PacketLog {
+    out: Box<dyn Write>,
+    // Bytes of hex printed for packets that can't be decoded
+    max_hex: usize,
+    start: Instant,
+}
+
+impl PacketLog {
+    pub fn stdout(max_hex: usize) -> Self {
+        Self::new(Box::new(io::stdout()), max_hex)
+    }
+
+    pub fn file(path: &Path, max_hex: usize) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(LineWriter::new(file)), max_hex))
+    }
+
+    fn new(out: Box<dyn Write>, max_hex: usize) -> Self {
+        Self {
+            out,
+            max_hex,
+            start: Instant::now(),
+        }
+    }
+
+    /// Logs `packet`, the packet id followed by the packet, which was `size` bytes on the wire
+    pub fn packet(
+        &mut self,
+        session: usize,
+        direction: Direction,
+        state: ProtocolState,
+        packet: &[u8],
+        size: usize,
+    ) -> io::Result<()> {
+        let arrow = match direction {
+            Direction::ClientToServer => "C->S",
+            Direction::ServerToClient => "S->C",
+        };
+
+        let mut body = packet;
+        let Ok(id) = VarInt::try_decode(&mut body).map(|id| id.0) else {
+            return writeln!(
+                self.out,
+                "{:>9.3} #{session} {arrow} {state:?} <no packet id> ({size} bytes)",
+                self.start.elapsed().as_secs_f64()
+            );
+        };
+
+        // Every known packet id fits in a byte
+        let known = u8::try_from(id).ok().and_then(|id| {
+            let name = state.packet_name(direction, id)?;
+            let decoded = state.debug_packet(direction, id, body)?;
+            Some((name, decoded))
+        });
+
+        let (name, details) = match known {
+            Some((name, Ok(decoded))) => (name, decoded),
+            Some((name, Err(error))) => (name, format!("<{error}> {}", self.hex(body))),
+            None => (format!("Unknown(0x{id:02x})"), self.hex(body)),
+        };
+
+        writeln!(
+            self.out,
+            "{:>9.3} #{session} {arrow} {state:?} {name} ({size} bytes) {details}",
+            self.start.elapsed().as_secs_f64()
+        )
+    }
+
+    fn hex(&self, bytes: &[u8]) -> String {
+        let mut hex = String::with_capacity(bytes.len().min(self.max_hex) * 3);
+
+        for byte in bytes.iter().take(self.max_hex) {
+            let _ = write!(hex, "{byte:02x} ");
+        }
+        hex.pop();
+
+        if bytes.len() > self.max_hex {
+            let _ = write!(hex, " (+{} bytes)", bytes.len() - self.max_hex);
+        }
+
+        hex
+    }
+}
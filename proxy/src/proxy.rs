@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use mc_io::capture::Recorder;
+use mc_io::error::CommunicationError;
+use mc_io::server::StopHandle;
+use mc_io::state::Client;
+use mc_io::GlobalReadContext;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use proto::Direction;
+
+use crate::packet_log::PacketLog;
+use crate::session::{Peer, Session};
+
+const LISTENER_TOKEN: Token = Token(0);
+const WAKER_TOKEN: Token = Token(1);
+// Each session takes two tokens after these, the client's and then the server's
+const FIRST_SESSION_TOKEN: usize = 2;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Accepts clients and forwards each to `target`, logging every packet in between
+pub struct Proxy {
+    poll: Poll,
+    listener: TcpListener,
+    stop: StopHandle,
+    target: SocketAddr,
+    sessions: HashMap<usize, Session>,
+    next_session: usize,
+    g_read_ctx: GlobalReadContext,
+    log: PacketLog,
+    /// Each session is captured into `<id>.mccap` in this directory when set
+    pub capture_dir: Option<PathBuf>,
+}
+
+impl Proxy {
+    pub fn bind(addr: SocketAddr, target: SocketAddr, log: PacketLog) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::bind(addr)?;
+        poll.registry()
+            .register(&mut listener, LISTENER_TOKEN, Interest::READABLE)?;
+        let stop = StopHandle::new(poll.registry(), WAKER_TOKEN)?;
+
+        Ok(Self {
+            poll,
+            listener,
+            stop,
+            target,
+            sessions: HashMap::new(),
+            next_session: 0,
+            g_read_ctx: GlobalReadContext::new(),
+            log,
+            capture_dir: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Runs the proxy until stopped with a `StopHandle`
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(256);
+
+        while !self.stop.is_stopped() {
+            let timeout = self
+                .sessions
+                .values()
+                .filter_map(|session| session.connect_deadline)
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            match self.poll.poll(&mut events, timeout) {
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                res => res?,
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER_TOKEN => self.accept()?,
+                    WAKER_TOKEN => {}
+                    Token(token) => {
+                        let session = (token - FIRST_SESSION_TOKEN) / 2;
+                        let direction = if (token - FIRST_SESSION_TOKEN) % 2 == 0 {
+                            Direction::ClientToServer
+                        } else {
+                            Direction::ServerToClient
+                        };
+
+                        self.handle_session(
+                            session,
+                            direction,
+                            event.is_readable(),
+                            event.is_writable(),
+                        );
+                    }
+                }
+            }
+
+            self.expire_connects();
+        }
+
+        Ok(())
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            let (mut client, addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    warn!("Could not accept a connection: {}", err);
+                    return Ok(());
+                }
+            };
+
+            // Finished once the server's socket is writable, see `handle_session`
+            let mut server = match TcpStream::connect(self.target) {
+                Ok(server) => server,
+                Err(err) => {
+                    // Dropping the client closes it
+                    warn!("Could not connect {} to {}: {}", addr, self.target, err);
+                    continue;
+                }
+            };
+
+            let id = self.next_session;
+            self.next_session += 1;
+
+            let client_token = Token(FIRST_SESSION_TOKEN + id * 2);
+            let server_token = Token(FIRST_SESSION_TOKEN + id * 2 + 1);
+            let interest = Interest::READABLE | Interest::WRITABLE;
+            self.poll
+                .registry()
+                .register(&mut client, client_token, interest)?;
+            self.poll
+                .registry()
+                .register(&mut server, server_token, interest)?;
+            if let Err(err) = client.set_nodelay(true) {
+                warn!("Could not set nodelay for {}: {}", addr, err);
+            }
+
+            let recorder = self.capture_dir.as_ref().and_then(|dir| {
+                let path = dir.join(format!("{id}.mccap"));
+
+                match File::create(&path).and_then(Recorder::new::<Client>) {
+                    Ok(recorder) => Some(recorder),
+                    Err(err) => {
+                        warn!("Could not capture #{} to {}: {}", id, path.display(), err);
+                        None
+                    }
+                }
+            });
+
+            info!("#{} {} connected, connecting to {}", id, addr, self.target);
+
+            let mut session =
+                Session::new(id, addr, Peer::new(client), Peer::new(server), recorder);
+            session.connect_deadline = Some(Instant::now() + CONNECT_TIMEOUT);
+            self.sessions.insert(id, session);
+        }
+    }
+
+    // Closes the sessions whose server didn't accept the connection in time
+    fn expire_connects(&mut self) {
+        let now = Instant::now();
+
+        self.sessions
+            .retain(|id, session| match session.connect_deadline {
+                Some(deadline) if deadline <= now => {
+                    warn!(
+                        "#{} could not connect {} to {}: timed out",
+                        id, session.addr, self.target
+                    );
+                    false
+                }
+                _ => true,
+            });
+    }
+
+    fn handle_session(&mut self, id: usize, direction: Direction, readable: bool, writable: bool) {
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return;
+        };
+
+        let res = if session.connect_deadline.is_some() {
+            // The client is only read once there is somewhere to forward to
+            if direction == Direction::ClientToServer {
+                return;
+            }
+
+            match session.finish_connect() {
+                Ok(false) => return,
+                Ok(true) => {
+                    // The client's data arrived while connecting, which isn't signalled again
+                    session
+                        .handle(
+                            Direction::ClientToServer,
+                            true,
+                            false,
+                            &mut self.g_read_ctx,
+                            &mut self.log,
+                        )
+                        .and_then(|()| {
+                            session.handle(
+                                direction,
+                                readable,
+                                writable,
+                                &mut self.g_read_ctx,
+                                &mut self.log,
+                            )
+                        })
+                }
+                Err(error) => {
+                    warn!(
+                        "#{} could not connect {} to {}: {}",
+                        id, session.addr, self.target, error
+                    );
+                    self.sessions.remove(&id);
+                    return;
+                }
+            }
+        } else {
+            session.handle(
+                direction,
+                readable,
+                writable,
+                &mut self.g_read_ctx,
+                &mut self.log,
+            )
+        };
+
+        let closed_by = match direction {
+            Direction::ClientToServer => "client",
+            Direction::ServerToClient => "server",
+        };
+
+        match res {
+            Ok(()) => return,
+            Err(CommunicationError::Closed) => {
+                info!("#{} {} closed by the {}", id, session.addr, closed_by)
+            }
+            Err(error) => warn!("#{} {} closed: {}", id, session.addr, error),
+        }
+
+        // Eg. a disconnect packet from the server
+        session.flush_client();
+        // The streams are deregistered when they are dropped
+        self.sessions.remove(&id);
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use mc_io::capture::{self, Recorder};
+use mc_io::error::{CommunicationError, ReadError};
+use mc_io::packet::helpers;
+use mc_io::state::{Client, ProtocolState, StateMachine};
+use mc_io::{ConnectionReadContext, ConnectionWriteContext, GlobalReadContext};
+use mio::net::TcpStream;
+use proto::packets::c2s::handshake::HandshakePacket;
+use proto::packets::s2c::login::{
+    EncryptionRequestPacket, LoginSuccessPacket, SetCompressionPacket,
+};
+use proto::primitive::{v21, VarInt};
+use proto::{Data, Direction, Packet};
+
+use crate::packet_log::PacketLog;
+
+/// One side of a session
+pub struct Peer {
+    pub read: ConnectionReadContext<Arc<TcpStream>>,
+    pub write: ConnectionWriteContext<Arc<TcpStream>>,
+}
+
+impl Peer {
+    pub fn new(stream: TcpStream) -> Self {
+        let stream = Arc::new(stream);
+
+        Self {
+            read: ConnectionReadContext::new(stream.clone()),
+            write: ConnectionWriteContext::new(stream),
+        }
+    }
+}
+
+/// A client connected through the proxy and its connection to the server
+pub struct Session {
+    pub id: usize,
+    pub addr: SocketAddr,
+    /// When connecting to the server is given up on, set while the connection is established
+    pub connect_deadline: Option<Instant>,
+    client: Peer,
+    server: Peer,
+    // Both directions share a state and compression threshold, tracked from the client's view
+    protocol: StateMachine<Client>,
+    recorder: Option<Recorder>,
+}
+
+impl Session {
+    pub fn new(
+        id: usize,
+        addr: SocketAddr,
+        client: Peer,
+        server: Peer,
+        recorder: Option<Recorder>,
+    ) -> Self {
+        Self {
+            id,
+            addr,
+            connect_deadline: None,
+            client,
+            server,
+            protocol: StateMachine::new(),
+            recorder,
+        }
+    }
+
+    /// Checks whether the non blocking connect to the server finished, to be called on events of
+    /// the server's socket while connecting
+    pub fn finish_connect(&mut self) -> io::Result<bool> {
+        let server = &self.server.write.socket;
+
+        if let Some(error) = server.take_error()? {
+            return Err(error);
+        }
+        match server.peer_addr() {
+            Err(error) if error.kind() == ErrorKind::NotConnected => return Ok(false),
+            Err(error) => return Err(error),
+            Ok(_) => {}
+        }
+
+        server.set_nodelay(true)?;
+        self.connect_deadline = None;
+
+        Ok(true)
+    }
+
+    /// Handles an event on the socket that sends packets in `direction`, forwarding everything
+    /// read to the other socket
+    pub fn handle(
+        &mut self,
+        direction: Direction,
+        readable: bool,
+        writable: bool,
+        g_read_ctx: &mut GlobalReadContext,
+        log: &mut PacketLog,
+    ) -> Result<(), CommunicationError> {
+        let (from, to) = match direction {
+            Direction::ClientToServer => (&mut self.client, &mut self.server),
+            Direction::ServerToClient => (&mut self.server, &mut self.client),
+        };
+
+        if writable {
+            from.write.write_unwritten()?;
+        }
+
+        if !readable {
+            return Ok(());
+        }
+
+        let id = self.id;
+        let protocol = &mut self.protocol;
+        let recorder = self.recorder.as_ref();
+        let to = &mut to.write;
+
+        from.read.read(g_read_ctx, |framed, compression_ctx| {
+            // Forwarded as is, before it is inspected
+            let mut header = [0; 3];
+            let remaining = v21(framed.0.len() as u32).encode(&mut header).len();
+            let header = &header[..3 - remaining];
+            to.write_vectored([header, framed.0].into_iter())?;
+
+            let state = protocol.state();
+            let threshold = protocol.compression_threshold();
+            let packet = helpers::read_packet(framed, compression_ctx, threshold)?;
+
+            if let Some(recorder) = recorder {
+                let compressed = capture::is_compressed(framed, threshold);
+                recorder.record(direction, state, compressed, &packet)?;
+            }
+
+            log.packet(
+                id,
+                direction,
+                state,
+                packet.0,
+                header.len() + framed.0.len(),
+            )?;
+
+            follow(protocol, direction, packet.0)
+        })
+    }
+
+    /// Sends anything still buffered for the client, best effort as the session is closing
+    pub fn flush_client(&mut self) {
+        let _ = self.client.write.write_unwritten();
+    }
+}
+
+// Updates `protocol` with the state changes and compression `packet` causes
+fn follow(
+    protocol: &mut StateMachine<Client>,
+    direction: Direction,
+    packet: &[u8],
+) -> Result<(), CommunicationError> {
+    let mut body = packet;
+    // None of the followed packets have ids past a byte
+    let Ok(id) = u8::try_from(VarInt::try_decode(&mut body)?.0) else {
+        return Ok(());
+    };
+
+    match (direction, protocol.state(), id) {
+        (Direction::ClientToServer, ProtocolState::Handshake, HandshakePacket::PACKET_ID_NUM) => {
+            let handshake = HandshakePacket::try_decode(&mut body)?;
+            let next_state = ProtocolState::from_id(handshake.next_state)
+                .ok_or(ReadError::UnknownState(handshake.next_state))?;
+            protocol.transition(next_state)?;
+        }
+        (
+            Direction::ServerToClient,
+            ProtocolState::Login,
+            EncryptionRequestPacket::PACKET_ID_NUM,
+        ) => {
+            return Err(
+                "The server is in online mode, only offline mode servers can be proxied".into(),
+            );
+        }
+        (Direction::ServerToClient, ProtocolState::Login, SetCompressionPacket::PACKET_ID_NUM) => {
+            let packet = SetCompressionPacket::try_decode(&mut body)?;
+            protocol.set_compression_threshold(packet.threshold);
+        }
+        (Direction::ServerToClient, ProtocolState::Login, LoginSuccessPacket::PACKET_ID_NUM) => {
+            protocol.transition(ProtocolState::Play)?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}